@@ -0,0 +1,358 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+
+use anyhow::{Context, Result, bail};
+use camino::Utf8Path;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+
+use crate::{db, util};
+
+// constants for XML dat file
+const TAG_HEADER: &str = "header";
+const ATTR_HEADER_NAME: &str = "name";
+const ATTR_HEADER_DESC: &str = "description";
+const ATTR_HEADER_VERSION: &str = "version";
+const ATTR_HEADER_AUTHOR: &str = "author";
+const ATTR_HEADER_URL: &str = "url";
+const TAG_CLRMAMEPRO: &str = "clrmamepro";
+const ATTR_CLRMAMEPRO_HEADER: &str = "header";
+const TAG_GAME: &str = "game";
+//newer logiqx dat files use machine instead of game
+const TAG_MACHINE: &str = "machine";
+const ATTR_GAME_NAME: &str = "name";
+const ATTR_GAME_CLONEOF: &str = "cloneof";
+const TAG_ROM: &str = "rom";
+const ATTR_ROM_NAME: &str = "name";
+const ATTR_ROM_SIZE: &str = "size";
+const ATTR_ROM_STATUS: &str = "status";
+//software lists have no header, and nest roms in <part><dataarea> of each software
+const TAG_SOFTWARELIST: &str = "softwarelist";
+const ATTR_SOFTWARELIST_NAME: &str = "name";
+const ATTR_SOFTWARELIST_DESC: &str = "description";
+const TAG_SOFTWARE: &str = "software";
+const TAG_PART: &str = "part";
+const TAG_DATAAREA: &str = "dataarea";
+const ATTR_AREA_NAME: &str = "name";
+// the hash attribute names for roms are the same as `util::HashType::as_str`
+
+#[derive(Debug, Default)]
+pub struct DatHeader {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub version: Option<String>,
+    pub author: Option<String>,
+    //where newer versions of the dat file can be found
+    pub url: Option<String>,
+    //the clrmamepro detector for a header that dumps can have, which the hashes don't include
+    pub header_detector: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct DatGame {
+    pub name: String,
+    pub cloneof: Option<String>,
+    pub roms: Vec<DatRom>,
+}
+
+#[derive(Debug)]
+pub struct DatRom {
+    pub name: String,
+    //the part and data area a software list rom is in, joined as `part/dataarea`
+    pub area: Option<String>,
+    attributes: Vec<(String, String)>,
+}
+
+impl DatRom {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn size(&self) -> Option<&str> {
+        self.attribute(ATTR_ROM_SIZE)
+    }
+
+    pub fn hash(&self, hash_type: util::HashType) -> Option<&str> {
+        self.attribute(hash_type.as_str())
+    }
+}
+
+// Reads a dat file one game at a time, so the whole file never has to be held in memory
+pub struct DatReader<R: BufRead> {
+    reader: Reader<R>,
+    buf: Vec<u8>,
+    header: DatHeader,
+}
+
+impl DatReader<BufReader<File>> {
+    pub fn open<P: AsRef<Utf8Path>>(path: P) -> Result<Self> {
+        let reader = Reader::from_file(path.as_ref()).context("Unable to read reference dat file")?;
+        Self::new(reader)
+    }
+}
+
+impl<R: BufRead> DatReader<R> {
+    // Reads a dat file from anywhere, such as a download, as far as the header
+    pub fn from_reader(reader: R) -> Result<Self> {
+        Self::new(Reader::from_reader(reader))
+    }
+
+    fn new(mut reader: Reader<R>) -> Result<Self> {
+        reader.config_mut().trim_text(true);
+        let mut dat_reader = Self {
+            reader,
+            buf: Vec::new(),
+            header: DatHeader::default(),
+        };
+        dat_reader.read_header().context("Unable to parse reference dat file")?;
+        Ok(dat_reader)
+    }
+
+    pub fn header(&self) -> &DatHeader {
+        &self.header
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+
+    //reads up to the end of the header, leaving the reader at the first game
+    fn read_header(&mut self) -> Result<()> {
+        let root = loop {
+            match self.next_event()? {
+                Event::Start(e) => break e,
+                Event::Eof => bail!("Could not find header in reference dat file"),
+                _ => {}
+            }
+        };
+        if root.local_name().as_ref() == TAG_SOFTWARELIST.as_bytes() {
+            self.header.name = attribute(&root, ATTR_SOFTWARELIST_NAME)?;
+            self.header.description = attribute(&root, ATTR_SOFTWARELIST_DESC)?.or(self.header.name.clone());
+            self.header.version = Some(String::new());
+            self.header.author = Some(String::new());
+            return Ok(());
+        }
+
+        loop {
+            match self.next_event()? {
+                Event::Start(e) if e.local_name().as_ref() == TAG_HEADER.as_bytes() => break,
+                Event::Start(e) | Event::Empty(e) if is_game(&e) => {
+                    bail!("Could not find header in reference dat file")
+                }
+                Event::Eof => bail!("Could not find header in reference dat file"),
+                _ => {}
+            }
+        }
+        let mut field = None;
+        loop {
+            match self.next_event()? {
+                Event::Empty(e) if e.local_name().as_ref() == TAG_CLRMAMEPRO.as_bytes() => {
+                    self.header.header_detector = attribute(&e, ATTR_CLRMAMEPRO_HEADER)?;
+                }
+                Event::Start(e) => field = Some(String::from_utf8_lossy(e.local_name().as_ref()).into_owned()),
+                Event::Text(text) => {
+                    let text = text.unescape()?.into_owned();
+                    match field.as_deref() {
+                        Some(ATTR_HEADER_NAME) => self.header.name = Some(text),
+                        Some(ATTR_HEADER_DESC) => self.header.description = Some(text),
+                        Some(ATTR_HEADER_VERSION) => self.header.version = Some(text),
+                        Some(ATTR_HEADER_AUTHOR) => self.header.author = Some(text),
+                        Some(ATTR_HEADER_URL) => self.header.url = Some(text),
+                        _ => {}
+                    }
+                }
+                Event::End(e) if e.local_name().as_ref() == TAG_HEADER.as_bytes() => return Ok(()),
+                Event::End(_) => field = None,
+                Event::Eof => bail!("Unexpected end of reference dat file in header"),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn next_game(&mut self) -> Result<Option<DatGame>> {
+        self.read_game().context("Unable to parse reference dat file")
+    }
+
+    fn read_game(&mut self) -> Result<Option<DatGame>> {
+        //games are direct children of the root, anything else at that level is skipped over
+        let mut depth = 0usize;
+        loop {
+            match self.next_event()? {
+                Event::Start(e) if depth == 0 && is_game(&e) => {
+                    let mut game = new_game(&e)?;
+                    self.read_roms(&mut game)?;
+                    return Ok(Some(game));
+                }
+                Event::Empty(e) if depth == 0 && is_game(&e) => return Ok(Some(new_game(&e)?)),
+                Event::Start(_) => depth += 1,
+                Event::End(_) if depth == 0 => return Ok(None),
+                Event::End(_) => depth -= 1,
+                Event::Eof => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+
+    //roms can be nested in software list parts and data areas, so look at everything in the game
+    fn read_roms(&mut self, game: &mut DatGame) -> Result<()> {
+        //the area name each open element adds, if it is a part or data area
+        let mut open: Vec<Option<String>> = Vec::new();
+        loop {
+            match self.next_event()? {
+                Event::Start(e) => {
+                    push_dumped_rom(&e, &open, &mut game.roms)?;
+                    open.push(area_name(&e)?);
+                }
+                Event::Empty(e) => push_dumped_rom(&e, &open, &mut game.roms)?,
+                Event::End(_) if open.is_empty() => return Ok(()),
+                Event::End(_) => {
+                    open.pop();
+                }
+                Event::Eof => bail!("Unexpected end of reference dat file in game `{}`", game.name),
+                _ => {}
+            }
+        }
+    }
+
+    fn next_event(&mut self) -> Result<Event<'static>> {
+        self.buf.clear();
+        let event = self.reader.read_event_into(&mut self.buf)?;
+        Ok(event.into_owned())
+    }
+}
+
+fn is_game(e: &BytesStart) -> bool {
+    let name = e.local_name();
+    [TAG_GAME, TAG_MACHINE, TAG_SOFTWARE]
+        .iter()
+        .any(|tag| name.as_ref() == tag.as_bytes())
+}
+
+fn new_game(e: &BytesStart) -> Result<DatGame> {
+    Ok(DatGame {
+        name: attribute(e, ATTR_GAME_NAME)?.context("Unable to read game name in reference dat file")?,
+        cloneof: attribute(e, ATTR_GAME_CLONEOF)?,
+        roms: Vec::new(),
+    })
+}
+
+//roms without a name or that have never been dumped can never be matched, so are skipped
+fn push_dumped_rom(e: &BytesStart, open: &[Option<String>], roms: &mut Vec<DatRom>) -> Result<()> {
+    if e.local_name().as_ref() != TAG_ROM.as_bytes() {
+        return Ok(());
+    }
+    let mut name = None;
+    let mut attributes = Vec::new();
+    for attr in e.attributes() {
+        let attr = attr?;
+        let key = String::from_utf8_lossy(attr.key.local_name().as_ref()).into_owned();
+        let value = attr.unescape_value()?.into_owned();
+        if key == ATTR_ROM_NAME {
+            name = Some(value);
+        } else {
+            attributes.push((key, value));
+        }
+    }
+    if let Some(name) = name
+        && !attributes
+            .iter()
+            .any(|(key, value)| key == ATTR_ROM_STATUS && value == "nodump")
+    {
+        roms.push(DatRom {
+            name,
+            area: area(open),
+            attributes,
+        });
+    }
+    Ok(())
+}
+
+fn area_name(e: &BytesStart) -> Result<Option<String>> {
+    let name = e.local_name();
+    if [TAG_PART, TAG_DATAAREA]
+        .iter()
+        .any(|tag| name.as_ref() == tag.as_bytes())
+    {
+        attribute(e, ATTR_AREA_NAME)
+    } else {
+        Ok(None)
+    }
+}
+
+fn area(open: &[Option<String>]) -> Option<String> {
+    let names: Vec<_> = open.iter().flatten().map(String::as_str).collect();
+    (!names.is_empty()).then(|| names.join("/"))
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Result<Option<String>> {
+    match e.try_get_attribute(name)? {
+        Some(attr) => Ok(Some(attr.unescape_value()?.into_owned())),
+        None => Ok(None),
+    }
+}
+
+// Writes a Logiqx dat file, a game at a time
+pub struct DatWriter<W: Write> {
+    out: W,
+    hash_type: util::HashType,
+}
+
+impl<W: Write> DatWriter<W> {
+    pub fn new(mut out: W, header: &DatHeader, hash_type: util::HashType) -> Result<Self> {
+        writeln!(out, r#"<?xml version="1.0"?>"#)?;
+        writeln!(out, "<datafile>")?;
+        writeln!(out, "\t<{TAG_HEADER}>")?;
+        for (tag, value) in [
+            (ATTR_HEADER_NAME, &header.name),
+            (ATTR_HEADER_DESC, &header.description),
+            (ATTR_HEADER_VERSION, &header.version),
+            (ATTR_HEADER_AUTHOR, &header.author),
+        ] {
+            writeln!(out, "\t\t<{tag}>{}</{tag}>", escape(value.as_deref().unwrap_or_default()))?;
+        }
+        if let Some(url) = &header.url {
+            writeln!(out, "\t\t<{ATTR_HEADER_URL}>{}</{ATTR_HEADER_URL}>", escape(url))?;
+        }
+        if let Some(detector) = &header.header_detector {
+            writeln!(out, "\t\t<{TAG_CLRMAMEPRO} {ATTR_CLRMAMEPRO_HEADER}=\"{}\"/>", escape(detector))?;
+        }
+        writeln!(out, "\t</{TAG_HEADER}>")?;
+        Ok(Self { out, hash_type })
+    }
+
+    pub fn write_game(&mut self, name: &str, roms: &[db::RomRecord]) -> Result<()> {
+        let name = escape(name);
+        writeln!(self.out, "\t<{TAG_GAME} {ATTR_GAME_NAME}=\"{name}\">")?;
+        writeln!(self.out, "\t\t<description>{name}</description>")?;
+        for rom in roms {
+            let crc = match &rom.crc {
+                Some(crc) if self.hash_type != util::HashType::Crc => format!(" {}=\"{crc}\"", util::HashType::Crc),
+                _ => String::new(),
+            };
+            //roms without a size in the dat file they came from are written without one too
+            let size = match rom.size {
+                Some(size) => format!(" {ATTR_ROM_SIZE}=\"{size}\""),
+                None => String::new(),
+            };
+            writeln!(
+                self.out,
+                "\t\t<{TAG_ROM} {ATTR_ROM_NAME}=\"{}\"{size}{crc} {}=\"{}\"/>",
+                escape(&rom.name),
+                self.hash_type,
+                rom.hash
+            )?;
+        }
+        writeln!(self.out, "\t</{TAG_GAME}>")?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        writeln!(self.out, "</datafile>")?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
@@ -2,7 +2,7 @@
 
 use camino::Utf8Path;
 
-use anyhow::{Result, bail};
+use anyhow::{Result, bail, ensure};
 use rusqlite::{Connection, named_params, params};
 
 //macro that generates a select statement
@@ -11,13 +11,12 @@ macro_rules! sql_query_one {
         let wheres = vec![$(concat!(stringify!($where_field), " = :", stringify!($where_field))),+].join(" AND ");
         let sql = format!("SELECT {} FROM {} WHERE {}", $fields, $table, wheres);
 
-        $conn.query_one(
-            &sql,
+        $conn.prepare_cached(&sql).and_then(|mut stmt| stmt.query_one(
             &[$(
                 (concat!(":", stringify!($where_field)), &sql_query_one!(@value $where_field $(= $where_value)?) as &dyn rusqlite::ToSql)
             ),+] as &[(&str, &dyn rusqlite::ToSql)],
             $mapper
-        )
+        ))
     }};
 
     (@value $field:ident = $value:expr) => { $value };
@@ -29,7 +28,7 @@ macro_rules! sql_query {
         let wheres = vec![$(concat!(stringify!($where_field), " = :", stringify!($where_field))),+].join(" AND ");
         let sql = format!("SELECT {} FROM {} WHERE {} ORDER BY {}", $fields, $table, wheres, $order);
 
-        $conn.prepare(&sql).and_then(|mut stmt| stmt.query_map(
+        $conn.prepare_cached(&sql).and_then(|mut stmt| stmt.query_map(
             &[$(
                 (concat!(":", stringify!($where_field)), &sql_query!(@value $where_field $(= $where_value)?) as &dyn rusqlite::ToSql)
             ),+] as &[(&str, &dyn rusqlite::ToSql)],
@@ -41,7 +40,7 @@ macro_rules! sql_query {
         let wheres = vec![$(concat!(stringify!($where_field), " = :", stringify!($where_field))),+].join(" AND ");
         let sql = format!("SELECT {} FROM {} WHERE {}", $fields, $table, wheres);
 
-        $conn.prepare(&sql).and_then(|mut stmt| stmt.query_map(
+        $conn.prepare_cached(&sql).and_then(|mut stmt| stmt.query_map(
             &[$(
                 (concat!(":", stringify!($where_field)), &sql_query!(@value $where_field $(= $where_value)?) as &dyn rusqlite::ToSql)
             ),+] as &[(&str, &dyn rusqlite::ToSql)],
@@ -124,6 +123,23 @@ impl rusqlite::types::FromSql for SizeWrapper {
     }
 }
 
+// Turns a glob with `*` and `?` wildcards into a LIKE pattern escaped with `\`
+fn glob_to_like(glob: &str) -> String {
+    let mut pattern = String::with_capacity(glob.len());
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push('%'),
+            '?' => pattern.push('_'),
+            '%' | '_' | '\\' => {
+                pattern.push('\\');
+                pattern.push(c);
+            }
+            _ => pattern.push(c),
+        }
+    }
+    pattern
+}
+
 pub trait Queryable: Sized {
     type IdType: HasId;
 
@@ -169,7 +185,44 @@ pub trait DeletableByDat: Queryable {
     }
 }
 
+// How partial names are compared with the names of records when searching
+#[derive(Debug, Clone, Copy)]
+pub struct NameSearch {
+    pub match_all: bool,
+    pub ignore_case: bool,
+    pub whole_word: bool,
+}
+
+impl NameSearch {
+    //either matching any of the partial names or all of them
+    pub fn matches<S: AsRef<str>>(&self, name: &str, partial_names: &[S]) -> bool {
+        let mut found = partial_names
+            .iter()
+            .map(|partial| self.contains(name, partial.as_ref()));
+        if self.match_all { found.all(|f| f) } else { partial_names.is_empty() || found.any(|f| f) }
+    }
+
+    fn contains(&self, name: &str, partial: &str) -> bool {
+        let (name, partial) = if self.ignore_case {
+            (name.to_lowercase(), partial.to_lowercase())
+        } else {
+            (name.to_string(), partial.to_string())
+        };
+        //a whole word can't have a letter or number either side of it
+        name.match_indices(&partial).any(|(start, _)| {
+            !self.whole_word
+                || !(name[..start].chars().next_back().is_some_and(char::is_alphanumeric)
+                    || name[start + partial.len()..]
+                        .chars()
+                        .next()
+                        .is_some_and(char::is_alphanumeric))
+        })
+    }
+}
+
 pub trait FindableByName: Queryable {
+    fn name(&self) -> &str;
+
     fn find_by_name(conn: &Connection, dat_id: &DatId, name: &str, exact: bool) -> Result<Vec<Self>> {
         let matches = if exact {
             sql_query!(conn, Self::table_name(), Self::fields(), where {dat_id = dat_id.id(), name}, order by "name", Self::from_row)
@@ -187,6 +240,42 @@ pub trait FindableByName: Queryable {
         }?;
         Ok(matches)
     }
+
+    //partial match on several names, the query ignores case and word boundaries so the search decides those.
+    //like only ignores the case of ascii letters, so it can't narrow down a search ignoring the case of any others.
+    fn find_by_names<S: AsRef<str>>(
+        conn: &Connection,
+        dat_id: &DatId,
+        names: &[S],
+        search: &NameSearch,
+    ) -> Result<Vec<Self>> {
+        let conditions: Vec<_> = (0..names.len()).map(|i| format!("name LIKE (?{})", i + 2)).collect();
+        let prefilter = !search.ignore_case || names.iter().all(|name| name.as_ref().is_ascii());
+        let filter = if !prefilter {
+            String::new()
+        } else {
+            format!(" AND ({})", conditions.join(if search.match_all { " AND " } else { " OR " }))
+        };
+        let mut stmt = conn.prepare_cached(
+            format!(
+                "SELECT {} FROM {} WHERE dat_id = (?1){} ORDER BY name",
+                Self::fields(),
+                Self::table_name(),
+                filter
+            )
+            .as_str(),
+        )?;
+        let patterns: Vec<_> = names.iter().map(|name| format!("%{}%", name.as_ref())).collect();
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![dat_id];
+        if prefilter {
+            params.extend(patterns.iter().map(|pattern| pattern as &dyn rusqlite::ToSql));
+        }
+        let mut matches = stmt
+            .query_map(params.as_slice(), Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        matches.retain(|record| search.matches(record.name(), names));
+        Ok(matches)
+    }
 }
 
 pub trait Bindable {
@@ -199,7 +288,7 @@ where
 {
     type NewType: Bindable;
 
-    fn insert(conn: &Connection, new: &Self::NewType) -> Result<Self> {
+    fn insert_sql(new: &Self::NewType) -> String {
         let params = new.bind_params();
         let values: Vec<&str> = params.iter().map(|(name, _)| *name).collect();
         let columns: Vec<String> = values
@@ -207,13 +296,28 @@ where
             .map(|name| name.strip_prefix(":").unwrap_or(name).to_string())
             .collect();
 
-        let sql = format!("INSERT INTO {} ({}) VALUES ({})", Self::table_name(), columns.join(", "), values.join(", "));
+        format!("INSERT INTO {} ({}) VALUES ({})", Self::table_name(), columns.join(", "), values.join(", "))
+    }
 
-        conn.execute(&sql, params.as_slice())?;
+    fn insert(conn: &Connection, new: &Self::NewType) -> Result<Self> {
+        conn.prepare_cached(&Self::insert_sql(new))?
+            .execute(new.bind_params().as_slice())?;
         let raw_id = conn.last_insert_rowid();
         let id = Self::IdType::from(raw_id);
         Self::get_by_id(conn, &id)
     }
+
+    //prepares the insert once for all the rows, and doesn't read them back
+    fn insert_many(conn: &Connection, news: &[Self::NewType]) -> Result<()> {
+        let Some(first) = news.first() else {
+            return Ok(());
+        };
+        let mut stmt = conn.prepare_cached(&Self::insert_sql(first))?;
+        for new in news {
+            stmt.execute(new.bind_params().as_slice())?;
+        }
+        Ok(())
+    }
 }
 
 pub type DatId = Id<DatRecord>;
@@ -226,6 +330,15 @@ pub struct DatRecord {
     pub version: String,
     pub author: String,
     pub hash_type: String,
+    pub imported_at: Option<String>,
+    pub last_scanned_at: Option<String>,
+    pub content_hash: Option<String>, //sha1 of the dat file, not known for dats imported before it was stored
+    pub removed_at: Option<String>,   //set when the dat was removed but its scanned files were kept
+    pub header_detector: Option<String>,
+    pub url: Option<String>,         //where newer versions of the dat file can be downloaded from
+    pub name_map: Option<String>,    //json pairs of replacements for names on disk, the default mapping if not set
+    pub header_rule: Option<String>, //set by the user to override the header detector of the dat file
+    pub strict_size: bool,           //only match by hash if the size matches as well
 }
 
 impl Queryable for DatRecord {
@@ -236,7 +349,8 @@ impl Queryable for DatRecord {
     }
 
     fn fields() -> &'static str {
-        "id, name, description, version, author, hash_type"
+        "id, name, description, version, author, hash_type, imported_at, last_scanned_at, content_hash, removed_at, \
+        header_detector, url, name_map, header_rule, strict_size"
     }
 
     fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
@@ -247,6 +361,15 @@ impl Queryable for DatRecord {
             version: row.get("version")?,
             author: row.get("author")?,
             hash_type: row.get("hash_type")?,
+            imported_at: row.get("imported_at")?,
+            last_scanned_at: row.get("last_scanned_at")?,
+            content_hash: row.get("content_hash")?,
+            removed_at: row.get("removed_at")?,
+            header_detector: row.get("header_detector")?,
+            url: row.get("url")?,
+            name_map: row.get("name_map")?,
+            header_rule: row.get("header_rule")?,
+            strict_size: row.get("strict_size")?,
         })
     }
 }
@@ -260,6 +383,10 @@ pub struct NewDat {
     pub version: String,
     pub author: String,
     pub hash_type: String,
+    pub imported_at: Option<String>,
+    pub content_hash: Option<String>,
+    pub header_detector: Option<String>,
+    pub url: Option<String>,
 }
 
 impl Bindable for NewDat {
@@ -269,7 +396,11 @@ impl Bindable for NewDat {
             ":description": self.description,
             ":version": self.version,
             ":author": self.author,
-            ":hash_type": self.hash_type
+            ":hash_type": self.hash_type,
+            ":imported_at": self.imported_at,
+            ":content_hash": self.content_hash,
+            ":header_detector": self.header_detector,
+            ":url": self.url,
         }
         .to_vec()
     }
@@ -287,6 +418,7 @@ pub struct SetRecord {
 
     pub dat_id: DatId,
     pub name: String,
+    pub parent_id: Option<SetId>, //the set this is a clone of, if any
 }
 
 impl Queryable for SetRecord {
@@ -297,7 +429,7 @@ impl Queryable for SetRecord {
     }
 
     fn fields() -> &'static str {
-        "id, dat_id, name"
+        "id, dat_id, name, parent_id"
     }
 
     fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
@@ -305,13 +437,18 @@ impl Queryable for SetRecord {
             id: row.get("id")?,
             dat_id: row.get("dat_id")?,
             name: row.get("name")?,
+            parent_id: row.get("parent_id")?,
         })
     }
 }
 
 impl QueryableByDat for SetRecord {}
 impl DeletableByDat for SetRecord {}
-impl FindableByName for SetRecord {}
+impl FindableByName for SetRecord {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct NewSet {
@@ -343,8 +480,10 @@ pub struct RomRecord {
 
     pub set_id: SetId,
     pub name: String,
-    pub size: u64,
+    pub size: Option<u64>, //not every dat file gives one
     pub hash: String,
+    pub crc: Option<String>,  //not every dat file has one, and only stored since crc was added
+    pub area: Option<String>, //the part and data area of a software list rom, such as `cart/rom`
 }
 
 impl Queryable for RomRecord {
@@ -355,7 +494,7 @@ impl Queryable for RomRecord {
     }
 
     fn fields() -> &'static str {
-        "id, dat_id, set_id, name, size, hash"
+        "id, dat_id, set_id, name, size, hash, crc, area"
     }
 
     fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
@@ -364,15 +503,21 @@ impl Queryable for RomRecord {
             dat_id: row.get("dat_id")?,
             set_id: row.get("set_id")?,
             name: row.get("name")?,
-            size: row.get::<_, SizeWrapper>("size")?.0,
+            size: row.get::<_, Option<SizeWrapper>>("size")?.map(|size| size.0),
             hash: row.get("hash")?,
+            crc: row.get("crc")?,
+            area: row.get("area")?,
         })
     }
 }
 
 impl QueryableByDat for RomRecord {}
 impl DeletableByDat for RomRecord {}
-impl FindableByName for RomRecord {}
+impl FindableByName for RomRecord {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct NewRom {
@@ -380,8 +525,10 @@ pub struct NewRom {
 
     pub set_id: SetId,
     pub name: String,
-    pub size: SizeWrapper,
+    pub size: Option<SizeWrapper>,
     pub hash: String,
+    pub crc: Option<String>,
+    pub area: Option<String>,
 }
 
 impl Bindable for NewRom {
@@ -392,6 +539,8 @@ impl Bindable for NewRom {
             ":name": self.name,
             ":size": self.size,
             ":hash": self.hash,
+            ":crc": self.crc,
+            ":area": self.area,
         }
         .to_vec()
     }
@@ -410,6 +559,11 @@ pub struct DirRecord {
     pub dat_id: DatId,
     pub path: String,
     pub parent_id: Option<DirId>,
+    pub target_set_id: Option<SetId>, //the set an archive is named for, even if none of its files match it
+    pub hashed_with: Option<String>,  //the hash type and header rule the files were last hashed with
+    //only known for archives, to tell if they have changed since they were scanned
+    pub size: Option<u64>,
+    pub modified_at: Option<String>,
 }
 
 impl Queryable for DirRecord {
@@ -420,7 +574,7 @@ impl Queryable for DirRecord {
     }
 
     fn fields() -> &'static str {
-        "id, dat_id, path, parent_id"
+        "id, dat_id, path, parent_id, target_set_id, hashed_with, size, modified_at"
     }
 
     fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
@@ -429,6 +583,10 @@ impl Queryable for DirRecord {
             dat_id: row.get("dat_id")?,
             path: row.get("path")?,
             parent_id: row.get("parent_id")?,
+            target_set_id: row.get("target_set_id")?,
+            hashed_with: row.get("hashed_with")?,
+            size: row.get::<_, Option<SizeWrapper>>("size")?.map(|size| size.0),
+            modified_at: row.get("modified_at")?,
         })
     }
 }
@@ -471,6 +629,13 @@ pub struct FileRecord {
     pub name: String,
     pub size: u64,
     pub hash: String,
+    //only known for files in zip archives
+    pub compression: Option<String>,
+    pub compressed_size: Option<u64>,
+    //only known for loose files, to tell if they have changed since they were scanned
+    pub modified_at: Option<String>,
+    //only hashed when the dat has crcs as well
+    pub crc: Option<String>,
 }
 
 impl Queryable for FileRecord {
@@ -481,7 +646,7 @@ impl Queryable for FileRecord {
     }
 
     fn fields() -> &'static str {
-        "id, dat_id, dir_id, name, size, hash"
+        "id, dat_id, dir_id, name, size, hash, compression, compressed_size, modified_at, crc"
     }
 
     fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
@@ -492,6 +657,10 @@ impl Queryable for FileRecord {
             name: row.get("name")?,
             size: row.get::<_, SizeWrapper>("size")?.0,
             hash: row.get("hash")?,
+            compression: row.get("compression")?,
+            compressed_size: row.get::<_, Option<SizeWrapper>>("compressed_size")?.map(|size| size.0),
+            modified_at: row.get("modified_at")?,
+            crc: row.get("crc")?,
         })
     }
 }
@@ -508,6 +677,26 @@ pub struct NewFile {
     pub name: String,
     pub size: SizeWrapper,
     pub hash: String,
+    pub compression: Option<String>,
+    pub compressed_size: Option<SizeWrapper>,
+    pub modified_at: Option<String>,
+    pub crc: Option<String>,
+}
+
+impl NewFile {
+    pub fn new(dat_id: &DatId, dir_id: &DirId, name: &str, size: u64, hash: &str) -> Self {
+        Self {
+            dat_id: dat_id.clone(),
+            dir_id: dir_id.clone(),
+            name: name.to_string(),
+            size: SizeWrapper(size),
+            hash: hash.to_string(),
+            compression: None,
+            compressed_size: None,
+            modified_at: None,
+            crc: None,
+        }
+    }
 }
 
 impl Bindable for NewFile {
@@ -518,6 +707,10 @@ impl Bindable for NewFile {
             ":name": self.name,
             ":size": self.size,
             ":hash": self.hash,
+            ":compression": self.compression,
+            ":compressed_size": self.compressed_size,
+            ":modified_at": self.modified_at,
+            ":crc": self.crc,
         }
         .to_vec()
     }
@@ -527,6 +720,70 @@ impl Insertable for FileRecord {
     type NewType = NewFile;
 }
 
+pub type AliasId = Id<AliasRecord>;
+
+//an alternate name that a rom will also be matched by
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AliasRecord {
+    pub id: AliasId,
+    pub dat_id: DatId, //denormalized to avoid N+1 queries
+
+    pub rom_id: RomId,
+    pub name: String,
+}
+
+impl Queryable for AliasRecord {
+    type IdType = AliasId;
+
+    fn table_name() -> &'static str {
+        "rom_aliases"
+    }
+
+    fn fields() -> &'static str {
+        "id, dat_id, rom_id, name"
+    }
+
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(AliasRecord {
+            id: row.get("id")?,
+            dat_id: row.get("dat_id")?,
+            rom_id: row.get("rom_id")?,
+            name: row.get("name")?,
+        })
+    }
+}
+
+impl QueryableByDat for AliasRecord {}
+impl DeletableByDat for AliasRecord {}
+impl FindableByName for AliasRecord {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NewAlias {
+    pub dat_id: DatId, //denormalized to avoid N+1 queries
+
+    pub rom_id: RomId,
+    pub name: String,
+}
+
+impl Bindable for NewAlias {
+    fn bind_params(&self) -> Vec<(&'static str, &dyn rusqlite::ToSql)> {
+        named_params! {
+            ":dat_id": self.dat_id,
+            ":rom_id": self.rom_id,
+            ":name": self.name,
+        }
+        .to_vec()
+    }
+}
+
+impl Insertable for AliasRecord {
+    type NewType = NewAlias;
+}
+
 pub type MatchId = Id<MatchRecord>;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -599,7 +856,12 @@ impl Insertable for MatchRecord {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum MatchStatus {
     Hash,
+    //the name matches, but the hash doesn't, and the rom has no size to tell if it is a bad dump
     Name,
+    //name and size match, but no rom has the hash, so probably a bad dump
+    Corrupt,
+    //only the name matches, so probably a different file entirely
+    NameOnly,
     Match,
 }
 
@@ -608,25 +870,156 @@ impl rusqlite::types::FromSql for MatchStatus {
         value.as_str().and_then(|s| match s {
             "hash" => Ok(MatchStatus::Hash),
             "name" => Ok(MatchStatus::Name),
+            "corrupt" => Ok(MatchStatus::Corrupt),
+            "name_only" => Ok(MatchStatus::NameOnly),
             "match" => Ok(MatchStatus::Match),
             _ => Err(rusqlite::types::FromSqlError::InvalidType),
         })
     }
 }
 
-impl rusqlite::ToSql for MatchStatus {
-    #[inline]
-    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
-        let str_value = match self {
+impl MatchStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
             MatchStatus::Hash => "hash",
             MatchStatus::Name => "name",
+            MatchStatus::Corrupt => "corrupt",
+            MatchStatus::NameOnly => "name_only",
             MatchStatus::Match => "match",
-        };
-        Ok(rusqlite::types::ToSqlOutput::from(str_value))
+        }
+    }
+}
+
+impl rusqlite::ToSql for MatchStatus {
+    #[inline]
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(rusqlite::types::ToSqlOutput::from(self.as_str()))
     }
 }
 
 impl DatRecord {
+    /// dats that have not been removed
+    pub fn get_installed(conn: &Connection) -> Result<Vec<DatRecord>> {
+        let sql = format!("SELECT {} FROM {} WHERE removed_at IS NULL", Self::fields(), Self::table_name());
+        let mut stmt = conn.prepare(&sql)?;
+        let matches = stmt
+            .query_map(params![], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(matches)
+    }
+
+    /// installed dats with the name and version, most recently imported first
+    pub fn get_installed_by_version(conn: &Connection, name: &str, version: &str) -> Result<Vec<DatRecord>> {
+        let sql = format!(
+            "SELECT {} FROM {} WHERE removed_at IS NULL AND name = :name AND version = :version ORDER BY id DESC",
+            Self::fields(),
+            Self::table_name()
+        );
+        let mut stmt = conn.prepare(&sql)?;
+        let matches = stmt
+            .query_map(named_params! {":name": name, ":version": version}, Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(matches)
+    }
+
+    /// dats that were removed with their scanned files kept, most recently removed first
+    pub fn get_removed_by_name(conn: &Connection, name: &str) -> Result<Vec<DatRecord>> {
+        let sql = format!(
+            "SELECT {} FROM {} WHERE removed_at IS NOT NULL AND name = :name ORDER BY removed_at DESC, id DESC",
+            Self::fields(),
+            Self::table_name()
+        );
+        let mut stmt = conn.prepare(&sql)?;
+        let matches = stmt
+            .query_map(named_params! {":name": name}, Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(matches)
+    }
+
+    pub fn mark_removed(conn: &Connection, dat_id: &DatId, timestamp: &str) -> Result<()> {
+        let sql = format!("UPDATE {} SET removed_at = :removed_at WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": dat_id,
+                ":removed_at": timestamp,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn update_name_map(conn: &Connection, dat_id: &DatId, name_map: Option<&str>) -> Result<()> {
+        let sql = format!("UPDATE {} SET name_map = :name_map WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": dat_id,
+                ":name_map": name_map,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn update_header_rule(conn: &Connection, dat_id: &DatId, header_rule: Option<&str>) -> Result<()> {
+        let sql = format!("UPDATE {} SET header_rule = :header_rule WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": dat_id,
+                ":header_rule": header_rule,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn update_strict_size(conn: &Connection, dat_id: &DatId, strict_size: bool) -> Result<()> {
+        let sql = format!("UPDATE {} SET strict_size = :strict_size WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": dat_id,
+                ":strict_size": strict_size,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn update_url(conn: &Connection, dat_id: &DatId, url: &str) -> Result<()> {
+        let sql = format!("UPDATE {} SET url = :url WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": dat_id,
+                ":url": url,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn update_content_hash(conn: &Connection, dat_id: &DatId, content_hash: &str) -> Result<()> {
+        let sql = format!("UPDATE {} SET content_hash = :content_hash WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": dat_id,
+                ":content_hash": content_hash,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn update_last_scanned(conn: &Connection, dat_id: &DatId, timestamp: &str) -> Result<()> {
+        let sql = format!("UPDATE {} SET last_scanned_at = :last_scanned_at WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": dat_id,
+                ":last_scanned_at": timestamp,
+            },
+        )?;
+        Ok(())
+    }
+
     pub fn get_sets(&self, conn: &Connection) -> Result<Vec<SetRecord>> {
         SetRecord::get_by_dat(conn, &self.id)
     }
@@ -644,18 +1037,79 @@ impl SetRecord {
     pub fn get_roms(&self, conn: &Connection) -> Result<Vec<RomRecord>> {
         RomRecord::get_by_set(conn, &self.id)
     }
+
+    pub fn update_parent(conn: &Connection, set_id: &SetId, parent_id: &SetId) -> Result<()> {
+        let sql = format!("UPDATE {} SET parent_id = :parent_id WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": set_id,
+                ":parent_id": parent_id,
+            },
+        )?;
+        Ok(())
+    }
 }
 
 impl RomRecord {
+    //roms without a size in the dat file match a file of any size
+    pub fn size_matches(&self, size: u64) -> bool {
+        self.size.is_none_or(|rom_size| rom_size == size)
+    }
+
     fn get_by_set(conn: &Connection, set_id: &SetId) -> Result<Vec<Self>> {
         let matches = sql_query!(conn, Self::table_name(), Self::fields(), where {set_id}, Self::from_row)?;
         Ok(matches)
     }
 
+    pub fn get_by_crc(conn: &Connection, dat_id: &DatId, crc: &str) -> Result<Vec<RomRecord>> {
+        let matches = sql_query!(conn, Self::table_name(), Self::fields(), where {dat_id, crc}, Self::from_row)?;
+        Ok(matches)
+    }
+
+    pub fn has_crcs(conn: &Connection, dat_id: &DatId) -> Result<bool> {
+        let sql = format!("SELECT EXISTS (SELECT 1 FROM {} WHERE dat_id = ?1 AND crc IS NOT NULL)", Self::table_name());
+        Ok(conn.query_row(&sql, [dat_id], |row| row.get(0))?)
+    }
+
     pub fn get_by_hash(conn: &Connection, dat_id: &DatId, hash: &str) -> Result<Vec<RomRecord>> {
         let matches = sql_query!(conn, Self::table_name(), Self::fields(), where {dat_id, hash}, Self::from_row)?;
         Ok(matches)
     }
+
+    /// roms that have been given an alias with exactly this name
+    pub fn get_by_alias(conn: &Connection, dat_id: &DatId, name: &str) -> Result<Vec<RomRecord>> {
+        let mut stmt = conn.prepare_cached(
+            format!(
+                "SELECT {} FROM {} WHERE id IN (SELECT rom_id FROM {} WHERE dat_id = :dat_id AND name = :name) ORDER BY name",
+                Self::fields(),
+                Self::table_name(),
+                AliasRecord::table_name()
+            )
+            .as_str(),
+        )?;
+        let matches = stmt
+            .query_map(named_params! {":dat_id": dat_id.id(), ":name": name}, Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(matches)
+    }
+
+    /// roms that do not belong to a set in the same dat file
+    pub fn get_orphaned_by_dat(conn: &Connection, dat_id: &DatId) -> Result<Vec<RomRecord>> {
+        let mut stmt = conn.prepare(
+            format!(
+                "SELECT {} FROM {} WHERE dat_id = :dat_id AND set_id NOT IN (SELECT id FROM {} WHERE dat_id = :dat_id) ORDER BY name",
+                Self::fields(),
+                Self::table_name(),
+                SetRecord::table_name()
+            )
+            .as_str(),
+        )?;
+        let matches = stmt
+            .query_map(named_params! {":dat_id": dat_id.id()}, Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(matches)
+    }
 }
 
 impl DirRecord {
@@ -665,6 +1119,51 @@ impl DirRecord {
         Ok(matches)
     }
 
+    pub fn update_target_set(conn: &Connection, dir_id: &DirId, target_set_id: Option<&SetId>) -> Result<()> {
+        let sql = format!("UPDATE {} SET target_set_id = :target_set_id WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": dir_id,
+                ":target_set_id": target_set_id,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn update_hashed_with(conn: &Connection, dir_id: &DirId, hashed_with: &str) -> Result<()> {
+        let sql = format!("UPDATE {} SET hashed_with = :hashed_with WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": dir_id,
+                ":hashed_with": hashed_with,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn update_archive_state(conn: &Connection, dir_id: &DirId, size: u64, modified_at: Option<&str>) -> Result<()> {
+        let sql = format!("UPDATE {} SET size = :size, modified_at = :modified_at WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": dir_id,
+                ":size": SizeWrapper(size),
+                ":modified_at": modified_at,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn find_by_id(conn: &Connection, id: &DirId) -> Result<Option<DirRecord>> {
+        match sql_query_one!(conn, Self::table_name(), Self::fields(), where {id = id.id()}, Self::from_row) {
+            Ok(dir) => Ok(Some(dir)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => bail!(e),
+        }
+    }
+
     pub fn get_by_dat_path(conn: &Connection, dat_id: &DatId, path: &str) -> Result<Option<DirRecord>> {
         match sql_query_one!(conn, Self::table_name(), Self::fields(), where {path, dat_id}, Self::from_row
         ) {
@@ -687,6 +1186,10 @@ impl DirRecord {
         FileRecord::find_by_name(conn, &self.id, name, exact)
     }
 
+    pub fn glob_files(&self, conn: &Connection, glob: &str) -> Result<Vec<FileRecord>> {
+        FileRecord::find_by_pattern(conn, &self.id, &glob_to_like(glob))
+    }
+
     pub fn delete_files(&self, conn: &Connection) -> Result<usize> {
         FileRecord::delete_files(conn, &self.id)
     }
@@ -712,30 +1215,93 @@ impl FileRecord {
     }
 
     pub fn find_by_name(conn: &Connection, dir_id: &DirId, name: &str, exact: bool) -> Result<Vec<FileRecord>> {
-        let matches = if exact {
-            sql_query!(conn, Self::table_name(), FileRecord::fields(), where {dir_id, name}, order by "name", Self::from_row)
+        if exact {
+            let matches = sql_query!(conn, Self::table_name(), FileRecord::fields(), where {dir_id, name}, order by "name", Self::from_row)?;
+            Ok(matches)
         } else {
-            let mut stmt = conn.prepare(
-                format!(
-                    "SELECT {} FROM {} WHERE dir_id = (?1) AND name LIKE (?2) ORDER BY name",
-                    Self::fields(),
-                    Self::table_name()
-                )
-                .as_str(),
-            )?;
+            Self::find_by_pattern(conn, dir_id, &format!("%{}%", name.replace('\\', "\\\\")))
+        }
+    }
 
-            stmt.query_map(params![dir_id, format!("%{}%", name)], FileRecord::from_row)?
-                .collect::<Result<Vec<_>, _>>()
-        }?;
+    //the pattern is for LIKE, with `\` escaping the wildcards
+    pub fn find_by_pattern(conn: &Connection, dir_id: &DirId, pattern: &str) -> Result<Vec<FileRecord>> {
+        let mut stmt = conn.prepare(
+            format!(
+                "SELECT {} FROM {} WHERE dir_id = (?1) AND name LIKE (?2) ESCAPE '\\' ORDER BY name",
+                Self::fields(),
+                Self::table_name()
+            )
+            .as_str(),
+        )?;
+        let matches = stmt
+            .query_map(params![dir_id, pattern], FileRecord::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
         Ok(matches)
     }
 
     pub fn delete_files(conn: &Connection, dir_id: &DirId) -> Result<usize> {
+        //matches reference the files, so they have to go first
+        let sql = format!(
+            "DELETE FROM {} WHERE file_id IN (SELECT id FROM {} WHERE dir_id = :dir_id)",
+            MatchRecord::table_name(),
+            Self::table_name()
+        );
+        conn.execute(&sql, named_params! {":dir_id": dir_id})?;
+
         let sql = format!("DELETE FROM {} WHERE dir_id = :dir_id", Self::table_name());
         let num_deleted = conn.execute(&sql, named_params! {":dir_id": dir_id})?;
         Ok(num_deleted)
     }
 
+    pub fn update_hash(&self, conn: &Connection, size: u64, hash: &str, crc: Option<&str>) -> Result<Self> {
+        let sql = format!("UPDATE {} SET size = :size, hash = :hash, crc = :crc WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": self.id,
+                ":size": SizeWrapper(size),
+                ":hash": hash,
+                ":crc": crc,
+            },
+        )?;
+        Ok(Self {
+            size,
+            hash: hash.to_string(),
+            crc: crc.map(str::to_string),
+            ..self.clone()
+        })
+    }
+
+    pub fn rename(&self, conn: &Connection, name: &str) -> Result<Self> {
+        let sql = format!("UPDATE {} SET name = :name WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": self.id,
+                ":name": name,
+            },
+        )?;
+        Ok(Self {
+            name: name.to_string(),
+            ..self.clone()
+        })
+    }
+
+    pub fn update_modified_at(&self, conn: &Connection, modified_at: Option<&str>) -> Result<Self> {
+        let sql = format!("UPDATE {} SET modified_at = :modified_at WHERE id = :id", Self::table_name());
+        conn.execute(
+            &sql,
+            named_params! {
+                ":id": self.id,
+                ":modified_at": modified_at,
+            },
+        )?;
+        Ok(Self {
+            modified_at: modified_at.map(str::to_string),
+            ..self.clone()
+        })
+    }
+
     pub fn relink_files(conn: &Connection, old_dat_id: &DatId, new_dat_id: &DatId) -> Result<usize> {
         let sql = format!("UPDATE {} SET dat_id = :new_dat_id WHERE dat_id = :old_dat_id", Self::table_name());
         let num_updated = conn.execute(
@@ -756,11 +1322,23 @@ impl MatchRecord {
         Ok(matches)
     }
 
+    pub fn get_by_rom(conn: &Connection, rom_id: &RomId) -> Result<Vec<Self>> {
+        let matches =
+            sql_query!(conn, Self::table_name(), Self::fields(), where {rom_id}, order by "id", Self::from_row)?;
+        Ok(matches)
+    }
+
     pub fn get_by_file_status(conn: &Connection, file_id: &FileId, status: &str) -> Result<Vec<Self>> {
         let matches = sql_query!(conn, Self::table_name(), Self::fields(), where {file_id, status}, order by "id", Self::from_row)?;
         Ok(matches)
     }
 
+    pub fn delete_by_file(conn: &Connection, file_id: &FileId) -> Result<usize> {
+        let sql = format!("DELETE FROM {} WHERE file_id = :file_id", Self::table_name());
+        let num_deleted = conn.execute(&sql, named_params! {":file_id": file_id})?;
+        Ok(num_deleted)
+    }
+
     pub fn update(&self, conn: &Connection, status: &MatchStatus) -> Result<Self> {
         let sql = format!("UPDATE {} SET status = :status WHERE id = :id", Self::table_name());
         conn.execute(
@@ -781,6 +1359,31 @@ impl MatchRecord {
     }
 }
 
+pub fn get_setting(conn: &Connection, name: &str) -> Result<Option<String>> {
+    match conn
+        .query_one("SELECT value FROM settings WHERE name = :name", named_params! {":name": name}, |row| row.get(0))
+    {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => bail!(e),
+    }
+}
+
+//set a setting, or remove it to fall back to the default if there is no value
+pub fn set_setting(conn: &Connection, name: &str, value: Option<&str>) -> Result<()> {
+    match value {
+        Some(value) => conn.execute(
+            "INSERT INTO settings (name, value) VALUES (:name, :value) ON CONFLICT(name) DO UPDATE SET value = :value",
+            named_params! {":name": name, ":value": value},
+        )?,
+        None => conn.execute("DELETE FROM settings WHERE name = :name", named_params! {":name": name})?,
+    };
+    Ok(())
+}
+
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
 pub fn open_or_create<P: AsRef<Utf8Path>>(db_path: P) -> Result<Connection> {
     const CREATE_STATEMENTS: [&str; 15] = [
         /* dat file */
@@ -813,6 +1416,15 @@ pub fn open_or_create<P: AsRef<Utf8Path>>(db_path: P) -> Result<Connection> {
     ];
 
     let mut conn = Connection::open(db_path.as_ref())?;
+    //wal lets other connections read while a scan is writing, and they wait for a lock rather than failing straight away
+    let journal_mode: String = conn.query_row("PRAGMA journal_mode = WAL;", [], |row| row.get(0))?;
+    ensure!(
+        journal_mode.eq_ignore_ascii_case("wal") || journal_mode.eq_ignore_ascii_case("memory"),
+        "unable to use wal journal mode for the database, it is using {journal_mode}"
+    );
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    //the queries run for each scanned file are prepared once, so there needs to be room for all of them
+    conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
     conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
 
     for stmt in CREATE_STATEMENTS {
@@ -827,7 +1439,7 @@ pub fn open_or_create<P: AsRef<Utf8Path>>(db_path: P) -> Result<Connection> {
     Ok(conn)
 }
 
-fn run_migrations(conn: &Connection) -> Result<()> {
+pub fn get_schema_version(conn: &Connection) -> Result<i64> {
     let result: std::result::Result<Option<i64>, rusqlite::Error> =
         conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get(0));
     let version: Option<i64> = match result {
@@ -835,8 +1447,13 @@ fn run_migrations(conn: &Connection) -> Result<()> {
         Err(rusqlite::Error::QueryReturnedNoRows) => None,
         Err(e) => bail!(e),
     };
+    Ok(version.unwrap_or(0))
+}
 
-    if version.is_none() {
+fn run_migrations(conn: &Connection) -> Result<()> {
+    let version = get_schema_version(conn)?;
+
+    if version < 1 {
         // Migration 1: Move matches from duplicating files to a new table referenced by the file record.
         // This stops having the need for multiple file entries for the same file when it matches multiple roms
         // as well as allowing us to ditch the none status.
@@ -899,5 +1516,278 @@ fn run_migrations(conn: &Connection) -> Result<()> {
         conn.execute("INSERT INTO schema_version (version) VALUES (1)", [])?;
     }
 
+    if version < 2 {
+        // Migration 2: Track when a dat was imported and when it was last scanned against.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dats ADD COLUMN imported_at VARCHAR;
+            ALTER TABLE dats ADD COLUMN last_scanned_at VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (2)", [])?;
+    }
+
+    if version < 3 {
+        // Migration 3: Store user settings, such as the default extensions to exclude when scanning.
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS settings (
+                name VARCHAR PRIMARY KEY,
+                value VARCHAR NOT NULL
+            );
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (3)", [])?;
+    }
+
+    if version < 4 {
+        // Migration 4: Link clone sets to their parent set.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE sets ADD COLUMN parent_id INTEGER REFERENCES sets(id);
+            CREATE INDEX IF NOT EXISTS idx_sets_parent_id ON sets(parent_id);
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (4)", [])?;
+    }
+
+    if version < 5 {
+        // Migration 5: Alternate names that a rom can also be matched by.
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS rom_aliases (
+                id INTEGER PRIMARY KEY,
+                dat_id INTEGER NOT NULL REFERENCES dats(id),
+                rom_id INTEGER NOT NULL REFERENCES roms(id),
+                name VARCHAR NOT NULL,
+                UNIQUE(rom_id, name)
+            );
+            CREATE INDEX IF NOT EXISTS idx_rom_aliases_dat_name ON rom_aliases(dat_id, name);
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (5)", [])?;
+    }
+
+    if version < 6 {
+        // Migration 6: Hash of the dat file contents, to identify a dat file the same way on every machine.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dats ADD COLUMN content_hash VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (6)", [])?;
+    }
+
+    if version < 7 {
+        // Migration 7: Allow a dat to be removed while keeping its scanned files, to be rematched on import.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dats ADD COLUMN removed_at VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (7)", [])?;
+    }
+
+    if version < 8 {
+        // Migration 8: Record how files in zip archives are compressed, to find entries that are stored inefficiently.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE files ADD COLUMN compression VARCHAR;
+            ALTER TABLE files ADD COLUMN compressed_size VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (8)", [])?;
+    }
+
+    if version < 9 {
+        // Migration 9: Store the header detector a dat declares, so headered dumps can be matched.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dats ADD COLUMN header_detector VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (9)", [])?;
+    }
+
+    if version < 10 {
+        // Migration 10: Record when loose files were modified, so a full scan can skip hashing unchanged files.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE files ADD COLUMN modified_at VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (10)", [])?;
+    }
+
+    if version < 11 {
+        // Migration 11: Store where newer versions of a dat file can be found, to check for updates.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dats ADD COLUMN url VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (11)", [])?;
+    }
+
+    if version < 12 {
+        // Migration 12: Record the set an archive is named for, so it can be shown when none of its files match.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dirs ADD COLUMN target_set_id INTEGER;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (12)", [])?;
+    }
+
+    if version < 13 {
+        // Migration 13: Allow the mapping of rom names to names that can be used on disk to be changed per dat.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dats ADD COLUMN name_map VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (13)", [])?;
+    }
+
+    if version < 14 {
+        // Migration 14: Store the crc of roms as well as their hash, for matching without reading whole files.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE roms ADD COLUMN crc VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (14)", [])?;
+    }
+
+    if version < 15 {
+        // Migration 15: Allow how headers are skipped before hashing to be set per dat, over the dat's own detector.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dats ADD COLUMN header_rule VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (15)", [])?;
+    }
+
+    if version < 16 {
+        // Migration 16: Remember how the files in each dir were hashed, so they are hashed again when that changes.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dirs ADD COLUMN hashed_with VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (16)", [])?;
+    }
+
+    if version < 17 {
+        // Migration 17: Remember the size and modification time of archives, so changed ones are scanned again.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dirs ADD COLUMN size VARCHAR;
+            ALTER TABLE dirs ADD COLUMN modified_at VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (17)", [])?;
+    }
+
+    if version < 18 {
+        // Migration 18: Remember per dat whether matching by hash needs the size to match as well.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE dats ADD COLUMN strict_size BOOLEAN NOT NULL DEFAULT 0;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (18)", [])?;
+    }
+
+    if version < 19 {
+        // Migration 19: Store the crc of files as well, when the dat has crcs, hashed in the same pass as the file.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE files ADD COLUMN crc VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (19)", [])?;
+    }
+
+    if version < 20 {
+        // Migration 20: Index roms by crc, as zip entries are looked up by the crc stored in the archive.
+        conn.execute_batch(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_dat_roms_crc ON roms(dat_id, crc);
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (20)", [])?;
+    }
+
+    if version < 21 {
+        // Migration 21: Roms without a size in the dat file have no size, rather than a size of 0 that can't be
+        // told apart from an empty rom. Those stored as 0 without the hash of empty data were given no size.
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS roms_new (
+                id INTEGER PRIMARY KEY,
+                dat_id INTEGER NOT NULL,
+                set_id INTEGER NOT NULL,
+                name VARCHAR NOT NULL,
+                size VARCHAR,
+                hash VARCHAR NOT NULL,
+                crc VARCHAR,
+                FOREIGN KEY (dat_id) REFERENCES dats(id),
+                FOREIGN KEY (set_id) REFERENCES sets(id)
+            );
+
+            INSERT INTO roms_new (id, dat_id, set_id, name, size, hash, crc)
+                SELECT id, dat_id, set_id, name, size, hash, crc FROM roms;
+
+            UPDATE roms_new SET size = NULL WHERE size = '0' AND hash NOT IN (
+                '00000000',
+                'd41d8cd98f00b204e9800998ecf8427e',
+                'da39a3ee5e6b4b0d3255bfef95601890afd80709',
+                'e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855'
+            );
+
+            DROP TABLE roms;
+
+            ALTER TABLE roms_new RENAME TO roms;
+            CREATE INDEX IF NOT EXISTS idx_set_roms ON roms(set_id);
+            CREATE INDEX IF NOT EXISTS idx_dat_roms_name ON roms(dat_id, name);
+            CREATE INDEX IF NOT EXISTS idx_dat_roms_hash ON roms(dat_id, hash);
+            CREATE INDEX IF NOT EXISTS idx_dat_roms_crc ON roms(dat_id, crc);
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (21)", [])?;
+    }
+
+    if version < 22 {
+        // Migration 22: Keep the part and data area of software list roms, which were flattened into the software.
+        conn.execute_batch(
+            r#"
+            ALTER TABLE roms ADD COLUMN area VARCHAR;
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (22)", [])?;
+    }
+
+    if version < 23 {
+        // Migration 23: Bad dumps have their own status, apart from files that are only named the same as a rom
+        // without a size. Those matched by name with the same size as the rom are bad dumps, and those with a
+        // different size were never moved to name_only when it was added.
+        conn.execute_batch(
+            r#"
+            UPDATE matches SET status = 'corrupt' WHERE status = 'name' AND EXISTS (
+                SELECT 1 FROM roms, files
+                WHERE roms.id = matches.rom_id AND files.id = matches.file_id AND roms.size = files.size
+            );
+            UPDATE matches SET status = 'name_only' WHERE status = 'name' AND EXISTS (
+                SELECT 1 FROM roms, files
+                WHERE roms.id = matches.rom_id AND files.id = matches.file_id
+                AND roms.size IS NOT NULL AND roms.size <> files.size
+            );
+            "#,
+        )?;
+        conn.execute("INSERT INTO schema_version (version) VALUES (23)", [])?;
+    }
+
     Ok(())
 }
@@ -1,32 +1,28 @@
+mod dat;
 mod db;
+mod torrentzip;
 mod util;
 
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
-use std::io::{BufReader, IsTerminal, Write};
+use std::io::{BufReader, BufWriter, IsTerminal, Read, Write};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{Context, Result, anyhow, bail, ensure};
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::{Parser, Subcommand, ValueEnum};
-use roxmltree::{Document, ParsingOptions};
+use quick_xml::escape::escape;
+use rayon::prelude::*;
 use rusqlite::{Connection, Transaction, TransactionBehavior};
 
-use crate::db::{Deletable, DeletableByDat, FindableByName, Insertable, Queryable, QueryableByDat};
+use crate::db::{Deletable, DeletableByDat, FindableByName, HasId, Insertable, Queryable, QueryableByDat};
 
 const APP_NAME: &str = "rrm";
 
-// constants for XML dat file
-const TAG_HEADER: &str = "header";
-const ATTR_HEADER_NAME: &str = "name";
-const ATTR_HEADER_DESC: &str = "description";
-const ATTR_HEADER_VERSION: &str = "version";
-const ATTR_HEADER_AUTHOR: &str = "author";
-const TAG_GAME: &str = "game";
-const ATTR_GAME_NAME: &str = "name";
-const TAG_ROM: &str = "rom";
-const ATTR_ROM_NAME: &str = "name";
-const ATTR_ROM_SIZE: &str = "size";
-const ATTR_ROM_HASH: &str = "sha1";
+const SETTING_EXCLUDE: &str = "exclude";
+const DEFAULT_EXCLUDE: &str = "m3u,dat,txt";
 
 macro_rules! println_if {
     ($cond:expr, $($arg:tt)*) => {
@@ -40,9 +36,25 @@ macro_rules! println_if {
 #[clap(version, about, long_about = None)]
 struct Args {
     /// select the dat file to use
-    #[arg(short, long)]
+    #[arg(short, long, group = "selection")]
     select: Option<usize>,
 
+    /// select the dat file to use by its content hash, or the start of it
+    #[arg(long, conflicts_with = "select", group = "selection")]
+    select_hash: Option<String>,
+
+    /// also match files against these dat files, by index, as well as the selected one
+    #[arg(long, value_delimiter = ',', requires = "selection")]
+    also: Vec<usize>,
+
+    /// use this dat file in a temporary database, leaving the installed dat files and scans untouched
+    #[arg(long, conflicts_with = "selection", value_hint = clap::ValueHint::FilePath)]
+    dat: Option<Utf8PathBuf>,
+
+    /// use the database at this path instead of the one in the data directory, creating it if needed
+    #[arg(long, conflicts_with = "dat", value_hint = clap::ValueHint::FilePath)]
+    database: Option<Utf8PathBuf>,
+
     /// command to execute, if none given will enter interactive mode
     #[command(subcommand)]
     command: Option<Commands>,
@@ -50,6 +62,14 @@ struct Args {
     /// force enter interactive mode, if command is given
     #[arg(short, long)]
     interactive: bool,
+
+    /// the format to show file, set and rom listings in
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// how many copies of the database to keep from previous runs, 0 to keep none
+    #[arg(long, default_value_t = 3)]
+    backups: usize,
 }
 
 #[derive(Debug, Parser)]
@@ -57,6 +77,10 @@ struct Args {
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// the format to show this command's output in, instead of the one rrm was started with
+    #[arg(long, global = true, value_enum)]
+    format: Option<OutputFormat>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -74,12 +98,32 @@ enum Commands {
     /// Alias for `data select`
     Select {
         /// the index of the dat file to select, as seen in list
-        index: usize,
+        #[arg(required_unless_present = "hash")]
+        index: Option<usize>,
+        /// select by the dat file's id instead, which doesn't change when other dat files are removed
+        #[arg(long)]
+        id: bool,
+        /// select by the dat file's content hash, or the start of it, which is the same on every machine
+        #[arg(long, conflicts_with_all = ["index", "id"])]
+        hash: Option<String>,
+        /// also match files against these dat files, as well as the selected one
+        #[arg(long, value_delimiter = ',')]
+        also: Vec<usize>,
     },
+    /// show the database in use and its state
+    Info,
     /// exit from interactive mode
     Exit,
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// human readable text
+    Text,
+    /// structured json
+    Json,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, ValueEnum)]
 enum ListMode {
     /// list all files
@@ -92,19 +136,107 @@ enum ListMode {
     Unmatched,
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum MergeMode {
+    /// clones have their own set, with only the roms that are not in the parent
+    Split,
+    /// clones are part of their parent set, which has the roms of every clone
+    Merged,
+    /// clones have their own set, with the roms of the parent as well
+    NonMerged,
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum ListSort {
+    /// sort files by name, grouped by directory
+    Name,
+    /// sort files by size, largest first
+    Size,
+}
+
 #[derive(Debug, Subcommand)]
 enum FileCommands {
+    /// list the scanned directories and archives of the current dat file, with their ids
+    Dirs,
+    /// re-scan the files in one scanned directory, by its id as seen in dirs, without walking the whole tree
+    RescanDir {
+        /// the id of the directory to re-scan
+        id: i64,
+        /// hash every file again, even if it hasn't changed since it was last scanned
+        #[arg(long, default_value_t = false)]
+        force_rehash: bool,
+        /// extensions to exclude when scanning files, instead of the default list
+        #[arg(long, value_delimiter = ',')]
+        exclude: Option<Vec<String>>,
+        /// extensions to exclude when scanning files, as well as the default list
+        #[arg(long, value_delimiter = ',', conflicts_with = "exclude")]
+        exclude_add: Vec<String>,
+        /// match files by this hash instead of the dat file's hash type, either crc or the dat file's own
+        #[arg(long, value_enum)]
+        hash: Option<util::HashType>,
+        /// match consecutively numbered split files (.001, .002, ...) as a single file,
+        /// needed if the directory was scanned with it, or the joined files are lost
+        #[arg(long, default_value_t = false)]
+        join_splits: bool,
+        /// check files in zip archives against the crc stored in the archive, and warn if they differ
+        #[arg(long, default_value_t = false)]
+        verify_crc: bool,
+    },
     /// scan a path and match files with the current dat file
     Scan {
-        /// extensions to exclude when scanning files
-        #[arg(long, value_delimiter = ',', default_value = "m3u,dat,txt")]
-        exclude: Vec<String>,
+        /// extensions to exclude when scanning files, instead of the default list
+        #[arg(long, value_delimiter = ',')]
+        exclude: Option<Vec<String>>,
+        /// extensions to exclude when scanning files, as well as the default list
+        #[arg(long, value_delimiter = ',', conflicts_with = "exclude")]
+        exclude_add: Vec<String>,
         /// scan recursively each directory found
         #[arg(short('R'), long, default_value_t = false)]
         recursive: bool,
         /// re-scan existing files in the directory and not just new files
         #[arg(long, default_value_t = false)]
         full: bool,
+        /// hash every file in a full scan, even those that haven't changed since they were last scanned
+        #[arg(long, default_value_t = false, requires = "full")]
+        force_rehash: bool,
+        /// match files by this hash instead of the dat file's hash type, either crc or the dat file's own
+        #[arg(long, value_enum)]
+        hash: Option<util::HashType>,
+        /// show each set as soon as the scan completes it
+        #[arg(long, default_value_t = false)]
+        show_completions: bool,
+        /// show errors as they happen, as well as at the end of the scan
+        #[arg(short, long, default_value_t = false)]
+        verbose: bool,
+        /// match consecutively numbered split files (.001, .002, ...) as a single file
+        #[arg(long, default_value_t = false)]
+        join_splits: bool,
+        /// print each scanned file as a line of json as it is matched, instead of showing progress
+        #[arg(long, default_value_t = false)]
+        json_lines: bool,
+        /// limit how fast files are read in MB/s, to avoid saturating network mounts
+        #[arg(long)]
+        max_rate: Option<u64>,
+        /// check files in zip archives against the crc stored in the archive, and warn if they differ
+        /// (this also reads entries that would otherwise be matched by their stored crc alone)
+        #[arg(long, default_value_t = false)]
+        verify_crc: bool,
+        /// only match by hash if the size matches as well, to avoid false matches with weak hashes like crc
+        /// (this is remembered for the dat file, see `data strict-size`)
+        #[arg(long, default_value_t = false)]
+        strict_size: bool,
+        /// list only the files found by this scan when it finishes, with their status
+        #[arg(long, default_value_t = false, conflicts_with = "json_lines")]
+        only_new: bool,
+        /// commit to the database after every N files, instead of once at the end of the scan
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        commit_every: Option<u64>,
+        /// show how long the scan spent hashing, matching and writing to the database
+        #[arg(long, default_value_t = false)]
+        profile: bool,
+        /// hash at most this many files at once, defaults to the number of cores (a max rate is shared between them)
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        threads: Option<u64>,
         /// the path to use for scanning files
         #[arg(default_value=".", value_hint = clap::ValueHint::DirPath)]
         path: Utf8PathBuf,
@@ -114,6 +246,18 @@ enum FileCommands {
         /// show only files with this status
         #[arg(long, value_enum, default_value_t = ListMode::All)]
         mode: ListMode,
+        /// the order to list files in
+        #[arg(long, value_enum, default_value_t = ListSort::Name)]
+        sort: ListSort,
+        /// show at most this many files
+        #[arg(long)]
+        limit: Option<usize>,
+        /// show only the totals, without listing each file
+        #[arg(long, default_value_t = false)]
+        summary_only: bool,
+        /// treat the name as a glob pattern, with `*` and `?` wildcards, instead of a partial name
+        #[arg(long, requires = "partial_name")]
+        glob: bool,
         /// show only files partially matching this name
         partial_name: Option<String>,
     },
@@ -124,6 +268,9 @@ enum FileCommands {
     },
     /// alias for `sets --missing`
     Missing {
+        /// write the sets that aren't complete to a dat file, with only the roms still missing from each
+        #[arg(long, value_hint = clap::ValueHint::FilePath)]
+        write_dat: Option<Utf8PathBuf>,
         /// show only sets partially matching this name
         partial_name: Option<String>,
     },
@@ -132,11 +279,137 @@ enum FileCommands {
         /// show missing sets instead of matches
         #[arg(long, default_value_t = false)]
         missing: bool,
+        /// show only the totals, without listing each set
+        #[arg(long, default_value_t = false)]
+        summary_only: bool,
+        /// hide sets that are clones of another set
+        #[arg(long, default_value_t = false)]
+        parents_only: bool,
+        /// show clones nested under their parent set, with how many roms of each were found
+        #[arg(long, default_value_t = false, conflicts_with_all = ["missing", "parents_only"])]
+        tree: bool,
+        /// show only sets that have every rom, but need loose files renamed to be complete
+        #[arg(long, default_value_t = false, conflicts_with_all = ["missing", "tree"])]
+        renamable: bool,
+        /// show only how complete each found set is, least complete first
+        #[arg(long, default_value_t = false, conflicts_with_all = ["missing", "tree", "renamable"])]
+        percent: bool,
+        /// show the directory or archive of each matched file, to find sets spread across several of them
+        #[arg(long, default_value_t = false, conflicts_with_all = ["missing", "tree", "percent"])]
+        show_paths: bool,
+        /// write the sets that aren't complete to a dat file, with only the roms still missing from each
+        #[arg(long, requires = "missing", value_hint = clap::ValueHint::FilePath)]
+        write_dat: Option<Utf8PathBuf>,
+        /// how clones are laid out, which decides the roms each set needs to be complete
+        #[arg(long, value_enum, default_value_t = MergeMode::Split, conflicts_with = "tree")]
+        merge_mode: MergeMode,
         /// show only sets partially matching this name
         partial_name: Option<String>,
     },
     //rename files to the correct name (loose files only)
-    Rename,
+    Rename {
+        /// only keep the renames if every file is renamed successfully, otherwise restore them all
+        #[arg(long)]
+        atomic: bool,
+        /// only rename files in this directory and its subdirectories
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        path: Option<Utf8PathBuf>,
+    },
+    /// move unknown loose files into a directory to review them, instead of deleting them
+    MoveUnmatched {
+        /// the directory to move the files to, which is created if needed
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        dir: Utf8PathBuf,
+    },
+    /// link the matched loose files into a directory of sets, with the names from the dat file
+    Link {
+        /// the directory to create the sets in, which is created if needed
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        dir: Utf8PathBuf,
+    },
+    /// copy the matched files into a directory of sets, with the names from the dat file
+    Rebuild {
+        /// the directory to create the sets in, which is created if needed
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        output: Utf8PathBuf,
+        /// create a zip file for each set instead of a directory, written the same way as torrentzip
+        #[arg(long, default_value_t = false)]
+        zip: bool,
+        /// rebuild sets that have missing roms as well, with the roms that were found
+        #[arg(long, default_value_t = false)]
+        incomplete: bool,
+        /// don't ask for confirmation, and replace sets that already exist in the output
+        #[arg(long, default_value_t = false)]
+        yes: bool,
+        /// how clones are laid out, which decides the sets that are created and the roms in each
+        #[arg(long, value_enum, default_value_t = MergeMode::Split)]
+        merge_mode: MergeMode,
+    },
+    /// show or set the extensions excluded by default when scanning
+    Exclude {
+        /// reset the default list to the built in one
+        #[arg(long, conflicts_with = "extensions")]
+        reset: bool,
+        /// the extensions to exclude by default, comma separated
+        #[arg(value_delimiter = ',')]
+        extensions: Vec<String>,
+    },
+    /// re-hash scanned files and update their matches, without scanning for new files
+    Recheck {
+        /// only recheck files with a warning status
+        #[arg(long)]
+        warnings: bool,
+    },
+    /// check scanned files still exist and hash the same, without scanning for new files
+    Verify {
+        /// remove the records of files that are missing or have changed
+        #[arg(long)]
+        prune: bool,
+    },
+    /// write every scanned file and what it matched to a csv file, one row per match
+    Export {
+        /// the csv file to write
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        path: Utf8PathBuf,
+    },
+    /// list files that are not needed for the collection, either unknown or duplicates of another file
+    Extras,
+    /// list files that are duplicates of another file, which keeps every rom they match
+    Duplicates {
+        /// delete the duplicate loose files, keeping the other copy
+        #[arg(long)]
+        delete_extra: bool,
+        /// don't ask for confirmation, and perform the action
+        #[arg(long, requires = "delete_extra")]
+        yes: bool,
+    },
+    /// list incomplete sets that only one scanned file matches, which may be the start of another set
+    Orphans,
+    /// decompress every entry of each scanned zip file to check the archives are not corrupt
+    VerifyArchives,
+    /// show how each entry of the scanned zip files is compressed
+    Archives {
+        /// show only entries that are stored uncompressed, or barely compressed
+        #[arg(long)]
+        stored: bool,
+    },
+    /// show which scanned files satisfy a rom, found by hash or partial name
+    Locate {
+        /// the hash or partial name of the rom
+        rom: String,
+    },
+    /// write a report of the status of every set, to share the state of the collection
+    Report {
+        /// write the report as a self-contained html page to this file
+        #[arg(long, value_hint = clap::ValueHint::FilePath)]
+        html: Utf8PathBuf,
+    },
+    /// remove all scanned files and matches for the current dat file, so scanning starts fresh
+    Reset {
+        /// don't ask for confirmation, and perform the action
+        #[arg(long)]
+        yes: bool,
+    },
     /// alias for `list --mode unmatched`
     Unmatched {
         /// show only files partially matching this name
@@ -153,9 +426,17 @@ enum FileCommands {
 enum DataCommands {
     /// import a dat file into the system and make it the current dat file
     Import {
-        /// the path and filename of the dat file to import
+        /// the path and filename, or http(s) url, of the dat file to import
         #[arg(value_hint = clap::ValueHint::FilePath)]
         dat_file: Utf8PathBuf,
+
+        /// don't allow the dat file to be downloaded
+        #[arg(long)]
+        no_network: bool,
+
+        /// the hash to match files with, defaults to the strongest hash the dat file provides
+        #[arg(long, value_enum)]
+        hash_type: Option<util::HashType>,
     },
     /// update the current dat file with a new version and re-match files
     Update {
@@ -172,25 +453,151 @@ enum DataCommands {
         /// don't ask for confirmation, and perform the action
         #[arg(long)]
         yes: bool,
+        /// keep the scanned files, so importing the same dat file again rematches them without scanning
+        #[arg(long)]
+        keep_files: bool,
     },
     /// List dat files in the system
     List,
+    /// check the url of each installed dat file for a newer version
+    CheckUpdates {
+        /// don't allow the urls to be checked
+        #[arg(long)]
+        no_network: bool,
+    },
+    /// show or change how rom names are mapped to names that can be used on disk
+    NameMap {
+        /// replacements as from=to, applied in order to rom names
+        #[arg(conflicts_with_all = ["reset", "clear", "windows"])]
+        mappings: Vec<String>,
+        /// go back to the default mapping, of characters that windows doesn't allow on windows and none elsewhere
+        #[arg(long)]
+        reset: bool,
+        /// don't map rom names at all
+        #[arg(long, conflicts_with = "reset")]
+        clear: bool,
+        /// map the characters that windows doesn't allow, on any system
+        #[arg(long, conflicts_with_all = ["reset", "clear"])]
+        windows: bool,
+    },
+    /// show or set how a header is skipped before hashing files, instead of the dat file's header detector
+    Header {
+        /// a detector (ines, fds, a7800 or lynx), a number of bytes to skip from every file, or none
+        #[arg(conflicts_with = "reset")]
+        rule: Option<String>,
+        /// go back to the header detector of the dat file
+        #[arg(long)]
+        reset: bool,
+    },
+    /// show or set whether matching by hash needs the size to match as well, for dat files with weak hashes like crc
+    StrictSize {
+        /// on to only match by hash if the size matches as well, off to match by hash alone
+        #[arg(value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: Option<bool>,
+    },
     /// Select the current dat file
     Select {
         /// the index of the dat file to select, as seen in list
-        index: usize,
+        #[arg(required_unless_present = "hash")]
+        index: Option<usize>,
+        /// select by the dat file's id instead, which doesn't change when other dat files are removed
+        #[arg(long)]
+        id: bool,
+        /// select by the dat file's content hash, or the start of it, which is the same on every machine
+        #[arg(long, conflicts_with_all = ["index", "id"])]
+        hash: Option<String>,
+        /// also match files against these dat files, as well as the selected one
+        #[arg(long, value_delimiter = ',')]
+        also: Vec<usize>,
     },
     /// Show all Set and Roms in the current dat file
     Records,
+    /// show totals for the current dat file, and how much of it the scanned files cover
+    Stats,
+    /// show the sets and roms that differ between the current dat file and another one, without importing it
+    Diff {
+        /// the path and filename of the dat file to compare with
+        #[arg(value_hint = clap::ValueHint::FilePath, required_unless_present = "since_version")]
+        dat_file: Option<Utf8PathBuf>,
+        /// compare with an older version of the current dat file that is installed alongside it, by its version
+        #[arg(long, conflicts_with = "dat_file")]
+        since_version: Option<String>,
+    },
     /// Search for a Set in the current dat file
     Sets {
-        /// an optional partial name to match
-        partial_name: Option<String>,
+        /// only show sets matching all of the partial names, instead of any of them
+        #[arg(long)]
+        all: bool,
+        /// match the case of the partial names, instead of ignoring it
+        #[arg(long)]
+        case_sensitive: bool,
+        /// only match the partial names as whole words, instead of anywhere in a name
+        #[arg(long)]
+        whole_word: bool,
+        /// hide sets that are clones of another set
+        #[arg(long)]
+        parents_only: bool,
+        /// compare set names with another installed dat file, by index, listing the sets only in either of them
+        #[arg(long, conflicts_with_all = ["all", "partial_names"])]
+        missing_in: Option<usize>,
+        /// optional partial names to match
+        partial_names: Vec<String>,
+    },
+    /// export a dat file with one preferred version of each game, chosen by region (1G1R)
+    OneGameOneRom {
+        /// regions in order of preference, games without a version in any of these regions are left out
+        #[arg(long, value_delimiter = ',', required = true)]
+        regions: Vec<String>,
+        /// the path and filename of the dat file to write
+        #[arg(long, value_hint = clap::ValueHint::FilePath)]
+        output: Utf8PathBuf,
     },
     /// Search for a Rom in the current dat file
+    #[command(args_conflicts_with_subcommands = true)]
     Roms {
-        /// an optional partial name to match
-        partial_name: Option<String>,
+        /// only show roms in this set, partial names are allowed if they match a single set
+        #[arg(long)]
+        set: Option<String>,
+        /// only show roms matching all of the partial names, instead of any of them
+        #[arg(long)]
+        all: bool,
+        /// match the case of the partial names, instead of ignoring it
+        #[arg(long)]
+        case_sensitive: bool,
+        /// only match the partial names as whole words, instead of anywhere in a name
+        #[arg(long)]
+        whole_word: bool,
+        /// show roms that don't belong to any set, which means the dat file was not stored correctly
+        #[arg(long, conflicts_with_all = ["set", "all", "partial_names"])]
+        orphans: bool,
+        /// optional partial names to match
+        partial_names: Vec<String>,
+
+        #[command(subcommand)]
+        roms: Option<RomCommands>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum RomCommands {
+    /// manage alternate names that a rom will also be matched by
+    Alias {
+        #[command(subcommand)]
+        alias: AliasCommands,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AliasCommands {
+    /// add an alternate name for a rom, files with this name will match the rom as if it had the rom's name
+    Add {
+        /// only look for the rom in this set, partial names are allowed if they match a single set
+        #[arg(long)]
+        set: Option<String>,
+        /// the name of the rom in the dat file
+        rom: String,
+        /// the alternate name to match files with
+        name: String,
     },
 }
 
@@ -205,34 +612,152 @@ fn readline() -> Result<String> {
 struct TermInfo {
     tty_in: bool,
     tty_out: bool,
+    format: OutputFormat,
 }
 
-fn main() -> Result<()> {
-    let data_path = util::data_dir()
-        .context("could not resolve data directory for platform")?
-        .join(APP_NAME);
-    std::fs::create_dir_all(&data_path)?;
-    let db_path = data_path.join("rrm.db");
+impl TermInfo {
+    fn json(&self) -> bool {
+        self.format == OutputFormat::Json
+    }
+
+    //messages that aren't part of a listing go to stderr for json, so stdout can be parsed as is
+    fn notice(&self, message: &str) {
+        if self.json() {
+            eprintln!("{message}");
+        } else {
+            println!("{message}");
+        }
+    }
+}
+
+// The dat file that commands act on, and any others that files are matched against as well
+#[derive(Default)]
+struct Selection {
+    dat_id: Option<db::DatId>,
+    also: Vec<db::DatId>,
+}
+
+// The sets and roms of a dat file, which only change when a dat file is imported or updated
+struct DatCatalog {
+    dat_id: db::DatId,
+    sets: Vec<db::SetRecord>,
+    roms: Vec<db::RomRecord>,
+}
+
+// Keeps the catalog of the selected dat file between commands, so repeated listings in an
+// interactive session don't have to reload it each time
+#[derive(Default)]
+struct CatalogCache {
+    catalog: Option<DatCatalog>,
+}
+
+impl CatalogCache {
+    fn get(&mut self, conn: &Connection, dat_id: &db::DatId) -> Result<&DatCatalog> {
+        if self.catalog.as_ref().is_none_or(|catalog| &catalog.dat_id != dat_id) {
+            self.catalog = Some(DatCatalog {
+                dat_id: dat_id.clone(),
+                sets: db::SetRecord::get_by_dat(conn, dat_id)?,
+                roms: db::RomRecord::get_by_dat(conn, dat_id)?,
+            });
+        }
+        Ok(self.catalog.as_ref().expect("catalog should have been loaded"))
+    }
 
-    if db_path.exists() {
-        let bak = data_path.join("rrm.bak");
-        std::fs::copy(&db_path, &bak)?;
+    fn invalidate(&mut self) {
+        self.catalog = None;
     }
-    let mut conn = db::open_or_create(&db_path)?;
-    let mut dat_id = None;
+}
+
+//keeps copies of the database from the last few runs next to it, as rrm.bak.1 (the newest) to rrm.bak.N,
+//a copy is only made when the database has changed since the newest one
+//has to be checked before the database is opened, as opening it creates the write ahead log
+fn database_changed_at(db_path: &Utf8Path) -> Option<SystemTime> {
+    //writes may still be sitting in the write ahead log, so that counts as a change to the database too
+    let wal_path = Utf8PathBuf::from(format!("{db_path}-wal"));
+    [db_path, wal_path.as_path()]
+        .iter()
+        .filter_map(|path| path.metadata().and_then(|meta| meta.modified()).ok())
+        .max()
+}
+
+//has to run before open_or_create, so that the copy is of the database as it was before any migrations
+fn backup_database(db_path: &Utf8Path, changed_at: Option<SystemTime>, backups: usize) -> Result<()> {
+    let Some(changed_at) = changed_at.filter(|_| backups > 0) else {
+        return Ok(());
+    };
+    let backup_path = |n: usize| db_path.with_extension(format!("bak.{n}"));
+    let newest = backup_path(1);
+    //older versions kept a single rrm.bak, so that becomes the newest backup and gets rotated with the rest
+    let legacy = db_path.with_extension("bak");
+    if legacy.exists() && !newest.exists() {
+        std::fs::rename(&legacy, &newest)?;
+    } else if let Ok(backed_up_at) = newest.metadata().and_then(|meta| meta.modified())
+        && changed_at <= backed_up_at
+    {
+        return Ok(());
+    }
+    for n in (1..backups).rev() {
+        let from = backup_path(n);
+        if from.exists() {
+            std::fs::rename(&from, backup_path(n + 1))?;
+        }
+    }
+    //sqlite will not vacuum into a file that already exists, which is the case when only one backup is kept
+    if newest.exists() {
+        std::fs::remove_file(&newest)?;
+    }
+    //copying the file would miss anything not yet checkpointed from the log, so let sqlite write a consistent copy
+    let conn = Connection::open(db_path)?;
+    conn.execute("VACUUM INTO ?1", [newest.as_str()])
+        .with_context(|| format!("could not back up the database to {newest}"))?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut conn = if args.dat.is_some() {
+        db::open_or_create(":memory:")?
+    } else {
+        let db_path = match &args.database {
+            Some(db_path) => db_path.clone(),
+            None => util::data_dir()
+                .context("could not resolve data directory for platform")?
+                .join(APP_NAME)
+                .join("rrm.db"),
+        };
+        if let Some(data_path) = db_path.parent().filter(|path| !path.as_str().is_empty()) {
+            std::fs::create_dir_all(data_path)?;
+        }
+
+        backup_database(&db_path, database_changed_at(&db_path), args.backups)?;
+        db::open_or_create(&db_path)?
+    };
+    let mut selection = Selection::default();
+    let mut cache = CatalogCache::default();
 
     let term = TermInfo {
         tty_in: std::io::stdin().is_terminal(),
         tty_out: std::io::stdout().is_terminal(),
+        format: args.format,
     };
 
-    let args = Args::parse();
-    if let Some(index) = args.select {
+    if let Some(dat_file) = &args.dat {
+        ensure!(dat_file.is_file(), "`{}` is not a valid file", dat_file);
+        let (dat, counts) = import_dat(&mut conn, dat_file, None)?;
+        term.notice(&format!("dat file `{}` loaded, {counts}.", dat.name));
+        selection.dat_id = Some(dat.id);
+    } else if args.select.is_some() || args.select_hash.is_some() {
         do_command(
             &mut conn,
-            &mut dat_id,
+            &mut selection,
+            &mut cache,
             &Commands::Data {
-                data: DataCommands::Select { index },
+                data: DataCommands::Select {
+                    index: args.select,
+                    id: false,
+                    hash: args.select_hash.clone(),
+                    also: args.also.clone(),
+                },
             },
             &term,
         )?;
@@ -244,10 +769,15 @@ fn main() -> Result<()> {
             .and_then(|path| Utf8PathBuf::try_from(path).ok())
         {
             let paths = db::DirRecord::get_by_path(&conn, current_path.as_str())?;
-            if !paths.is_empty() {
-                let dat = db::DatRecord::get_by_id(&conn, &paths[0].dat_id)?;
-                println!("dat file `{}` selected.", dat.name);
-                dat_id = Some(dat.id);
+            //files kept from a removed dat file are still linked to it, so skip those
+            let dat = paths
+                .iter()
+                .map(|path| db::DatRecord::get_by_id(&conn, &path.dat_id))
+                .find(|dat| dat.as_ref().map_or(true, |dat| dat.removed_at.is_none()))
+                .transpose()?;
+            if let Some(dat) = dat {
+                term.notice(&format!("dat file `{}` selected.", dat.name));
+                selection.dat_id = Some(dat.id);
             } else {
                 eprintln!("No default dat file for current path.");
             }
@@ -257,7 +787,7 @@ fn main() -> Result<()> {
     }
 
     let interactive = if let Some(command) = args.command {
-        do_command(&mut conn, &mut dat_id, &command, &term)?;
+        do_command(&mut conn, &mut selection, &mut cache, &command, &term)?;
         args.interactive
     } else {
         true
@@ -273,14 +803,20 @@ fn main() -> Result<()> {
 
             if let Some(args) = shlex::split(line) {
                 match Cli::try_parse_from(args) {
-                    Ok(cli) => match do_command(&mut conn, &mut dat_id, &cli.command, &term) {
-                        Ok(exit) => {
-                            if exit {
-                                break;
+                    Ok(cli) => {
+                        let term = TermInfo {
+                            format: cli.format.unwrap_or(term.format),
+                            ..term
+                        };
+                        match do_command(&mut conn, &mut selection, &mut cache, &cli.command, &term) {
+                            Ok(exit) => {
+                                if exit {
+                                    break;
+                                }
                             }
+                            Err(e) => eprintln!("Unable to perform command, {e}"),
                         }
-                        Err(e) => eprintln!("Unable to perform command, {e}"),
-                    },
+                    }
                     Err(e) => e.print()?,
                 };
             } else {
@@ -293,21 +829,50 @@ fn main() -> Result<()> {
 
 fn do_command(
     conn: &mut Connection,
-    dat_id: &mut Option<db::DatId>,
+    selection: &mut Selection,
+    cache: &mut CatalogCache,
     command: &Commands,
     term: &TermInfo,
 ) -> Result<bool> {
     match command {
         Commands::Data { data } => {
-            handle_data_commands(conn, dat_id, term, data)?;
+            //most data commands change the sets and roms, so don't try to keep track of which
+            cache.invalidate();
+            handle_data_commands(conn, selection, term, data)?;
+            Ok(false)
+        }
+        Commands::Files { files } if !selection.also.is_empty() && files.matches_all_dats() => {
+            //run once per dat file, so every line of output is attributed to the dat file it is for
+            let dat_ids = selection.dat_id.iter().chain(&selection.also);
+            for dat_id in dat_ids {
+                let dat = db::DatRecord::get_by_id(conn, dat_id)?;
+                let heading = format!("=== DAT FILE `{}` ===", dat.name);
+                //keep stdout to just the json lines when scanning with them
+                if matches!(files, FileCommands::Scan { json_lines: true, .. }) {
+                    eprintln!("{heading}");
+                } else {
+                    println!("{heading}");
+                }
+                handle_file_commands(conn, Some(dat_id), &[], cache, term, files)?;
+            }
             Ok(false)
         }
         Commands::Files { files } => {
-            handle_file_commands(conn, dat_id.as_ref(), term, files)?;
+            handle_file_commands(conn, selection.dat_id.as_ref(), &selection.also, cache, term, files)?;
+            Ok(false)
+        }
+        Commands::Select { index, id, hash, also } => {
+            let select = DataCommands::Select {
+                index: *index,
+                id: *id,
+                hash: hash.clone(),
+                also: also.clone(),
+            };
+            handle_data_commands(conn, selection, term, &select)?;
             Ok(false)
         }
-        Commands::Select { index } => {
-            handle_data_commands(conn, dat_id, term, &DataCommands::Select { index: *index })?;
+        Commands::Info => {
+            show_info(conn, &term.format)?;
             Ok(false)
         }
         Commands::Exit => Ok(true),
@@ -316,61 +881,253 @@ fn do_command(
 
 fn handle_data_commands(
     conn: &mut Connection,
-    dat_id: &mut Option<db::DatId>,
+    selection: &mut Selection,
     term: &TermInfo,
     data: &DataCommands,
 ) -> Result<()> {
+    let Selection { dat_id, also } = selection;
     match data {
-        DataCommands::Import { dat_file } => {
-            ensure!(dat_file.is_file(), "`{}` is not a valid file", dat_file);
+        DataCommands::Import {
+            dat_file,
+            no_network,
+            hash_type,
+        } => {
+            let download = if util::is_url(dat_file.as_str()) {
+                ensure!(!no_network, "`{}` is a url, but network access is disabled", dat_file);
+                let download =
+                    util::download(dat_file.as_str()).with_context(|| format!("Unable to download `{dat_file}`"))?;
+                Some(download)
+            } else {
+                ensure!(dat_file.is_file(), "`{}` is not a valid file", dat_file);
+                None
+            };
+            let url = download.is_some().then_some(dat_file.as_str());
+            let dat_file = download.as_ref().map_or(dat_file.as_path(), |download| download.path());
 
-            import_dat(conn, dat_file).map(|imported| {
-                println!("dat file `{}` imported and selected.", imported.name);
-                *dat_id = Some(imported.id);
-            })
+            let (imported, counts) = import_dat(conn, dat_file, *hash_type)?;
+            //a dat file downloaded without a url of its own can be checked for updates where it came from
+            if let (None, Some(url)) = (&imported.url, url) {
+                db::DatRecord::update_url(conn, &imported.id, url)?;
+            }
+            println!("dat file `{}` imported and selected, {counts}.", imported.name);
+            *dat_id = Some(imported.id);
+            also.clear();
+            Ok(())
         }
         DataCommands::Update { dat_file, yes } => {
             ensure!(dat_id.is_some(), "No dat file selected");
 
             if ask_for_confirmation(term, "Are you sure you want to update the current dat file? (y/N): ", *yes)? {
                 let old_dat_id = dat_id.take().expect("Option should contain data");
-                update_dat(conn, dat_file, old_dat_id).map(|imported| {
-                    println!("dat file `{}` imported and updated.", imported.name);
+                update_dat(conn, term, dat_file, old_dat_id).map(|(imported, counts)| {
+                    println!("dat file `{}` imported and updated, {counts}.", imported.name);
                     *dat_id = Some(imported.id);
                 })?;
             }
             Ok(())
         }
-        DataCommands::Remove { yes } => {
+        DataCommands::Remove { yes, keep_files } => {
             ensure!(dat_id.is_some(), "No dat file selected");
 
             if ask_for_confirmation(term, "Are you sure you want to remove the current dat file? (y/N): ", *yes)? {
                 let old_dat_id = dat_id.take().expect("Option should contain data");
-                delete_dat(conn, old_dat_id).map(|_| {
+                also.clear();
+                if *keep_files {
+                    remove_dat_keep_files(conn, old_dat_id)?;
+                    println!("dat file removed, scanned files kept.");
+                } else {
+                    delete_dat(conn, old_dat_id)?;
                     println!("dat file removed.");
-                    *dat_id = None;
-                })?;
+                }
             }
             Ok(())
         }
         DataCommands::List => list_dat_files(conn),
-        DataCommands::Select { index } => db::DatRecord::get_all(conn).and_then(|dats| {
-            let dat = dats.get(*index).ok_or_else(|| anyhow!("Invalid dat file selection."))?;
-            println!("dat file `{}` selected.", dat.name);
+        DataCommands::CheckUpdates { no_network } => check_dat_updates(conn, *no_network),
+        DataCommands::Header { rule, reset } => {
+            let dat_id = dat_id.as_ref().context("No dat file selected")?;
+            if let Some(rule) = rule {
+                ensure!(
+                    rule.eq_ignore_ascii_case("none") || util::HeaderRule::parse(rule).is_some(),
+                    "Unknown header rule `{rule}`, expected one of {}, a number of bytes or none",
+                    util::HeaderRule::detector_names().collect::<Vec<_>>().join(", ")
+                );
+                db::DatRecord::update_header_rule(conn, dat_id, Some(&rule.to_ascii_lowercase()))?;
+            } else if *reset {
+                db::DatRecord::update_header_rule(conn, dat_id, None)?;
+            }
+            let dat = db::DatRecord::get_by_id(conn, dat_id)?;
+            match (&dat.header_rule, &dat.header_detector) {
+                (Some(rule), _) if rule == "none" => println!("Headers are not skipped."),
+                (Some(rule), _) => match rule.parse::<usize>() {
+                    Ok(size) => println!("The first {size} bytes of every file are skipped."),
+                    Err(_) => println!("Headers are skipped with the `{rule}` detector."),
+                },
+                (None, Some(detector)) => println!("Headers are skipped with the dat file's `{detector}` detector."),
+                (None, None) => println!("Headers are not skipped, the dat file has no header detector."),
+            }
+            if rule.is_some() || *reset {
+                println!("Rescan files to hash them again with the new header rule, they are not matched until then.");
+            }
+            Ok(())
+        }
+        DataCommands::StrictSize { enabled } => {
+            let dat_id = dat_id.as_ref().context("No dat file selected")?;
+            if let Some(enabled) = enabled {
+                db::DatRecord::update_strict_size(conn, dat_id, *enabled)?;
+            }
+            if db::DatRecord::get_by_id(conn, dat_id)?.strict_size {
+                println!("Files are only matched by hash if their size matches as well.");
+            } else {
+                println!("Files are matched by hash whatever their size.");
+            }
+            if enabled.is_some() {
+                println!("Rescan or recheck files to match them with the new setting.");
+            }
+            Ok(())
+        }
+        DataCommands::NameMap {
+            mappings,
+            reset,
+            clear,
+            windows,
+        } => {
+            let dat_id = dat_id.as_ref().context("No dat file selected")?;
+            let pairs = if *clear {
+                Some(Vec::new())
+            } else if *windows {
+                Some(util::NameMap::windows().pairs().to_vec())
+            } else if mappings.is_empty() {
+                None
+            } else {
+                Some(
+                    mappings
+                        .iter()
+                        .map(|m| util::NameMap::parse_pair(m))
+                        .collect::<Result<Vec<_>>>()?,
+                )
+            };
+            if let Some(pairs) = &pairs {
+                db::DatRecord::update_name_map(conn, dat_id, Some(&serde_json::to_string(pairs)?))?;
+            } else if *reset {
+                db::DatRecord::update_name_map(conn, dat_id, None)?;
+            }
+            let name_map = resolve_name_map(conn, dat_id)?;
+            if name_map.pairs().is_empty() {
+                println!("Rom names are not mapped.");
+            }
+            for (from, to) in name_map.pairs() {
+                println!("`{from}` -> `{to}`");
+            }
+            if pairs.is_some() || *reset {
+                println!("Rescan or recheck files to match them with the new mapping.");
+            }
+            Ok(())
+        }
+        DataCommands::Select {
+            index,
+            id,
+            hash,
+            also: also_indexes,
+        } => db::DatRecord::get_installed(conn).and_then(|dats| {
+            let by_index = |index: usize| {
+                if *id { dats.iter().find(|dat| dat.id.id() == index as i64) } else { dats.get(index) }
+            };
+            let dat = match (index, hash) {
+                (_, Some(hash)) => find_dat_by_hash(&dats, hash)?,
+                (Some(index), None) => by_index(*index),
+                (None, None) => None,
+            };
+            let dat = dat.ok_or_else(|| anyhow!("Invalid dat file selection."))?;
+            let mut also_dats = Vec::new();
+            for index in also_indexes {
+                let also_dat = by_index(*index).ok_or_else(|| anyhow!("Invalid dat file selection `{index}`."))?;
+                if also_dat.id != dat.id && !also_dats.contains(&also_dat) {
+                    also_dats.push(also_dat);
+                }
+            }
+            term.notice(&format!("dat file `{}` selected.", dat.name));
+            for also_dat in &also_dats {
+                term.notice(&format!("dat file `{}` also selected.", also_dat.name));
+            }
             *dat_id = Some(dat.id.clone());
+            *also = also_dats.into_iter().map(|dat| dat.id.clone()).collect();
             Ok(())
         }),
+        DataCommands::OneGameOneRom { regions, output } => {
+            let dat_id = dat_id.as_ref().ok_or_else(|| anyhow!("No dat file selected"))?;
+            let (written, total) = export_one_game_one_rom(conn, dat_id, regions, output)?;
+            println!("{written} of {total} games written to {output}.");
+            Ok(())
+        }
         DataCommands::Records => {
             let dat_id = dat_id.as_ref().ok_or_else(|| anyhow!("No dat file selected"))?;
-            list_dat_records(conn, dat_id)
+            list_dat_records(conn, dat_id, term)
+        }
+        DataCommands::Stats => {
+            let dat_id = dat_id.as_ref().ok_or_else(|| anyhow!("No dat file selected"))?;
+            show_dat_stats(conn, dat_id, term)
+        }
+        DataCommands::Diff {
+            dat_file,
+            since_version,
+        } => {
+            let dat_id = dat_id.as_ref().ok_or_else(|| anyhow!("No dat file selected"))?;
+            match since_version {
+                Some(version) => diff_since_version(conn, dat_id, term, version),
+                None => diff_dat(conn, dat_id, term, dat_file.as_deref().expect("clap should require a dat file")),
+            }
+        }
+        DataCommands::Sets {
+            all,
+            case_sensitive,
+            whole_word,
+            parents_only,
+            missing_in,
+            partial_names,
+        } => {
+            let dat_id = dat_id.as_ref().ok_or_else(|| anyhow!("No dat file selected"))?;
+            let search = db::NameSearch {
+                match_all: *all,
+                ignore_case: !*case_sensitive,
+                whole_word: *whole_word,
+            };
+            match missing_in {
+                Some(index) => compare_set_names(conn, dat_id, *index, *parents_only),
+                None => find_sets_by_name(conn, dat_id, term, partial_names, &search, *parents_only),
+            }
         }
-        DataCommands::Sets { partial_name } => {
+        DataCommands::Roms {
+            roms: Some(RomCommands::Alias { alias }),
+            ..
+        } => {
             let dat_id = dat_id.as_ref().ok_or_else(|| anyhow!("No dat file selected"))?;
-            find_sets_by_name(conn, dat_id, partial_name.as_deref())
+            match alias {
+                AliasCommands::Add { set, rom, name } => add_rom_alias(conn, dat_id, set.as_deref(), rom, name),
+            }
         }
-        DataCommands::Roms { partial_name } => {
+        DataCommands::Roms {
+            set,
+            all,
+            case_sensitive,
+            whole_word,
+            orphans,
+            partial_names,
+            roms: None,
+        } => {
             let dat_id = dat_id.as_ref().ok_or_else(|| anyhow!("No dat file selected"))?;
-            find_roms(conn, dat_id, partial_name.as_deref())
+            let search = db::NameSearch {
+                match_all: *all,
+                ignore_case: !*case_sensitive,
+                whole_word: *whole_word,
+            };
+            if *orphans {
+                list_orphaned_roms(conn, dat_id, term)
+            } else if let Some(set) = set {
+                list_set_roms(conn, dat_id, term, set, partial_names, &search)
+            } else {
+                find_roms(conn, dat_id, term, partial_names, &search)
+            }
         }
     }
 }
@@ -391,73 +1148,445 @@ fn ask_for_confirmation(term: &TermInfo, prompt: &str, skip: bool) -> Result<boo
 fn handle_file_commands(
     conn: &mut Connection,
     dat_id: Option<&db::DatId>,
+    also: &[db::DatId],
+    cache: &mut CatalogCache,
     term: &TermInfo,
     files: &FileCommands,
 ) -> Result<()> {
-    let dat_id = dat_id.ok_or_else(|| anyhow!("No dat file selected"))?;
+    //settings apply to every dat file, so only the commands that use one need a dat file selected
+    let selected = || dat_id.ok_or_else(|| anyhow!("No dat file selected"));
+    let dat_ids = || -> Result<Vec<_>> { Ok(std::iter::once(selected()?).chain(also).cloned().collect()) };
 
     match files {
+        FileCommands::Dirs => list_dirs(conn, selected()?, term),
+        FileCommands::RescanDir {
+            id,
+            force_rehash,
+            exclude,
+            exclude_add,
+            hash,
+            join_splits,
+            verify_crc,
+        } => {
+            let dat_id = selected()?;
+            let dir = db::DirRecord::find_by_id(conn, &db::DirId::from(*id))?
+                .filter(|dir| &dir.dat_id == dat_id)
+                .ok_or_else(|| anyhow!("No scanned directory with id {id} for the current dat file"))?;
+            let scan_path = Utf8PathBuf::from(&dir.path);
+            ensure!(
+                !util::is_archive_file(&scan_path),
+                "`{scan_path}` is an archive, re-scan the directory it is in instead"
+            );
+            ensure!(scan_path.is_dir(), "`{}` is not a valid directory", scan_path);
+            let exclude = resolve_exclude(conn, exclude.as_deref(), exclude_add)?;
+            let options = ScanOptions {
+                exclude: &exclude,
+                recursive: false,
+                incremental: false,
+                force_rehash: *force_rehash,
+                hash_type: resolve_hash_type(conn, dat_id)?,
+                hash_types: resolve_hash_types(conn, dat_id)?,
+                header: resolve_header_rule(conn, dat_id)?,
+                show_completions: false,
+                verbose: false,
+                join_splits: *join_splits,
+                json_lines: false,
+                rate_limit: None,
+                verify_crc: *verify_crc,
+                matching: MatchOptions {
+                    by_crc: resolve_match_by_crc(conn, dat_id, *hash)?,
+                    ..MatchOptions::new(conn, dat_id)?
+                },
+                only_new: false,
+                commit_every: None,
+                pool: hashing_pool(None)?,
+            };
+            cache.invalidate();
+            scan_files(conn, dat_id, term, &scan_path, &options)
+        }
         FileCommands::Scan {
             exclude,
+            exclude_add,
             recursive,
             full,
+            force_rehash,
+            hash,
+            show_completions,
+            verbose,
+            join_splits,
+            json_lines,
+            max_rate,
+            verify_crc,
+            strict_size,
+            only_new,
+            commit_every,
+            profile,
+            threads,
             path,
         } => {
+            let dat_id = selected()?;
             //make sure path is resolved to something absolute and proper before scanning
             let scan_path = path.canonicalize_utf8()?;
             ensure!(scan_path.is_dir(), "`{}` is not a valid directory", scan_path);
-            scan_files(conn, dat_id, term, &scan_path, exclude, *recursive, !full)
+            let exclude = resolve_exclude(conn, exclude.as_deref(), exclude_add)?;
+            //later rematches use the setting too, so it has to be kept with the dat file
+            if *strict_size {
+                db::DatRecord::update_strict_size(conn, dat_id, true)?;
+            }
+            let options = ScanOptions {
+                exclude: &exclude,
+                recursive: *recursive,
+                incremental: !full,
+                force_rehash: *force_rehash,
+                hash_type: resolve_hash_type(conn, dat_id)?,
+                hash_types: resolve_hash_types(conn, dat_id)?,
+                header: resolve_header_rule(conn, dat_id)?,
+                show_completions: *show_completions,
+                verbose: *verbose,
+                join_splits: *join_splits,
+                json_lines: *json_lines,
+                rate_limit: util::RateLimiter::new(*max_rate),
+                verify_crc: *verify_crc,
+                matching: MatchOptions {
+                    by_crc: resolve_match_by_crc(conn, dat_id, *hash)?,
+                    profile: profile.then(ScanProfile::default),
+                    ..MatchOptions::new(conn, dat_id)?
+                },
+                only_new: *only_new,
+                commit_every: *commit_every,
+                pool: hashing_pool(*threads)?,
+            };
+            cache.invalidate();
+            scan_files(conn, dat_id, term, &scan_path, &options)
+        }
+        FileCommands::List {
+            mode,
+            sort,
+            limit,
+            summary_only,
+            glob,
+            partial_name,
+        } => {
+            let options = ListOptions {
+                mode,
+                sort,
+                limit: *limit,
+                summary_only: *summary_only,
+                partial_name: partial_name.as_deref(),
+                glob: *glob,
+            };
+            list_files(conn, &dat_ids()?, term, &options)
+        }
+        FileCommands::Sets {
+            tree: true,
+            summary_only,
+            partial_name,
+            ..
+        } => list_sets_tree(conn, selected()?, term, *summary_only, partial_name.as_deref()),
+        FileCommands::Sets {
+            missing,
+            summary_only,
+            parents_only,
+            renamable,
+            percent,
+            show_paths,
+            write_dat,
+            merge_mode,
+            partial_name,
+            ..
+        } => {
+            let dat_id = selected()?;
+            let options = SetListOptions {
+                write_dat: write_dat.as_deref(),
+                merge_mode: *merge_mode,
+                missing: *missing,
+                summary_only: *summary_only,
+                parents_only: *parents_only,
+                renamable: *renamable,
+                percent: *percent,
+                show_paths: *show_paths,
+                partial_name: partial_name.as_deref(),
+            };
+            list_sets(conn, dat_id, cache.get(conn, dat_id)?, term, &options)
+        }
+        FileCommands::Rename { atomic, path } => {
+            let dat_id = selected()?;
+            //resolve the path the same way as scanning, so it matches the stored directories
+            let path = path.as_ref().map(|path| path.canonicalize_utf8()).transpose()?;
+            rename_files(conn, dat_id, term, *atomic, path.as_deref())
+        }
+        FileCommands::MoveUnmatched { dir } => move_unmatched_files(conn, selected()?, dir),
+        FileCommands::Link { dir } => link_matched_files(conn, selected()?, dir),
+        FileCommands::Rebuild {
+            output,
+            zip,
+            incomplete,
+            yes,
+            merge_mode,
+        } => {
+            let dat_id = selected()?;
+            let options = RebuildOptions {
+                zip: *zip,
+                incomplete: *incomplete,
+                yes: *yes,
+                merge_mode: *merge_mode,
+            };
+            rebuild_sets(conn, dat_id, term, output, &options)
+        }
+        FileCommands::Exclude { reset, extensions } => default_exclude(conn, *reset, extensions),
+        FileCommands::Export { path } => {
+            let dat_id = selected()?;
+            let rows = export_files_csv(conn, dat_id, path)?;
+            println!("{rows} rows written to {path}.");
+            Ok(())
+        }
+        FileCommands::Extras => list_extra_files(conn, selected()?, term),
+        FileCommands::Duplicates { delete_extra, yes } => {
+            list_duplicate_files(conn, selected()?, term, *delete_extra, *yes)
+        }
+        FileCommands::Orphans => list_orphan_matches(conn, selected()?, term),
+        FileCommands::Report { html } => {
+            let dat_id = selected()?;
+            write_html_report(conn, dat_id, html)?;
+            println!("report written to {html}.");
+            Ok(())
         }
-        FileCommands::List { mode, partial_name } => list_files(conn, dat_id, term, mode, partial_name.as_deref()),
-        FileCommands::Sets { missing, partial_name } => {
-            list_sets(conn, dat_id, term, *missing, partial_name.as_deref())
+        FileCommands::Reset { yes } => {
+            let dat_id = selected()?;
+            if ask_for_confirmation(term, "Are you sure you want to remove all scanned files? (y/N): ", *yes)? {
+                let count = reset_files(conn, dat_id)?;
+                println!("{count} scanned files removed.");
+            }
+            Ok(())
         }
-        FileCommands::Rename => rename_files(conn, dat_id, term),
+        FileCommands::VerifyArchives => verify_archives(conn, selected()?, term),
+        FileCommands::Archives { stored } => list_archive_compression(conn, selected()?, term, *stored),
+        FileCommands::Locate { rom } => locate_roms(conn, selected()?, term, rom),
+        FileCommands::Recheck { warnings } => recheck_files(conn, selected()?, term, *warnings),
+        FileCommands::Verify { prune } => verify_files(conn, selected()?, term, *prune),
         FileCommands::Matched { partial_name } => {
-            list_files(conn, dat_id, term, &ListMode::Matched, partial_name.as_deref())
+            list_files(conn, &dat_ids()?, term, &ListOptions::new(&ListMode::Matched, partial_name.as_deref()))
+        }
+        FileCommands::Missing {
+            write_dat,
+            partial_name,
+        } => {
+            let dat_id = selected()?;
+            let options = SetListOptions {
+                missing: true,
+                write_dat: write_dat.as_deref(),
+                ..SetListOptions::new(partial_name.as_deref())
+            };
+            list_sets(conn, dat_id, cache.get(conn, dat_id)?, term, &options)
         }
-        FileCommands::Missing { partial_name } => list_sets(conn, dat_id, term, true, partial_name.as_deref()),
         FileCommands::Unmatched { partial_name } => {
-            list_files(conn, dat_id, term, &ListMode::Unmatched, partial_name.as_deref())
+            list_files(conn, &dat_ids()?, term, &ListOptions::new(&ListMode::Unmatched, partial_name.as_deref()))
         }
         FileCommands::Warning { partial_name } => {
-            list_files(conn, dat_id, term, &ListMode::Warning, partial_name.as_deref())
+            list_files(conn, &dat_ids()?, term, &ListOptions::new(&ListMode::Warning, partial_name.as_deref()))
+        }
+    }
+}
+
+impl FileCommands {
+    //commands that only look at a dat file's sets or add files are safe to repeat for every selected dat file,
+    //listing files takes them all at once, and the others change files on disk so only act on the selected one
+    fn matches_all_dats(&self) -> bool {
+        matches!(
+            self,
+            FileCommands::Scan { .. }
+                | FileCommands::Missing { write_dat: None, .. }
+                | FileCommands::Sets { write_dat: None, .. }
+                | FileCommands::Locate { .. }
+        )
+    }
+}
+
+fn get_default_exclude(conn: &Connection) -> Result<Vec<String>> {
+    let exclude = db::get_setting(conn, SETTING_EXCLUDE)?.unwrap_or_else(|| DEFAULT_EXCLUDE.to_string());
+    Ok(exclude
+        .split(',')
+        .filter(|ext| !ext.is_empty())
+        .map(|ext| ext.to_string())
+        .collect())
+}
+
+//the extensions given replace the default list, and those added extend it
+fn resolve_exclude(conn: &Connection, exclude: Option<&[String]>, exclude_add: &[String]) -> Result<Vec<String>> {
+    match exclude {
+        Some(exclude) => Ok(exclude.to_vec()),
+        None => {
+            let mut exclude = get_default_exclude(conn)?;
+            exclude.extend(exclude_add.iter().cloned());
+            Ok(exclude)
+        }
+    }
+}
+
+fn default_exclude(conn: &Connection, reset: bool, extensions: &[String]) -> Result<()> {
+    if reset {
+        db::set_setting(conn, SETTING_EXCLUDE, None)?;
+    } else if !extensions.is_empty() {
+        db::set_setting(conn, SETTING_EXCLUDE, Some(&extensions.join(",")))?;
+    }
+    println!("Excluded by default: {}", get_default_exclude(conn)?.join(", "));
+    Ok(())
+}
+
+fn show_info(conn: &Connection, format: &OutputFormat) -> Result<()> {
+    let schema_version = db::get_schema_version(conn)?;
+    let db_path = conn.path().unwrap_or_default();
+    let data_dir = Utf8Path::new(db_path)
+        .parent()
+        .map(|dir| dir.as_str())
+        .unwrap_or_default();
+    let dat_count = db::DatRecord::get_installed(conn)?.len();
+
+    match format {
+        OutputFormat::Text => {
+            println!("{APP_NAME} {}", env!("CARGO_PKG_VERSION"));
+            println!("schema version: {schema_version}");
+            println!("database: {db_path}");
+            println!("data directory: {data_dir}");
+            println!("installed dat files: {dat_count}");
+        }
+        OutputFormat::Json => {
+            let info = serde_json::json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "schema_version": schema_version,
+                "database": db_path,
+                "data_directory": data_dir,
+                "dat_count": dat_count,
+            });
+            println!("{}", serde_json::to_string_pretty(&info)?);
         }
     }
+    Ok(())
+}
+
+fn find_dat_by_hash<'a>(dats: &'a [db::DatRecord], hash: &str) -> Result<Option<&'a db::DatRecord>> {
+    let hash = hash.to_ascii_lowercase();
+    let found: Vec<_> = dats
+        .iter()
+        .filter(|dat| {
+            dat.content_hash
+                .as_ref()
+                .is_some_and(|content_hash| content_hash.starts_with(&hash))
+        })
+        .collect();
+    if found.len() > 1 {
+        bail!("`{hash}` matches {} dat files, please give more of the hash", found.len());
+    }
+    Ok(found.first().copied())
 }
 
 fn list_dat_files(conn: &Connection) -> Result<()> {
-    let dats = db::DatRecord::get_all(conn)?;
+    let dats = db::DatRecord::get_installed(conn)?;
     if dats.is_empty() {
         eprintln!("No installed dat files.")
     } else {
         println!("Installed dat files:");
         for (i, dat) in dats.iter().enumerate() {
-            println!("[{i}] {} version: {}", dat.name, dat.version);
+            println!(
+                "[{i}] {} version: {} id: {} hash: {} imported: {} last scanned: {}",
+                dat.name,
+                dat.version,
+                dat.id.id(),
+                dat.content_hash.as_deref().unwrap_or("unknown"),
+                dat.imported_at.as_deref().unwrap_or("unknown"),
+                dat.last_scanned_at.as_deref().unwrap_or("never")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn check_dat_updates(conn: &Connection, no_network: bool) -> Result<()> {
+    ensure!(!no_network, "network access is disabled, so the urls of dat files can't be checked");
+    let dats = db::DatRecord::get_installed(conn)?;
+    let (mut checked, mut updates) = (0, 0);
+    for (i, dat) in dats.iter().enumerate() {
+        let Some(url) = &dat.url else {
+            continue;
+        };
+        checked += 1;
+        //only the header is needed, so the rest of the dat file isn't downloaded
+        let remote = util::open_url(url).and_then(|response| {
+            let reader = dat::DatReader::from_reader(BufReader::new(response))?;
+            Ok(reader.header().version.clone().unwrap_or_default())
+        });
+        match remote {
+            Ok(version) if version != dat.version => {
+                updates += 1;
+                println!("[{i}] {} version: {} has version {version} available at {url}", dat.name, dat.version);
+            }
+            Ok(_) => println!("[{i}] {} version: {} is up to date.", dat.name, dat.version),
+            Err(e) => eprintln!("[{i}] {} unable to check {url}. Error was {e:#}", dat.name),
         }
     }
+    if checked == 0 {
+        eprintln!("No installed dat files have a url to check.");
+    } else {
+        println!("{checked} dat files checked, {updates} with updates available.");
+    }
     Ok(())
 }
 
-fn update_dat(conn: &mut Connection, dat_file: &Utf8PathBuf, old_dat_id: db::DatId) -> Result<db::DatRecord> {
+fn update_dat(
+    conn: &mut Connection,
+    term: &TermInfo,
+    dat_file: &Utf8PathBuf,
+    old_dat_id: db::DatId,
+) -> Result<(db::DatRecord, ImportCounts)> {
     let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
 
-    let imported = parse_dat_file(&tx, dat_file)?;
+    //existing files were hashed with the old dat's hash type, so the new dat has to use the same one
+    let old_dat = db::DatRecord::get_by_id(&tx, &old_dat_id)?;
+    let (imported, counts) = parse_dat_file(&tx, dat_file, open_dat_file(dat_file)?, Some(old_dat.hash_type.parse()?))?;
+    check_dat_integrity(&tx, &imported.id)?;
+    db::DatRecord::update_name_map(&tx, &imported.id, old_dat.name_map.as_deref())?;
+    db::DatRecord::update_header_rule(&tx, &imported.id, old_dat.header_rule.as_deref())?;
+    db::DatRecord::update_strict_size(&tx, &imported.id, old_dat.strict_size)?;
+    let matching = MatchOptions::new(&tx, &imported.id)?;
 
-    //delete all existing matches for the old dat, we'll re-match them as we relink directories and files to the new dat
+    //remember which files matched before, to report the ones the new dat no longer matches
+    let previously_matched: BTreeSet<_> = db::MatchRecord::get_by_dat(&tx, &old_dat_id)?
+        .into_iter()
+        .filter(|m| m.status == db::MatchStatus::Match)
+        .map(|m| m.file_id)
+        .collect();
+    let mut changed = util::Table::new();
+
+    copy_aliases(&tx, &old_dat_id, &imported.id)?;
+
+    //delete all existing matches for the old dat, we'll re-match them as we relink directories and files to the new dat
     db::MatchRecord::delete_by_dat(&tx, &old_dat_id)?;
 
     for directory in db::DirRecord::get_by_dat(&tx, &old_dat_id)? {
         //check if its a zip file, if so, restrict matches to set name if matched
-        let matched_sets = if util::is_zip_file(&directory.path) {
+        let matched_sets = if util::is_set_archive(&directory.path) {
             match_sets(&tx, &imported.id, &directory.path)?
         } else {
             BTreeSet::new()
         };
+        db::DirRecord::update_target_set(&tx, &directory.id, matched_sets.first())?;
 
         for file in directory.get_files(&tx)? {
             //rematch using existing information, but link to the new dat
-            insert_matches(&tx, &imported.id, &file, &matched_sets)?;
+            let matches = insert_matches(&tx, &imported.id, &file, &matched_sets, &matching)?;
+            if previously_matched.contains(&file.id) && !matches.iter().any(|m| m.status == db::MatchStatus::Match) {
+                let (status, message) = match matches.first() {
+                    Some(m) => {
+                        (Some(&m.status), format_match_message(&m.status, &db::RomRecord::get_by_id(&tx, &m.rom_id)?))
+                    }
+                    None => (None, "no longer matches any rom".to_string()),
+                };
+                changed.add_row([
+                    format!("[{}]", format_file_indicator(status, term.tty_out)),
+                    Utf8Path::new(&directory.path).join(&file.name).to_string(),
+                    message,
+                ]);
+            }
         }
     }
 
@@ -472,103 +1601,452 @@ fn update_dat(conn: &mut Connection, dat_file: &Utf8PathBuf, old_dat_id: db::Dat
     //if we successfully updated everything and relinked and the transaction completed, we can now delete the old dat
     delete_dat(conn, old_dat_id)?;
 
-    Ok(imported)
+    if !changed.is_empty() {
+        println!("--- FILES NO LONGER MATCHED ---");
+        changed.write(&mut std::io::stdout().lock(), term.tty_out)?;
+    }
+
+    Ok((imported, counts))
+}
+
+//aliases are added by the user, so carry them over to any rom with the same set and name in the new dat
+fn copy_aliases(conn: &Connection, old_dat_id: &db::DatId, new_dat_id: &db::DatId) -> Result<()> {
+    for alias in db::AliasRecord::get_by_dat(conn, old_dat_id)? {
+        let old_rom = db::RomRecord::get_by_id(conn, &alias.rom_id)?;
+        let old_set = db::SetRecord::get_by_id(conn, &old_rom.set_id)?;
+        for set in db::SetRecord::find_by_name(conn, new_dat_id, &old_set.name, true)? {
+            for rom in set.get_roms(conn)?.into_iter().filter(|rom| rom.name == old_rom.name) {
+                db::AliasRecord::insert(
+                    conn,
+                    &db::NewAlias {
+                        dat_id: new_dat_id.clone(),
+                        rom_id: rom.id,
+                        name: alias.name.clone(),
+                    },
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+//the size and hash of each rom, by set and then by the area and name of the rom, as a set can have several
+//roms with the same name, such as in different parts of a software list
+type DatContents = BTreeMap<String, BTreeMap<(Option<String>, String), Vec<(Option<u64>, String)>>>;
+
+fn stored_contents(conn: &Connection, dat_id: &db::DatId) -> Result<DatContents> {
+    let set_names: BTreeMap<_, _> = db::SetRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|set| (set.id, set.name))
+        .collect();
+    let mut contents = DatContents::new();
+    for name in set_names.values() {
+        contents.entry(name.clone()).or_default();
+    }
+    for rom in db::RomRecord::get_by_dat(conn, dat_id)? {
+        contents
+            .entry(set_names[&rom.set_id].clone())
+            .or_default()
+            .entry((rom.area, rom.name))
+            .or_default()
+            .push((rom.size, rom.hash));
+    }
+    Ok(contents)
+}
+
+//read the same way as importing, but only kept in memory
+fn read_contents(dat_file: &Utf8Path, hash_type: util::HashType) -> Result<(DatContents, String)> {
+    let mut reader = dat::DatReader::open(dat_file)?;
+    let version = reader.header().version.clone().unwrap_or_default();
+    let mut contents = DatContents::new();
+    while let Some(game) = reader.next_game()? {
+        let roms = contents.entry(game.name).or_default();
+        for rom in &game.roms {
+            let rom = NormalisedRom::read(rom, hash_type)?;
+            roms.entry((rom.area, rom.name)).or_default().push((rom.size, rom.hash));
+        }
+    }
+    Ok((contents, version))
+}
+
+fn diff_dat(conn: &Connection, dat_id: &db::DatId, term: &TermInfo, dat_file: &Utf8Path) -> Result<()> {
+    let dat = db::DatRecord::get_by_id(conn, dat_id)?;
+    let current = stored_contents(conn, dat_id)?;
+    let (other, other_version) = read_contents(dat_file, dat.hash_type.parse()?)?;
+    print_dat_diff(term, &current, &other, &dat.version, &other_version)
+}
+
+//older versions are only kept when they are imported alongside the current one, instead of updated
+fn diff_since_version(conn: &Connection, dat_id: &db::DatId, term: &TermInfo, version: &str) -> Result<()> {
+    let dat = db::DatRecord::get_by_id(conn, dat_id)?;
+    let since = db::DatRecord::get_installed_by_version(conn, &dat.name, version)?
+        .into_iter()
+        .find(|since| since.id != dat.id)
+        .with_context(|| format!("No other installed dat file `{}` has version `{version}`", dat.name))?;
+    ensure!(
+        since.hash_type == dat.hash_type,
+        "Version `{version}` uses {} hashes and the current dat file uses {} hashes, so they can't be compared",
+        since.hash_type,
+        dat.hash_type
+    );
+    let old = stored_contents(conn, &since.id)?;
+    let new = stored_contents(conn, dat_id)?;
+    print_dat_diff(term, &old, &new, &since.version, &dat.version)
+}
+
+#[derive(Default)]
+struct DatDiff {
+    rows: Vec<Vec<String>>,
+    added: usize,
+    removed: usize,
+    changed: usize,
 }
 
-fn import_dat<P: AsRef<Utf8Path>>(conn: &mut Connection, file_path: P) -> Result<db::DatRecord> {
+fn diff_contents(current: &DatContents, other: &DatContents) -> DatDiff {
+    let describe = |(size, hash): &(Option<u64>, String)| format!("{hash} {}", format_rom_size(*size));
+    let mut diff = DatDiff::default();
+    let set_names: BTreeSet<_> = current.keys().chain(other.keys()).collect();
+    for name in set_names {
+        match (current.get(name), other.get(name)) {
+            (Some(_), None) => {
+                diff.removed += 1;
+                diff.rows.push(vec!["set removed".to_string(), name.clone()]);
+            }
+            (None, Some(_)) => {
+                diff.added += 1;
+                diff.rows.push(vec!["set added".to_string(), name.clone()]);
+            }
+            (Some(old_roms), Some(new_roms)) => {
+                let mut rows = Vec::new();
+                for key in old_roms.keys().chain(new_roms.keys()).collect::<BTreeSet<_>>() {
+                    let (area, rom_name) = key;
+                    let rom_name = match area {
+                        Some(area) => format!("{rom_name} ({area})"),
+                        None => rom_name.clone(),
+                    };
+                    let old = old_roms.get(key).map_or(&[][..], Vec::as_slice);
+                    let new = new_roms.get(key).map_or(&[][..], Vec::as_slice);
+                    //roms with the same name are only told apart by what they contain
+                    let gone: Vec<_> = old.iter().filter(|rom| !new.contains(rom)).collect();
+                    let came: Vec<_> = new.iter().filter(|rom| !old.contains(rom)).collect();
+                    if let ([old], [new]) = (gone.as_slice(), came.as_slice()) {
+                        let detail = format!("{} -> {}", describe(old), describe(new));
+                        rows.push(vec!["rom changed".to_string(), name.clone(), rom_name, detail]);
+                        continue;
+                    }
+                    for old in gone {
+                        rows.push(vec!["rom removed".to_string(), name.clone(), rom_name.clone(), describe(old)]);
+                    }
+                    for new in came {
+                        rows.push(vec!["rom added".to_string(), name.clone(), rom_name.clone(), describe(new)]);
+                    }
+                }
+                if !rows.is_empty() {
+                    diff.changed += 1;
+                    diff.rows.extend(rows);
+                }
+            }
+            (None, None) => {}
+        }
+    }
+    diff
+}
+
+fn print_dat_diff(
+    term: &TermInfo,
+    current: &DatContents,
+    other: &DatContents,
+    current_version: &str,
+    other_version: &str,
+) -> Result<()> {
+    let diff = diff_contents(current, other);
+    let mut table = util::Table::new();
+    diff.rows.into_iter().for_each(|row| table.add_row(row));
+    table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+    println!(
+        "{} sets added, {} removed and {} changed between version {current_version} and {other_version}.",
+        diff.added, diff.removed, diff.changed
+    );
+    Ok(())
+}
+
+fn import_dat<P: AsRef<Utf8Path>>(
+    conn: &mut Connection,
+    file_path: P,
+    hash_type: Option<util::HashType>,
+) -> Result<(db::DatRecord, ImportCounts)> {
+    let file_path = file_path.as_ref();
     let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
-    let dat = parse_dat_file(&tx, file_path)?;
+
+    //files kept when removing the same dat were hashed with its hash type, so the new dat has to use the same one
+    let reader = open_dat_file(file_path)?;
+    let removed = match reader.header().name.as_deref() {
+        Some(name) => db::DatRecord::get_removed_by_name(&tx, name)?.into_iter().next(),
+        None => None,
+    };
+    let hash_type = match (&removed, hash_type) {
+        (Some(removed), Some(hash_type)) if removed.hash_type != hash_type.as_str() => bail!(
+            "Scanned files kept from the removed dat file `{}` use {} hashes, so it must be imported with the same hash type",
+            removed.name,
+            removed.hash_type
+        ),
+        (Some(removed), _) => Some(removed.hash_type.parse()?),
+        (None, hash_type) => hash_type,
+    };
+
+    let (dat, counts) = parse_dat_file(&tx, file_path, reader, hash_type)?;
+    check_dat_integrity(&tx, &dat.id)?;
+    if let Some(removed) = removed {
+        let count = rematch_kept_files(&tx, &removed.id, &dat.id)?;
+        if let Some(last_scanned_at) = &removed.last_scanned_at {
+            db::DatRecord::update_last_scanned(&tx, &dat.id, last_scanned_at)?;
+        }
+        println!("{count} scanned files kept from the removed dat file were rematched.");
+    }
     tx.commit()?;
-    Ok(dat)
+    Ok((dat, counts))
 }
 
-fn parse_dat_file<P: AsRef<Utf8Path>>(conn: &Connection, file_path: P) -> Result<db::DatRecord> {
-    let df_buffer = std::fs::read_to_string(file_path.as_ref()).context("Unable to read reference dat file")?;
-    let df_xml = Document::parse_with_options(
-        df_buffer.as_str(),
-        ParsingOptions {
-            allow_dtd: true,
-            ..Default::default()
-        },
-    )
-    .context("Unable to parse reference dat file")?;
-    let mut name = None;
-    let mut description = None;
-    let mut version = None;
-    let mut author = None;
-    for header_node in df_xml
-        .root_element()
-        .children()
-        .find(|node| node.tag_name().name() == TAG_HEADER)
-        .map(|header| header.children())
-        .context("Could not find header in reference dat file")?
-    {
-        match header_node.tag_name().name() {
-            ATTR_HEADER_NAME => name = header_node.text(),
-            ATTR_HEADER_DESC => description = header_node.text(),
-            ATTR_HEADER_VERSION => version = header_node.text(),
-            ATTR_HEADER_AUTHOR => author = header_node.text(),
-            _ => {}
+//links the files kept from a removed dat to the new one, and removes what is left of the old dat
+fn rematch_kept_files(conn: &Connection, old_dat_id: &db::DatId, new_dat_id: &db::DatId) -> Result<usize> {
+    let old_dat = db::DatRecord::get_by_id(conn, old_dat_id)?;
+    db::DatRecord::update_name_map(conn, new_dat_id, old_dat.name_map.as_deref())?;
+    db::DatRecord::update_header_rule(conn, new_dat_id, old_dat.header_rule.as_deref())?;
+    db::DatRecord::update_strict_size(conn, new_dat_id, old_dat.strict_size)?;
+    let matching = MatchOptions::new(conn, new_dat_id)?;
+    let mut count = 0;
+    for directory in db::DirRecord::get_by_dat(conn, old_dat_id)? {
+        let matched_sets = if util::is_set_archive(&directory.path) {
+            match_sets(conn, new_dat_id, &directory.path)?
+        } else {
+            BTreeSet::new()
         };
+        db::DirRecord::update_target_set(conn, &directory.id, matched_sets.first())?;
+        for file in directory.get_files(conn)? {
+            insert_matches(conn, new_dat_id, &file, &matched_sets, &matching)?;
+            count += 1;
+        }
+    }
+    db::DirRecord::relink_dirs(conn, old_dat_id, new_dat_id)?;
+    db::FileRecord::relink_files(conn, old_dat_id, new_dat_id)?;
+    db::DatRecord::delete_by_id(conn, old_dat_id)?;
+    Ok(count)
+}
+
+fn check_dat_integrity(conn: &Connection, dat_id: &db::DatId) -> Result<()> {
+    let orphaned = db::RomRecord::get_orphaned_by_dat(conn, dat_id)?;
+    if let Some(rom) = orphaned.first() {
+        bail!(
+            "Imported dat file is inconsistent, {} roms do not belong to a set (e.g. {})",
+            orphaned.len(),
+            rom.name
+        );
+    }
+    Ok(())
+}
+
+//the strongest hash that every dumped rom has, as nameless and undumped roms are already left out by the reader
+fn detect_hash_type<P: AsRef<Utf8Path>>(file_path: P) -> Result<util::HashType> {
+    let mut reader = dat::DatReader::open(file_path)?;
+    let mut provided = util::HashType::ALL.to_vec();
+    let mut has_roms = false;
+    while let Some(game) = reader.next_game()? {
+        for rom in &game.roms {
+            has_roms = true;
+            provided.retain(|hash_type| rom.hash(*hash_type).is_some());
+        }
+    }
+    if !has_roms {
+        return Ok(util::HashType::Sha1);
+    }
+    provided
+        .first()
+        .copied()
+        .context("dat file does not have a hash type that is provided for every rom")
+}
+
+// How much was inserted from a dat file, to show that all of it was imported
+#[derive(Debug, Default)]
+struct ImportCounts {
+    sets: usize,
+    roms: usize,
+}
+
+impl std::fmt::Display for ImportCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} sets and {} roms", self.sets, self.roms)
+    }
+}
+
+// A rom as it is stored from a dat file, read the same way whether it is imported or only compared
+struct NormalisedRom {
+    name: String,
+    area: Option<String>,
+    size: Option<u64>,
+    hash: String,
+    crc: Option<String>,
+}
+
+impl NormalisedRom {
+    fn read(rom: &dat::DatRom, hash_type: util::HashType) -> Result<Self> {
+        let hash = rom
+            .hash(hash_type)
+            .with_context(|| format!("Unable to read {hash_type} hash for rom `{}`", rom.name))?;
+        Ok(Self {
+            //stray whitespace is an authoring error, as no file would ever be named that way
+            name: rom.name.trim().to_string(),
+            area: rom.area.clone(),
+            //some dat files leave out the size, those roms are matched by hash alone
+            size: rom
+                .size()
+                .map(|size| {
+                    size.parse()
+                        .with_context(|| format!("rom `{}` has an invalid size `{size}`", rom.name))
+                })
+                .transpose()?,
+            hash: hash.to_ascii_lowercase(),
+            crc: rom.hash(util::HashType::Crc).map(|crc| crc.to_ascii_lowercase()),
+        })
     }
+}
+
+type HashedDatReader = dat::DatReader<BufReader<util::HashingReader<File>>>;
+
+//the content hash is taken as the dat file is read for importing, rather than reading it again for it
+fn open_dat_file(file_path: &Utf8Path) -> Result<HashedDatReader> {
+    let file = File::open(file_path).context("Unable to read reference dat file")?;
+    dat::DatReader::from_reader(BufReader::new(util::HashingReader::new(file, util::HashType::Sha1)))
+}
+
+//memory use doesn't grow with the size of the dat file, as it is read and inserted a game at a time. it is all
+//inserted in the caller's transaction, so an interrupted import leaves nothing behind and is run again from the
+//start rather than resumed. the reader has only read as far as the header, so the caller can look at it first
+fn parse_dat_file(
+    conn: &Connection,
+    file_path: &Utf8Path,
+    mut reader: HashedDatReader,
+    hash_type: Option<util::HashType>,
+) -> Result<(db::DatRecord, ImportCounts)> {
+    //the dat file is read a game at a time, so finding the hash type takes a pass of its own
+    let hash_type = match hash_type {
+        Some(hash_type) => hash_type,
+        None => detect_hash_type(file_path)?,
+    };
+    let header = reader.header();
     let new_dat = db::NewDat {
-        name: name.context("unable to find name attribute in header")?.to_string(),
-        description: description
-            .context("unable to find description attribute in header")?
-            .to_string(),
-        version: version
-            .context("unable to find version attribute in header")?
-            .to_string(),
-        author: author.context("unable to find author attribute in header")?.to_string(),
-        hash_type: "sha1".to_string(),
+        name: header.name.clone().context("unable to find name attribute in header")?,
+        description: header
+            .description
+            .clone()
+            .context("unable to find description attribute in header")?,
+        version: header
+            .version
+            .clone()
+            .context("unable to find version attribute in header")?,
+        author: header
+            .author
+            .clone()
+            .context("unable to find author attribute in header")?,
+        hash_type: hash_type.to_string(),
+        imported_at: Some(util::timestamp()),
+        content_hash: None,
+        header_detector: header.header_detector.clone(),
+        url: header.url.clone(),
     };
     let dat = db::DatRecord::insert(conn, &new_dat)?;
-    for game_node in df_xml
-        .root_element()
-        .children()
-        .filter(|node| node.tag_name().name() == TAG_GAME)
-    {
-        let game_name = game_node
-            .attribute(ATTR_GAME_NAME)
-            .context("Unable to read game name in reference dat file")?;
-
+    let mut counts = ImportCounts::default();
+    //clones can appear before their parent, so link them once every set is known
+    let mut set_ids = BTreeMap::new();
+    let mut clones = Vec::new();
+    while let Some(game) = reader.next_game()? {
         let set = db::SetRecord::insert(
             conn,
             &db::NewSet {
                 dat_id: dat.id.clone(),
-                name: game_name.to_string(),
+                name: game.name.clone(),
             },
         )?;
+        if let Some(parent_name) = game.cloneof {
+            clones.push((set.id.clone(), parent_name));
+        }
+        set_ids.insert(game.name, set.id.clone());
+        counts.sets += 1;
 
-        for rom_node in game_node.descendants().filter(|node| node.tag_name().name() == TAG_ROM) {
-            let rom_name = rom_node.attribute(ATTR_ROM_NAME).context("Unable to read game name")?;
-            let rom_size = rom_node.attribute(ATTR_ROM_SIZE).context("Unable to read game size")?;
-            let rom_hash = rom_node.attribute(ATTR_ROM_HASH).context("Unable to read game hash")?;
-            db::RomRecord::insert(
-                conn,
-                &db::NewRom {
-                    dat_id: dat.id.clone(),
-                    set_id: set.id.clone(),
-                    name: rom_name.to_string(),
-                    size: db::SizeWrapper(rom_size.parse().context("should be a valid number")?),
-                    hash: rom_hash.to_string(),
-                },
-            )?;
+        //nothing needs the inserted roms, so they are inserted together without reading each one back
+        let mut new_roms = Vec::with_capacity(game.roms.len());
+        for rom in &game.roms {
+            let normalised = NormalisedRom::read(rom, hash_type)?;
+            if normalised.name != rom.name {
+                eprintln!("Trimmed whitespace from rom `{}` in set `{}`.", rom.name, set.name);
+            }
+            new_roms.push(db::NewRom {
+                dat_id: dat.id.clone(),
+                set_id: set.id.clone(),
+                name: normalised.name,
+                size: normalised.size.map(db::SizeWrapper),
+                hash: normalised.hash,
+                crc: normalised.crc,
+                area: normalised.area,
+            });
+        }
+        db::RomRecord::insert_many(conn, &new_roms)?;
+        counts.roms += new_roms.len();
+    }
+
+    //a clone of a set that is not in the dat file is treated as a parent
+    for (set_id, parent_name) in clones {
+        if let Some(parent_id) = set_ids.get(&parent_name) {
+            db::SetRecord::update_parent(conn, &set_id, parent_id)?;
         }
     }
-    Ok(dat)
+
+    let content_hash = reader.into_inner().into_inner().finish()?;
+    db::DatRecord::update_content_hash(conn, &dat.id, &content_hash)?;
+    Ok((
+        db::DatRecord {
+            content_hash: Some(content_hash),
+            ..dat
+        },
+        counts,
+    ))
+}
+
+fn reset_files(conn: &mut Connection, dat_id: &db::DatId) -> Result<usize> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+
+    //remove all matches, scanned files and directories, in that order as each references the next
+    db::MatchRecord::delete_by_dat(&tx, dat_id)?;
+    let count = db::FileRecord::delete_by_dat(&tx, dat_id)?;
+    db::DirRecord::delete_by_dat(&tx, dat_id)?;
+
+    tx.commit()?;
+    Ok(count)
+}
+
+//removes everything but the scanned files and directories, which stay linked to the removed dat
+fn remove_dat_keep_files(conn: &mut Connection, dat_id: db::DatId) -> Result<()> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+
+    db::MatchRecord::delete_by_dat(&tx, &dat_id)?;
+    db::AliasRecord::delete_by_dat(&tx, &dat_id)?;
+    db::RomRecord::delete_by_dat(&tx, &dat_id)?;
+    db::SetRecord::delete_by_dat(&tx, &dat_id)?;
+    db::DatRecord::mark_removed(&tx, &dat_id, &util::timestamp())?;
+
+    tx.commit()?;
+    Ok(())
 }
 
 fn delete_dat(conn: &mut Connection, dat_id: db::DatId) -> Result<()> {
     let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
 
-    //remove all scanned files and directories
-    for dir in db::DirRecord::get_by_dat(&tx, &dat_id)? {
-        dir.delete_files(&tx)?;
-    }
+    //remove all matches, scanned files and directories, in that order as each references the next
+    db::MatchRecord::delete_by_dat(&tx, &dat_id)?;
+    db::FileRecord::delete_by_dat(&tx, &dat_id)?;
     db::DirRecord::delete_by_dat(&tx, &dat_id)?;
 
     //remove all roms and sets before removing the dat
+    db::AliasRecord::delete_by_dat(&tx, &dat_id)?;
     db::RomRecord::delete_by_dat(&tx, &dat_id)?;
     db::SetRecord::delete_by_dat(&tx, &dat_id)?;
 
@@ -578,31 +2056,130 @@ fn delete_dat(conn: &mut Connection, dat_id: db::DatId) -> Result<()> {
     Ok(())
 }
 
-fn list_dat_records(conn: &Connection, dat_id: &db::DatId) -> Result<()> {
+fn list_dat_records(conn: &Connection, dat_id: &db::DatId, term: &TermInfo) -> Result<()> {
     let dat_record = db::DatRecord::get_by_id(conn, dat_id)?;
+    if term.json() {
+        let mut sets = Vec::new();
+        for set in db::SetRecord::get_by_dat(conn, dat_id)? {
+            sets.push(set_json(&set.name, &set.get_roms(conn)?));
+        }
+        let dat = serde_json::json!({
+            "name": dat_record.name,
+            "description": dat_record.description,
+            "version": dat_record.version,
+            "author": dat_record.author,
+            "hash": dat_record.content_hash,
+            "imported": dat_record.imported_at,
+            "scanned": dat_record.last_scanned_at,
+            "sets": sets,
+        });
+        println!("{}", serde_json::to_string_pretty(&dat)?);
+        return Ok(());
+    }
     println!("Name:        {}", dat_record.name);
     println!("Description: {}", dat_record.description);
     println!("Version:     {}", dat_record.version);
     println!("Author:      {}", dat_record.author);
+    println!("Hash:        {}", dat_record.content_hash.as_deref().unwrap_or("unknown"));
+    println!("Imported:    {}", dat_record.imported_at.as_deref().unwrap_or("unknown"));
+    println!("Scanned:     {}", dat_record.last_scanned_at.as_deref().unwrap_or("never"));
 
     println!("--- SETS ---");
+    let mut lock = std::io::stdout().lock();
     for set in db::SetRecord::get_by_dat(conn, dat_id)? {
-        println!("{}", set.name);
-        for rom in set.get_roms(conn)? {
-            println!("    {} {} - {}", rom.hash, rom.name, util::human_size(rom.size));
-        }
+        writeln!(lock, "{}", set.name)?;
+        rom_table(&set.get_roms(conn)?).write(&mut lock, term.tty_out)?;
     }
     Ok(())
 }
 
-fn find_sets_by_name(conn: &Connection, dat_id: &db::DatId, name: Option<&str>) -> Result<()> {
-    let sets = if let Some(name) = name {
-        db::SetRecord::find_by_name(conn, dat_id, name, false)
+//sets are complete, partial or missing the same way as `files sets`, and a file counts once by its best match
+fn show_dat_stats(conn: &Connection, dat_id: &db::DatId, term: &TermInfo) -> Result<()> {
+    let sets = db::SetRecord::get_by_dat(conn, dat_id)?;
+    let roms = db::RomRecord::get_by_dat(conn, dat_id)?;
+    let matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
+    let files = db::FileRecord::get_by_dat(conn, dat_id)?;
+
+    let mut rom_counts: BTreeMap<_, usize> = BTreeMap::new();
+    roms.iter()
+        .for_each(|rom| *rom_counts.entry(&rom.set_id).or_default() += 1);
+    let mut found_roms: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+    let mut statuses_by_file: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for m in &matches {
+        found_roms.entry(&m.set_id).or_default().insert(&m.rom_id);
+        statuses_by_file.entry(&m.file_id).or_default().push(&m.status);
+    }
+
+    let (mut complete, mut partial, mut missing) = (0, 0, 0);
+    for set in &sets {
+        let found = found_roms.get(&set.id).map(|roms| roms.len()).unwrap_or_default();
+        match found {
+            0 => missing += 1,
+            found if found >= rom_counts.get(&set.id).copied().unwrap_or_default() => complete += 1,
+            _ => partial += 1,
+        }
+    }
+    let (mut matched, mut warnings, mut unmatched) = (0, 0, 0);
+    for file in &files {
+        match statuses_by_file.get(&file.id) {
+            Some(statuses) if statuses.contains(&&db::MatchStatus::Match) => matched += 1,
+            Some(_) => warnings += 1,
+            None => unmatched += 1,
+        }
+    }
+    let rom_bytes: u64 = roms.iter().filter_map(|rom| rom.size).sum();
+
+    let stats = [
+        ("sets", sets.len() as u64),
+        ("sets_complete", complete),
+        ("sets_partial", partial),
+        ("sets_missing", missing),
+        ("roms", roms.len() as u64),
+        ("rom_bytes", rom_bytes),
+        ("files", files.len() as u64),
+        ("files_matched", matched),
+        ("files_warning", warnings),
+        ("files_unmatched", unmatched),
+    ];
+    if term.json() {
+        let stats: serde_json::Map<_, _> = stats
+            .iter()
+            .map(|(key, value)| (key.to_string(), (*value).into()))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else if term.tty_out {
+        println!("Sets:  {} ({complete} complete, {partial} partial, {missing} missing)", sets.len());
+        println!("Roms:  {}, {} total", roms.len(), util::human_size(rom_bytes));
+        println!("Files: {} ({matched} matched, {warnings} warnings, {unmatched} unmatched)", files.len());
     } else {
+        for (key, value) in stats {
+            println!("{key}: {value}");
+        }
+    }
+    Ok(())
+}
+
+fn find_sets_by_name(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    term: &TermInfo,
+    names: &[String],
+    search: &db::NameSearch,
+    parents_only: bool,
+) -> Result<()> {
+    let mut sets = if names.is_empty() {
         db::SetRecord::get_by_dat(conn, dat_id)
+    } else {
+        db::SetRecord::find_by_names(conn, dat_id, names, search)
     }?;
-    if sets.is_empty() {
-        println!("No sets found.");
+    if parents_only {
+        sets.retain(|set| set.parent_id.is_none());
+    }
+    if term.json() {
+        let names: Vec<_> = sets.iter().map(|set| &set.name).collect();
+        println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "sets": names }))?);
+    } else if sets.is_empty() {
+        println!("No sets found.{}", format_suggestions(&suggest_set_names(conn, dat_id, names)?));
     } else {
         for set in sets {
             println!("{}", set.name);
@@ -611,13 +2188,279 @@ fn find_sets_by_name(conn: &Connection, dat_id: &db::DatId, name: Option<&str>)
     Ok(())
 }
 
-fn find_roms(conn: &Connection, dat_id: &db::DatId, name: Option<&str>) -> Result<()> {
-    let roms = if let Some(name) = name {
-        db::RomRecord::find_by_name(conn, dat_id, name, false)
+fn compare_set_names(conn: &Connection, dat_id: &db::DatId, other_index: usize, parents_only: bool) -> Result<()> {
+    let dat = db::DatRecord::get_by_id(conn, dat_id)?;
+    let other = db::DatRecord::get_installed(conn)?
+        .into_iter()
+        .nth(other_index)
+        .ok_or_else(|| anyhow!("Invalid dat file selection `{other_index}`."))?;
+    let set_names = |dat_id: &db::DatId| -> Result<BTreeSet<String>> {
+        let sets = db::SetRecord::get_by_dat(conn, dat_id)?;
+        Ok(sets
+            .into_iter()
+            .filter(|set| !parents_only || set.parent_id.is_none())
+            .map(|set| set.name)
+            .collect())
+    };
+    let names = set_names(&dat.id)?;
+    let other_names = set_names(&other.id)?;
+
+    for (name, only) in [
+        (&dat.name, names.difference(&other_names)),
+        (&other.name, other_names.difference(&names)),
+    ] {
+        println!("--- SETS ONLY IN `{name}` ---");
+        for set_name in only {
+            println!("{set_name}");
+        }
+    }
+    println!(
+        "{} sets only in `{}`, {} sets only in `{}`, {} sets in both.",
+        names.difference(&other_names).count(),
+        dat.name,
+        other_names.difference(&names).count(),
+        other.name,
+        names.intersection(&other_names).count()
+    );
+    Ok(())
+}
+
+//the closest few set names to any of the names, for when a search finds nothing
+fn suggest_set_names<S: AsRef<str>>(conn: &Connection, dat_id: &db::DatId, names: &[S]) -> Result<Vec<String>> {
+    const MAX_SUGGESTIONS: usize = 3;
+    const MIN_SIMILARITY: f64 = 0.7;
+
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let names: Vec<_> = names.iter().map(|name| name.as_ref().to_lowercase()).collect();
+    let mut scored: Vec<_> = db::SetRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|set| {
+            let set_name = set.name.to_lowercase();
+            let score = names
+                .iter()
+                .map(|name| strsim::jaro_winkler(name, &set_name))
+                .fold(0.0, f64::max);
+            (score, set.name)
+        })
+        .filter(|(score, _)| *score >= MIN_SIMILARITY)
+        .collect();
+    scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    Ok(scored.into_iter().take(MAX_SUGGESTIONS).map(|(_, name)| name).collect())
+}
+
+fn format_suggestions(suggestions: &[String]) -> String {
+    if suggestions.is_empty() {
+        String::new()
+    } else {
+        let names: Vec<_> = suggestions.iter().map(|name| format!("`{name}`")).collect();
+        format!(" Did you mean {}?", names.join(", "))
+    }
+}
+
+// The tags in parentheses in a set name, e.g. `Game (USA, Europe) (En,Fr)` has USA, Europe, En and Fr
+fn set_name_tags(name: &str) -> impl Iterator<Item = &str> {
+    name.split('(')
+        .skip(1)
+        .filter_map(|part| part.split_once(')'))
+        .flat_map(|(tags, _)| tags.split(',').map(str::trim))
+}
+
+//writes a dat file with the set from each parent/clone family that is in the most preferred region,
+//returning how many games were written and how many there are
+fn export_one_game_one_rom(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    regions: &[String],
+    output: &Utf8Path,
+) -> Result<(usize, usize)> {
+    let dat = db::DatRecord::get_by_id(conn, dat_id)?;
+    let sets = db::SetRecord::get_by_dat(conn, dat_id)?;
+    let sets_by_id: BTreeMap<_, _> = sets.iter().map(|set| (&set.id, set)).collect();
+
+    //clones of clones are grouped with the top parent, the limit stops a broken dat from looping forever
+    let mut families: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for set in &sets {
+        let mut root = set;
+        for _ in 0..sets.len() {
+            match root.parent_id.as_ref().and_then(|id| sets_by_id.get(id)) {
+                Some(parent) => root = parent,
+                None => break,
+            }
+        }
+        families.entry(&root.id).or_default().push(set);
+    }
+
+    let priority = |set: &db::SetRecord| {
+        set_name_tags(&set.name)
+            .filter_map(|tag| regions.iter().position(|region| region.eq_ignore_ascii_case(tag)))
+            .min()
+    };
+    //prefer the parent, then the first by name, when several sets are in the same region
+    let mut chosen: Vec<_> = families
+        .values()
+        .filter_map(|family| {
+            family
+                .iter()
+                .filter_map(|set| priority(set).map(|p| (p, set.parent_id.is_some(), &set.name, *set)))
+                .min()
+                .map(|(_, _, _, set)| set)
+        })
+        .collect();
+    chosen.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let header = dat::DatHeader {
+        name: Some(format!("{} (1G1R)", dat.name)),
+        description: Some(format!("{} (1G1R)", dat.description)),
+        version: Some(dat.version.clone()),
+        author: Some(dat.author.clone()),
+        header_detector: dat.header_detector.clone(),
+        url: dat.url.clone(),
+    };
+    let out = std::io::BufWriter::new(File::create(output)?);
+    let mut writer = dat::DatWriter::new(out, &header, dat.hash_type.parse()?)?;
+    for set in &chosen {
+        writer.write_game(&set.name, &set.get_roms(conn)?)?;
+    }
+    writer.finish()?;
+    Ok((chosen.len(), families.len()))
+}
+
+fn rom_table<R: std::borrow::Borrow<db::RomRecord>>(roms: &[R]) -> util::Table {
+    let mut table = util::Table::indented(4);
+    for rom in roms {
+        let rom = rom.borrow();
+        table.add_row([rom.hash.clone(), rom.name.clone(), format_rom_size(rom.size)]);
+    }
+    table
+}
+
+fn format_rom_size(size: Option<u64>) -> String {
+    size.map_or_else(|| "unknown size".to_string(), util::human_size)
+}
+
+fn rom_json(rom: &db::RomRecord) -> serde_json::Value {
+    serde_json::json!({
+        "name": rom.name,
+        "size": rom.size,
+        "hash": rom.hash,
+        "crc": rom.crc,
+        "area": rom.area,
+    })
+}
+
+fn set_json<R: std::borrow::Borrow<db::RomRecord>>(name: &str, roms: &[R]) -> serde_json::Value {
+    let roms: Vec<_> = roms.iter().map(|rom| rom_json(rom.borrow())).collect();
+    serde_json::json!({ "name": name, "roms": roms })
+}
+
+fn find_set(conn: &Connection, dat_id: &db::DatId, name: &str) -> Result<db::SetRecord> {
+    //prefer an exact match, as a set name can be a prefix of many others
+    let mut sets = db::SetRecord::find_by_name(conn, dat_id, name, true)?;
+    if sets.is_empty() {
+        sets = db::SetRecord::find_by_name(conn, dat_id, name, false)?;
+    }
+    match sets.len() {
+        0 => bail!("No set found matching `{name}`.{}", format_suggestions(&suggest_set_names(conn, dat_id, &[name])?)),
+        1 => Ok(sets.remove(0)),
+        n => {
+            let names: Vec<_> = sets.iter().map(|set| set.name.as_str()).collect();
+            bail!("`{name}` matches {n} sets, please be more specific:\n{}", names.join("\n"))
+        }
+    }
+}
+
+fn list_set_roms(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    term: &TermInfo,
+    set_name: &str,
+    names: &[String],
+    search: &db::NameSearch,
+) -> Result<()> {
+    let set = find_set(conn, dat_id, set_name)?;
+    let roms: Vec<_> = set
+        .get_roms(conn)?
+        .into_iter()
+        .filter(|rom| search.matches(&rom.name, names))
+        .collect();
+    if term.json() {
+        let sets = serde_json::json!({ "sets": [set_json(&set.name, &roms)] });
+        println!("{}", serde_json::to_string_pretty(&sets)?);
+    } else if roms.is_empty() {
+        println!("No roms found.");
     } else {
+        println!("{}", set.name);
+        rom_table(&roms).write(&mut std::io::stdout().lock(), term.tty_out)?;
+        let total: u64 = roms.iter().filter_map(|rom| rom.size).sum();
+        println!("{} roms, {} total.", roms.len(), util::human_size(total));
+    }
+    Ok(())
+}
+
+fn add_rom_alias(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    set_name: Option<&str>,
+    rom_name: &str,
+    name: &str,
+) -> Result<()> {
+    let mut roms = db::RomRecord::find_by_name(conn, dat_id, rom_name, true)?;
+    if let Some(set_name) = set_name {
+        let set = find_set(conn, dat_id, set_name)?;
+        roms.retain(|rom| rom.set_id == set.id);
+    }
+    let rom = match roms.len() {
+        0 => bail!("No rom found named `{rom_name}`"),
+        1 => roms.remove(0),
+        n => bail!("`{rom_name}` is in {n} sets, please use --set to choose one"),
+    };
+    let existing = db::AliasRecord::find_by_name(conn, dat_id, name, true)?;
+    if existing.iter().any(|alias| alias.rom_id == rom.id) {
+        bail!("`{name}` is already an alias of `{}`", rom.name);
+    }
+    db::AliasRecord::insert(
+        conn,
+        &db::NewAlias {
+            dat_id: dat_id.clone(),
+            rom_id: rom.id.clone(),
+            name: name.to_string(),
+        },
+    )?;
+    println!("`{name}` added as an alias of `{}`, files already scanned will match it once rescanned.", rom.name);
+    Ok(())
+}
+
+//the same as what is checked on import, but shows them rather than failing
+fn list_orphaned_roms(conn: &Connection, dat_id: &db::DatId, term: &TermInfo) -> Result<()> {
+    const SAMPLE_SIZE: usize = 10;
+    let orphaned = db::RomRecord::get_orphaned_by_dat(conn, dat_id)?;
+    if orphaned.is_empty() {
+        println!("No roms without a set found.");
+        return Ok(());
+    }
+    rom_table(&orphaned[..orphaned.len().min(SAMPLE_SIZE)]).write(&mut std::io::stdout().lock(), term.tty_out)?;
+    if orphaned.len() > SAMPLE_SIZE {
+        println!("    ...");
+    }
+    println!("{} roms do not belong to a set.", orphaned.len());
+    Ok(())
+}
+
+fn find_roms(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    term: &TermInfo,
+    names: &[String],
+    search: &db::NameSearch,
+) -> Result<()> {
+    let roms = if names.is_empty() {
         db::RomRecord::get_by_dat(conn, dat_id)
+    } else {
+        db::RomRecord::find_by_names(conn, dat_id, names, search)
     }?;
-    if roms.is_empty() {
+    if roms.is_empty() && !term.json() {
         println!("No roms found.");
     } else {
         let mut roms_by_set: BTreeMap<_, Vec<_>> = BTreeMap::new();
@@ -627,65 +2470,545 @@ fn find_roms(conn: &Connection, dat_id: &db::DatId, name: Option<&str>) -> Resul
         let all_sets = db::SetRecord::get_by_dat(conn, dat_id)?;
         let sets_by_id: BTreeMap<_, _> = all_sets.iter().map(|s| (&s.id, s)).collect();
 
+        if term.json() {
+            let sets: Vec<_> = roms_by_set
+                .iter()
+                .filter_map(|(set_id, roms)| sets_by_id.get(set_id).map(|set| set_json(&set.name, roms)))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "sets": sets }))?);
+            return Ok(());
+        }
+        let mut lock = std::io::stdout().lock();
         for (set_id, roms) in roms_by_set {
             if let Some(set) = sets_by_id.get(&set_id) {
-                println!("{}", set.name);
-                for rom in roms {
-                    println!("    {} {} - {}", rom.hash, rom.name, util::human_size(rom.size));
-                }
+                writeln!(lock, "{}", set.name)?;
+                rom_table(&roms).write(&mut lock, term.tty_out)?;
             }
         }
     }
     Ok(())
 }
 
-fn scan_files(
-    conn: &mut Connection,
-    dat_id: &db::DatId,
-    term: &TermInfo,
-    scan_path: &Utf8Path, //expect this to be canonicalized
-    exclude: &[String],
+struct ScanOptions<'a> {
+    exclude: &'a [String],
     recursive: bool,
     incremental: bool,
-) -> Result<()> {
-    let mut tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+    force_rehash: bool,
+    hash_type: util::HashType,
+    //the dat's hash type first, then anything else it needs hashed in the same pass
+    hash_types: Vec<util::HashType>,
+    header: Option<util::HeaderRule>,
+    show_completions: bool,
+    verbose: bool,
+    join_splits: bool,
+    json_lines: bool,
+    rate_limit: Option<util::RateLimiter>,
+    verify_crc: bool,
+    matching: MatchOptions,
+    only_new: bool,
+    commit_every: Option<u64>,
+    pool: rayon::ThreadPool,
+}
 
-    let mut file_count = 0;
-    scan_directory(&mut tx, dat_id, term, scan_path, exclude, recursive, incremental, None, &mut file_count)?;
+impl ScanOptions<'_> {
+    //stored hashes are only any use if they were made the same way
+    fn hashed_with(&self) -> String {
+        match &self.header {
+            Some(header) => format!("{}+{}", self.hash_type, header.name()),
+            None => self.hash_type.to_string(),
+        }
+    }
 
-    tx.commit()?;
+    fn hashed_the_same(&self, dir: &db::DirRecord) -> bool {
+        dir.hashed_with.as_deref() == Some(self.hashed_with().as_str())
+    }
 
-    if term.tty_out {
-        println!("{ANSI_CURSOR_START}{} new files scanned.{ANSI_ERASE_TO_END}", file_count);
-    } else {
-        println!("{} new files scanned.", file_count);
+    fn is_unchanged_archive(&self, dir: &db::DirRecord, path: &Utf8Path) -> bool {
+        self.hashed_the_same(dir)
+            && dir
+                .size
+                .is_some_and(|size| util::is_unchanged(path, size, dir.modified_at.as_deref(), None))
     }
-    Ok(())
 }
 
-const ANSI_CURSOR_START: &str = "\x1B[1000D";
-const ANSI_ERASE_TO_END: &str = "\x1B[K";
+//loose files are hashed on the pool, everything touching the database stays on the scanning thread
+fn hashing_pool(threads: Option<u64>) -> Result<rayon::ThreadPool> {
+    let threads = threads.map_or(0, |threads| threads as usize);
+    Ok(rayon::ThreadPoolBuilder::new().num_threads(threads).build()?)
+}
 
-#[allow(clippy::too_many_arguments)]
-fn scan_directory(
-    tx: &mut Transaction,
-    dat_id: &db::DatId,
+// How a file is matched to roms, beyond comparing its name and hash
+struct MatchOptions {
+    strict_size: bool,
+    //match by the crc of files and roms instead of the dat's hash, for files that have one
+    by_crc: bool,
+    name_map: util::NameMap,
+    profile: Option<ScanProfile>,
+}
+
+impl MatchOptions {
+    fn new(conn: &Connection, dat_id: &db::DatId) -> Result<Self> {
+        Ok(MatchOptions {
+            strict_size: db::DatRecord::get_by_id(conn, dat_id)?.strict_size,
+            by_crc: false,
+            name_map: resolve_name_map(conn, dat_id)?,
+            profile: None,
+        })
+    }
+
+    //adds how long it takes to the phase, if the scan is being profiled
+    fn timed<T>(&self, phase: ScanPhase, f: impl FnOnce() -> T) -> T {
+        let Some(profile) = &self.profile else {
+            return f();
+        };
+        let start = Instant::now();
+        let result = f();
+        let total = match phase {
+            ScanPhase::Hashing => &profile.hashing,
+            ScanPhase::Matching => &profile.matching,
+            ScanPhase::Inserting => &profile.inserting,
+        };
+        total.set(total.get() + start.elapsed());
+        result
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ScanPhase {
+    Hashing,
+    Matching,
+    Inserting,
+}
+
+// Time spent in each phase of a scan, anything else is walking directories and reading the database
+#[derive(Debug, Default)]
+struct ScanProfile {
+    hashing: Cell<Duration>,
+    matching: Cell<Duration>,
+    inserting: Cell<Duration>,
+}
+
+impl ScanProfile {
+    fn summary(&self, total: Duration) -> String {
+        let (hashing, matching, inserting) = (self.hashing.get(), self.matching.get(), self.inserting.get());
+        let walking = total.saturating_sub(hashing + matching + inserting);
+        format!(
+            "{:.2?} total, {hashing:.2?} hashing, {matching:.2?} matching, {inserting:.2?} inserting, \
+            {walking:.2?} walking directories and other work.",
+            total
+        )
+    }
+}
+
+// Tracks how many roms of each set have been found, so a scan can report when it completes a set
+struct CompletionTracker {
+    sets: BTreeMap<db::SetId, (String, usize)>,
+    found_roms: BTreeMap<db::SetId, BTreeSet<db::RomId>>,
+}
+
+impl CompletionTracker {
+    fn new(conn: &Connection, dat_id: &db::DatId) -> Result<Self> {
+        let mut rom_counts: BTreeMap<_, usize> = BTreeMap::new();
+        for rom in db::RomRecord::get_by_dat(conn, dat_id)? {
+            *rom_counts.entry(rom.set_id).or_default() += 1;
+        }
+        let sets = db::SetRecord::get_by_dat(conn, dat_id)?
+            .into_iter()
+            .map(|set| {
+                let rom_count = rom_counts.get(&set.id).copied().unwrap_or_default();
+                (set.id, (set.name, rom_count))
+            })
+            .collect();
+
+        let mut found_roms: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+        for m in db::MatchRecord::get_by_dat(conn, dat_id)? {
+            found_roms.entry(m.set_id).or_default().insert(m.rom_id);
+        }
+        Ok(Self { sets, found_roms })
+    }
+
+    //returns the names of any sets that these matches completed
+    fn add(&mut self, matches: &[FileMatch]) -> Vec<&str> {
+        let mut completed = Vec::new();
+        for m in matches {
+            let found = self.found_roms.entry(m.set_id.clone()).or_default();
+            if found.insert(m.rom_id.clone())
+                && let Some((name, rom_count)) = self.sets.get(&m.set_id)
+                && found.len() == *rom_count
+            {
+                completed.push(name.as_str());
+            }
+        }
+        completed
+    }
+}
+
+// A file that has been hashed and matched during a scan
+struct ScannedFile {
+    file_id: db::FileId,
+    path: Utf8PathBuf,
+    hash: String,
+    size: u64,
+    matches: Vec<FileMatch>,
+}
+
+struct ScanState {
+    file_count: u64,
+    committed_count: u64,
+    //how many archives are being scanned inside a savepoint, which can't be committed part way through
+    savepoints: usize,
+    completions: Option<CompletionTracker>,
+    errors: Vec<String>,
+    warnings: Vec<String>,
+    //the names to print each file with, when printing json lines
+    json_lines: Option<ScanNames>,
+    new_files: Option<Vec<db::FileId>>,
+    //the hash of each file scanned before, as a full scan inserts every file again
+    scanned_before: BTreeMap<Utf8PathBuf, String>,
+}
+
+impl ScanState {
+    fn print_progress(&self, term: &TermInfo) -> Result<()> {
+        if term.tty_out && self.json_lines.is_none() {
+            print!("{ANSI_CURSOR_START}{} new files scanned.{ANSI_ERASE_TO_END}", self.file_count);
+            std::io::stdout().flush()?;
+        }
+        Ok(())
+    }
+
+    //commits what has been scanned so far and starts a new transaction, so other connections aren't locked out
+    //for the whole scan. This must only be called when no savepoint is open, or half an archive would be committed.
+    fn checkpoint(&mut self, tx: &Transaction, options: &ScanOptions) -> Result<()> {
+        ensure!(self.savepoints == 0, "a scan can't be committed with {} savepoints open", self.savepoints);
+        debug_assert!(!tx.is_autocommit(), "a scan is committed from inside its transaction");
+        if let Some(commit_every) = options.commit_every
+            && self.file_count - self.committed_count >= commit_every
+        {
+            tx.execute_batch("COMMIT; BEGIN DEFERRED;")?;
+            self.committed_count = self.file_count;
+        }
+        Ok(())
+    }
+
+    fn add_error(&mut self, term: &TermInfo, options: &ScanOptions, error: String) {
+        Self::print_verbose(term, options, &error);
+        self.errors.push(error);
+    }
+
+    fn add_warning(&mut self, term: &TermInfo, options: &ScanOptions, warning: String) {
+        Self::print_verbose(term, options, &warning);
+        self.warnings.push(warning);
+    }
+
+    fn print_verbose(term: &TermInfo, options: &ScanOptions, message: &str) {
+        if options.verbose {
+            if term.tty_out {
+                print!("{ANSI_CURSOR_START}{ANSI_ERASE_TO_END}");
+            }
+            eprintln!("{message}");
+        }
+    }
+
+    fn add_file(&mut self, term: &TermInfo, file: &ScannedFile) -> Result<()> {
+        self.file_count += 1;
+        if let Some(new_files) = &mut self.new_files
+            && self.scanned_before.get(&file.path) != Some(&file.hash)
+        {
+            new_files.push(file.file_id.clone());
+        }
+        if let Some(names) = &self.json_lines {
+            print_scanned_file(names, file)?;
+        }
+        self.add_matches(term, &file.matches);
+        Ok(())
+    }
+
+    fn add_matches(&mut self, term: &TermInfo, matches: &[FileMatch]) {
+        if let Some(completions) = &mut self.completions {
+            let indicator = format_set_indicator(&SetStatus::Complete, term.tty_out);
+            for name in completions.add(matches) {
+                if self.json_lines.is_some() {
+                    println!("{}", serde_json::json!({ "completed_set": name }));
+                } else if term.tty_out {
+                    println!("{ANSI_CURSOR_START}[{indicator}] completed set: {name}{ANSI_ERASE_TO_END}");
+                } else {
+                    println!("[{indicator}] completed set: {name}");
+                }
+            }
+        }
+    }
+}
+
+//one line per match, so each line is a complete record
+//the names of the sets and roms of the dat, so each json line doesn't need a query
+struct ScanNames {
+    sets: BTreeMap<db::SetId, String>,
+    roms: BTreeMap<db::RomId, String>,
+}
+
+impl ScanNames {
+    fn new(conn: &Connection, dat_id: &db::DatId) -> Result<Self> {
+        Ok(ScanNames {
+            sets: db::SetRecord::get_by_dat(conn, dat_id)?
+                .into_iter()
+                .map(|set| (set.id, set.name))
+                .collect(),
+            roms: db::RomRecord::get_by_dat(conn, dat_id)?
+                .into_iter()
+                .map(|rom| (rom.id, rom.name))
+                .collect(),
+        })
+    }
+}
+
+fn print_scanned_file(names: &ScanNames, file: &ScannedFile) -> Result<()> {
+    let mut lock = std::io::stdout().lock();
+    if file.matches.is_empty() {
+        let line = serde_json::json!({
+            "path": file.path.as_str(),
+            "hash": file.hash,
+            "size": file.size,
+            "status": "unknown",
+            "set": null,
+            "rom": null,
+        });
+        writeln!(lock, "{line}")?;
+    }
+    for m in &file.matches {
+        let line = serde_json::json!({
+            "path": file.path.as_str(),
+            "hash": file.hash,
+            "size": file.size,
+            "status": m.status.as_str(),
+            "set": names.sets.get(&m.set_id),
+            "rom": names.roms.get(&m.rom_id),
+        });
+        writeln!(lock, "{line}")?;
+    }
+    Ok(())
+}
+
+fn resolve_name_map(conn: &Connection, dat_id: &db::DatId) -> Result<util::NameMap> {
+    match db::DatRecord::get_by_id(conn, dat_id)?.name_map {
+        Some(name_map) => Ok(util::NameMap::new(serde_json::from_str(&name_map)?)),
+        None => Ok(util::NameMap::default()),
+    }
+}
+
+fn resolve_header_rule(conn: &Connection, dat_id: &db::DatId) -> Result<Option<util::HeaderRule>> {
+    let dat = db::DatRecord::get_by_id(conn, dat_id)?;
+    if let Some(rule) = &dat.header_rule {
+        return Ok(util::HeaderRule::parse(rule));
+    }
+    let Some(detector) = dat.header_detector else {
+        return Ok(None);
+    };
+    let rule = util::HeaderRule::from_detector(&detector);
+    if rule.is_none() {
+        eprintln!("dat file `{}` uses the unknown header detector `{detector}`, so headers are not skipped.", dat.name);
+    }
+    Ok(rule)
+}
+
+//roms only carry the strongest hash the dat file has (and its crc), so that is what files are hashed with
+fn resolve_hash_type(conn: &Connection, dat_id: &db::DatId) -> Result<util::HashType> {
+    db::DatRecord::get_by_id(conn, dat_id)?.hash_type.parse()
+}
+
+//files are hashed with the crc as well when the roms have them, in the same pass as the dat's own hash
+fn resolve_hash_types(conn: &Connection, dat_id: &db::DatId) -> Result<Vec<util::HashType>> {
+    let hash_type = resolve_hash_type(conn, dat_id)?;
+    let crc =
+        (hash_type != util::HashType::Crc && db::RomRecord::has_crcs(conn, dat_id)?).then_some(util::HashType::Crc);
+    Ok(std::iter::once(hash_type).chain(crc).collect())
+}
+
+//files always carry the dat's hash and the crc, so those are the only hashes they can be matched by
+fn resolve_match_by_crc(conn: &Connection, dat_id: &db::DatId, hash_override: Option<util::HashType>) -> Result<bool> {
+    let Some(hash_type) = hash_override else {
+        return Ok(false);
+    };
+    let hash_types = resolve_hash_types(conn, dat_id)?;
+    ensure!(
+        hash_types.contains(&hash_type),
+        "dat file does not provide {hash_type} hashes, only {}",
+        hash_types
+            .iter()
+            .map(|hash_type| hash_type.as_str())
+            .collect::<Vec<_>>()
+            .join(" and ")
+    );
+    Ok(hash_type != hash_types[0])
+}
+
+fn scan_files(
+    conn: &mut Connection,
+    dat_id: &db::DatId,
+    term: &TermInfo,
+    scan_path: &Utf8Path, //expect this to be canonicalized
+    options: &ScanOptions,
+) -> Result<()> {
+    let started = Instant::now();
+    let mut tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+
+    let mut state = ScanState {
+        file_count: 0,
+        committed_count: 0,
+        savepoints: 0,
+        completions: if options.show_completions { Some(CompletionTracker::new(&tx, dat_id)?) } else { None },
+        errors: Vec::new(),
+        warnings: Vec::new(),
+        json_lines: if options.json_lines { Some(ScanNames::new(&tx, dat_id)?) } else { None },
+        new_files: if options.only_new { Some(Vec::new()) } else { None },
+        scanned_before: if options.only_new { scanned_hashes_by_path(&tx, dat_id)? } else { BTreeMap::new() },
+    };
+    scan_directory(&mut tx, dat_id, term, scan_path, options, options.incremental, None, &mut state)?;
+    db::DatRecord::update_last_scanned(&tx, dat_id, &util::timestamp())?;
+
+    tx.commit()?;
+
+    if term.tty_out && !options.json_lines {
+        print!("{ANSI_CURSOR_START}{ANSI_ERASE_TO_END}");
+    }
+    if let Some(new_files) = &state.new_files {
+        list_new_files(conn, dat_id, term, new_files)?;
+    }
+    if !state.warnings.is_empty() {
+        eprintln!("Warnings ({}):", state.warnings.len());
+        for warning in &state.warnings {
+            eprintln!("  {warning}");
+        }
+    }
+    if !state.errors.is_empty() {
+        eprintln!("Errors ({}):", state.errors.len());
+        for error in &state.errors {
+            eprintln!("  {error}");
+        }
+    }
+    let summary = format!(
+        "{} new files scanned, {} errors, {} warnings.",
+        state.file_count,
+        state.errors.len(),
+        state.warnings.len()
+    );
+    let summary = match &options.matching.profile {
+        Some(profile) => format!("{summary}\n{}", profile.summary(started.elapsed())),
+        None => summary,
+    };
+    //keep stdout to just the json lines, so it can be consumed directly
+    if options.json_lines {
+        eprintln!("{summary}");
+    } else {
+        println!("{summary}");
+    }
+    Ok(())
+}
+
+fn list_dirs(conn: &Connection, dat_id: &db::DatId, term: &TermInfo) -> Result<()> {
+    let dirs = db::DirRecord::get_by_dat(conn, dat_id)?;
+    let mut file_counts: BTreeMap<_, usize> = BTreeMap::new();
+    for file in db::FileRecord::get_by_dat(conn, dat_id)? {
+        *file_counts.entry(file.dir_id).or_default() += 1;
+    }
+    let mut table = util::Table::new();
+    for dir in &dirs {
+        table.add_row([
+            dir.id.id().to_string(),
+            dir.path.clone(),
+            format!("{} files", file_counts.get(&dir.id).copied().unwrap_or_default()),
+        ]);
+    }
+    table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+    println!("{} directories scanned.", dirs.len());
+    Ok(())
+}
+
+fn scanned_hashes_by_path(conn: &Connection, dat_id: &db::DatId) -> Result<BTreeMap<Utf8PathBuf, String>> {
+    let dirs: BTreeMap<_, _> = db::DirRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|dir| (dir.id, dir.path))
+        .collect();
+    Ok(db::FileRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .filter_map(|file| Some((Utf8Path::new(dirs.get(&file.dir_id)?).join(&file.name), file.hash)))
+        .collect())
+}
+
+fn list_new_files(conn: &Connection, dat_id: &db::DatId, term: &TermInfo, file_ids: &[db::FileId]) -> Result<()> {
+    //a first scan finds every file new, so get everything in bulk instead of a query per file
+    let files = db::FileRecord::get_by_dat(conn, dat_id)?;
+    let files_by_id: BTreeMap<_, _> = files.iter().map(|file| (&file.id, file)).collect();
+    let matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
+    let matches_by_file: BTreeMap<_, Vec<_>> = matches.iter().fold(BTreeMap::new(), |mut acc, m| {
+        acc.entry(&m.file_id).or_default().push(m);
+        acc
+    });
+    let roms_by_id: BTreeMap<_, _> = db::RomRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|rom| (rom.id.clone(), rom))
+        .collect();
+    let dir_paths: BTreeMap<_, _> = db::DirRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|dir| (dir.id, dir.path))
+        .collect();
+
+    //keep the directories in the order they were scanned
+    let mut tables: BTreeMap<&db::DirId, (usize, util::Table)> = BTreeMap::new();
+    for file in file_ids.iter().filter_map(|file_id| files_by_id.get(file_id)) {
+        let file_matches = matches_by_file.get(&file.id).map(Vec::as_slice).unwrap_or_default();
+        let statuses: Vec<_> =
+            if file_matches.is_empty() { vec![None] } else { file_matches.iter().map(|fm| Some(*fm)).collect() };
+        let order = tables.len();
+        let (_, table) = tables
+            .entry(&file.dir_id)
+            .or_insert_with(|| (order, util::Table::new()));
+        for fm in statuses {
+            let matched = fm.and_then(|fm| Some((fm, roms_by_id.get(&fm.rom_id)?)));
+            table.add_row(format_file_status(file, matched, term.tty_out));
+        }
+    }
+    let mut tables: Vec<_> = tables.into_iter().collect();
+    tables.sort_by_key(|(_, (order, _))| *order);
+
+    for (dir_id, (_, table)) in tables {
+        let path = dir_paths.get(dir_id).map_or("", String::as_str);
+        println!("--- NEW FILES IN '{path}' ---");
+        table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+    }
+    Ok(())
+}
+
+const ANSI_CURSOR_START: &str = "\x1B[1000D";
+const ANSI_ERASE_TO_END: &str = "\x1B[K";
+
+#[allow(clippy::too_many_arguments)]
+fn scan_directory(
+    tx: &mut Transaction,
+    dat_id: &db::DatId,
     term: &TermInfo,
     scan_path: &Utf8Path,
-    exclude: &[String],
-    recursive: bool,
+    options: &ScanOptions,
     incremental: bool,
     parent_id: Option<&db::DirId>,
-    file_count: &mut u64,
+    state: &mut ScanState,
 ) -> Result<()> {
+    let mut manifest = BTreeMap::new();
+    let mut reuse_hashes = !options.force_rehash;
     let (dir, incremental) = match db::DirRecord::get_by_dat_path(tx, dat_id, scan_path.as_str())? {
         Some(dir) => {
-            if incremental {
+            //if the hash type or header rule has changed since the last scan, every file needs hashing again
+            reuse_hashes &= options.hashed_the_same(&dir);
+            if incremental && reuse_hashes {
                 // add on to existing records
                 (dir, true)
             } else {
-                //wipe existing file records and do full scan
-                let _ = dir.delete_files(tx)?;
+                //keep what was scanned before, so unchanged files don't need hashing again. The records are only
+                //replaced as each file is scanned again, so a scan that is stopped part way doesn't lose them
+                manifest = dir
+                    .get_files(tx)?
+                    .into_iter()
+                    .map(|file| (file.name.clone(), file))
+                    .collect();
                 (dir, false)
             }
         }
@@ -702,6 +3025,15 @@ fn scan_directory(
             (dir, false)
         }
     };
+    //the same directory scanned for another dat file saves hashing the files that haven't changed since
+    let mut elsewhere = BTreeMap::new();
+    if !options.force_rehash {
+        for other in db::DirRecord::get_by_path(tx, scan_path.as_str())? {
+            if other.dat_id != *dat_id && options.hashed_the_same(&other) {
+                elsewhere.extend(other.get_files(tx)?.into_iter().map(|file| (file.name.clone(), file)));
+            }
+        }
+    }
 
     let existing_dirs = dir.get_children(tx)?;
     let mut existing_paths: BTreeSet<&str> = existing_dirs.iter().map(|dir| dir.path.as_str()).collect();
@@ -711,65 +3043,273 @@ fn scan_directory(
     existing_files
         .iter()
         .for_each(|file| existing_files_by_name.entry(file.name.as_str()).or_default().push(file));
+    let mut new_files = Vec::new();
+    let mut split_files: BTreeMap<String, Vec<(u32, Utf8PathBuf)>> = BTreeMap::new();
 
     for entry in scan_path.read_dir_utf8()? {
         let entry = entry?;
         let path = entry.path();
         if util::is_hidden_file(path) {
             //skip
-        } else if recursive && path.is_dir() {
-            scan_directory(tx, dat_id, term, path, exclude, recursive, incremental, Some(&dir.id), file_count)?;
+        } else if options.recursive && path.is_dir() {
+            scan_directory(tx, dat_id, term, path, options, incremental, Some(&dir.id), state)?;
             existing_paths.remove(path.as_str());
         } else if path.is_file() {
-            if util::has_extension(path, exclude) {
+            if util::has_extension(path, options.exclude) {
                 continue;
             }
             if util::is_zip_file(path) {
                 //for zip files we need to rollback the entire directory and files if it failed to scan properly
                 let mut sp = tx.savepoint()?;
-                match scan_zip_file(&sp, dat_id, path, incremental, exclude, &dir.id) {
-                    Ok(files_scanned) => {
+                state.savepoints += 1;
+                let mut warnings = Vec::new();
+                match scan_zip_file(&sp, dat_id, path, incremental, options, &dir.id, &mut warnings) {
+                    Ok(scanned_files) => {
+                        sp.commit()?;
+                        state.savepoints -= 1;
+
+                        for scanned_file in &scanned_files {
+                            state.add_file(term, scanned_file)?;
+                        }
+                        for warning in warnings {
+                            state.add_warning(term, options, warning);
+                        }
+                        existing_paths.remove(path.as_str());
+                    }
+                    Err(e) => {
+                        sp.rollback()?;
+                        state.savepoints -= 1;
+
+                        state.add_error(term, options, format!("Failed to scan {}. Error: {e}", path));
+                    }
+                }
+            } else if util::is_7z_file(path) {
+                //same as zip files, a 7z file that failed to scan is rolled back entirely
+                let mut sp = tx.savepoint()?;
+                state.savepoints += 1;
+                match scan_7z_file(&sp, dat_id, path, incremental, options, &dir.id) {
+                    Ok(scanned_files) => {
+                        sp.commit()?;
+                        state.savepoints -= 1;
+
+                        for scanned_file in &scanned_files {
+                            state.add_file(term, scanned_file)?;
+                        }
+                        existing_paths.remove(path.as_str());
+                    }
+                    Err(e) => {
+                        sp.rollback()?;
+                        state.savepoints -= 1;
+
+                        state.add_error(term, options, format!("Failed to scan {}. Error: {e}", path));
+                    }
+                }
+            } else if util::is_gzip_file(path)
+                && !(incremental
+                    && path
+                        .file_name()
+                        .is_some_and(|name| existing_files_by_name.contains_key(name)))
+            {
+                //for gzip files we need to rollback the directory if the file can't be decompressed
+                let mut sp = tx.savepoint()?;
+                state.savepoints += 1;
+                match scan_gzip_file(&sp, dat_id, path, incremental, options, &dir.id) {
+                    Ok(scanned_files) => {
                         sp.commit()?;
+                        state.savepoints -= 1;
 
-                        *file_count += files_scanned;
+                        for scanned_file in &scanned_files {
+                            state.add_file(term, scanned_file)?;
+                        }
                         existing_paths.remove(path.as_str());
                     }
                     Err(e) => {
                         sp.rollback()?;
+                        state.savepoints -= 1;
 
-                        eprintln!("Failed to scan {}. Error: {e}", path);
+                        //not a valid gzip file, so hash it as is like any other file
+                        state.add_warning(
+                            term,
+                            options,
+                            format!("{path} could not be decompressed, so was hashed as is. Error: {e}"),
+                        );
+                        new_files.push(path.to_path_buf());
                     }
                 }
             } else {
                 match path.file_name().context("Could not get filename") {
                     Ok(filename) => {
-                        let exists = existing_files_by_name.remove(filename).is_some();
-                        if exists && incremental {
-                            //there was an existing scanned file, so skip it
+                        if options.join_splits
+                            && let Some((joined_name, part)) = util::split_file_part(filename)
+                        {
+                            //defer until all the parts are known
+                            split_files
+                                .entry(joined_name.to_string())
+                                .or_default()
+                                .push((part, path.to_path_buf()));
                             continue;
                         }
 
-                        if let Err(e) = scan_file(tx, dat_id, &dir.id, path, filename) {
-                            eprintln!("Failed to scan {}. Error: {e}", path);
-                        } else {
-                            *file_count += 1;
+                        //an incremental scan skips files scanned before, unless they have changed since
+                        if let Some(existing) = existing_files_by_name.remove(filename)
+                            && incremental
+                        {
+                            if existing.iter().all(|file| {
+                                util::is_unchanged(path, file.size, file.modified_at.as_deref(), options.header)
+                            }) {
+                                continue;
+                            }
+                            for file in existing {
+                                db::MatchRecord::delete_by_file(tx, &file.id)?;
+                                db::FileRecord::delete_by_id(tx, &file.id)?;
+                            }
                         }
+
+                        //defer scanning until we know which files have vanished, as it may have just been renamed
+                        new_files.push(path.to_path_buf());
                     }
                     Err(e) => {
-                        eprintln!("Failed to scan {}. Error: {e}", path);
+                        state.add_error(term, options, format!("Failed to scan {}. Error: {e}", path));
                     }
                 }
             }
         }
-        if term.tty_out {
-            print!("{ANSI_CURSOR_START}{} new files scanned.{ANSI_ERASE_TO_END}", file_count);
-            std::io::stdout().flush()?;
+        state.print_progress(term)?;
+        state.checkpoint(tx, options)?;
+    }
+
+    //split files are matched as a single file when every part is present, otherwise each part is a file on its own
+    for (joined_name, mut parts) in split_files {
+        parts.sort();
+        let complete = parts.len() > 1 && parts.iter().enumerate().all(|(i, (part, _))| *part as usize == i + 1);
+        if !complete {
+            for (_, path) in parts {
+                let filename = path.file_name().expect("scanned files should have a name");
+                if existing_files_by_name.remove(filename).is_some() && incremental {
+                    continue;
+                }
+                new_files.push(path);
+            }
+            continue;
+        }
+
+        if existing_files_by_name.remove(joined_name.as_str()).is_some() && incremental {
+            continue;
+        }
+        let paths: Vec<_> = parts.into_iter().map(|(_, path)| path).collect();
+        let result = options
+            .matching
+            .timed(ScanPhase::Hashing, || {
+                hash_split_files(&paths, &options.hash_types, options.rate_limit.as_ref(), options.header)
+            })
+            .and_then(|hashes| {
+                let hashed = FileHashes::new(&hashes, options.hash_type);
+                remove_rescanned_file(tx, &mut manifest, &joined_name)?;
+                let new_file = db::NewFile {
+                    crc: hashed.crc,
+                    ..db::NewFile::new(dat_id, &dir.id, &joined_name, hashed.size, &hashed.hash)
+                };
+                let (file_id, matches) = insert_files_and_matches(tx, &new_file, &BTreeSet::new(), &options.matching)?;
+                Ok(ScannedFile {
+                    file_id,
+                    path: scan_path.join(&joined_name),
+                    hash: hashed.hash,
+                    size: hashed.size,
+                    matches,
+                })
+            });
+        match result {
+            Ok(scanned_file) => state.add_file(term, &scanned_file)?,
+            Err(e) => {
+                state.add_error(term, options, format!("Failed to scan {}. Error: {e}", scan_path.join(&joined_name)))
+            }
+        }
+        state.print_progress(term)?;
+        state.checkpoint(tx, options)?;
+    }
+
+    //any file that has vanished with the same hash as a new file has been renamed on disk, so
+    //rename the record instead of removing it and inserting a new one
+    let mut vanished_by_hash: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    existing_files_by_name
+        .values()
+        .flatten()
+        .for_each(|file| vanished_by_hash.entry(file.hash.as_str()).or_default().push(*file));
+    let mut renamed = BTreeSet::new();
+    //hash a few files per thread at a time, so progress is still shown as they are inserted
+    let (hash_type, hash_types, rate_limit, header) =
+        (options.hash_type, &options.hash_types, options.rate_limit.as_ref(), options.header);
+    for chunk in new_files.chunks(options.pool.current_num_threads() * 4) {
+        let hashed: Vec<_> = options.matching.timed(ScanPhase::Hashing, || {
+            options.pool.install(|| {
+                chunk
+                    .par_iter()
+                    .map(|path| {
+                        let filename = path.file_name().expect("scanned files should have a name");
+                        let modified_at = util::modified_at(path);
+                        //a full scan can trust the hash from the last scan, as long as the file hasn't changed since
+                        let unchanged = [manifest.get(filename).filter(|_| reuse_hashes), elsewhere.get(filename)]
+                            .into_iter()
+                            .flatten()
+                            .find(|file| util::is_unchanged(path, file.size, file.modified_at.as_deref(), header));
+                        let hashed = match unchanged {
+                            Some(file) => Ok(FileHashes::of(file)),
+                            None => hash_file(path, hash_types, rate_limit, header)
+                                .map(|hashes| FileHashes::new(&hashes, hash_type)),
+                        };
+                        (hashed, modified_at)
+                    })
+                    .collect()
+            })
+        });
+        for (path, (hashed, modified_at)) in chunk.iter().zip(hashed) {
+            let filename = path.file_name().expect("scanned files should have a name");
+            let result = hashed.and_then(|hashed| {
+                remove_rescanned_file(tx, &mut manifest, filename)?;
+                let vanished = vanished_by_hash.get_mut(hashed.hash.as_str()).and_then(|files| {
+                    let index = files.iter().position(|file| file.size == hashed.size)?;
+                    Some(files.remove(index))
+                });
+                let (file_id, matches) = match vanished {
+                    Some(vanished) => {
+                        let file = vanished
+                            .rename(tx, filename)?
+                            .update_modified_at(tx, modified_at.as_deref())?;
+                        //the name has changed so the match status may have as well
+                        db::MatchRecord::delete_by_file(tx, &file.id)?;
+                        let matches = insert_matches(tx, dat_id, &file, &BTreeSet::new(), &options.matching)?;
+                        renamed.insert(file.id.clone());
+                        (file.id, matches)
+                    }
+                    None => {
+                        let new_file = db::NewFile {
+                            modified_at,
+                            crc: hashed.crc,
+                            ..db::NewFile::new(dat_id, &dir.id, filename, hashed.size, &hashed.hash)
+                        };
+                        insert_files_and_matches(tx, &new_file, &BTreeSet::new(), &options.matching)?
+                    }
+                };
+                Ok(ScannedFile {
+                    file_id,
+                    path: path.clone(),
+                    hash: hashed.hash,
+                    size: hashed.size,
+                    matches,
+                })
+            });
+            match result {
+                Ok(scanned_file) => state.add_file(term, &scanned_file)?,
+                Err(e) => state.add_error(term, options, format!("Failed to scan {}. Error: {e}", path)),
+            }
+            state.print_progress(term)?;
+            state.checkpoint(tx, options)?;
         }
     }
 
     for existing_path in existing_paths {
-        if incremental && Utf8Path::new(&existing_path).is_dir() {
-            //if its an incremental scan and the directory still exists, don't delete
+        if (incremental || !options.recursive) && Utf8Path::new(&existing_path).is_dir() {
+            //if its an incremental or non-recursive scan and the directory still exists, don't delete
             //the directory as they may have missed the recursive flag and we don't
             //want to delete data unnecessarily
             continue;
@@ -778,27 +3318,57 @@ fn scan_directory(
             Ok(dir) => {
                 if let Some(dir) = dir {
                     if let Err(e) = dir.delete_files(tx) {
-                        eprintln!("Failed to delete files in {}. Error: {e}", existing_path);
+                        state.add_error(
+                            term,
+                            options,
+                            format!("Failed to delete files in {}. Error: {e}", existing_path),
+                        );
                     }
                     if let Err(e) = db::DirRecord::delete_by_id(tx, &dir.id) {
-                        eprintln!("Failed to delete directory {}. Error: {e}", existing_path);
+                        state.add_error(
+                            term,
+                            options,
+                            format!("Failed to delete directory {}. Error: {e}", existing_path),
+                        );
                     }
                 } else {
-                    eprintln!("Failed to find directory entry {}.", existing_path);
+                    state.add_error(term, options, format!("Failed to find directory entry {}.", existing_path));
                 }
             }
             Err(e) => {
-                eprintln!("Failed to get directory entry {}. Error: {e}", existing_path);
+                state.add_error(term, options, format!("Failed to get directory entry {}. Error: {e}", existing_path));
             }
         }
     }
     for (_, existing_files) in existing_files_by_name {
         for existing_file in existing_files {
-            if let Err(e) = db::FileRecord::delete_by_id(tx, &existing_file.id) {
-                eprintln!("Failed to remove {}. Error: {e}", existing_file.name);
+            if renamed.contains(&existing_file.id) {
+                continue;
             }
+            let result = db::MatchRecord::delete_by_file(tx, &existing_file.id)
+                .and_then(|_| db::FileRecord::delete_by_id(tx, &existing_file.id));
+            if let Err(e) = result {
+                state.add_error(term, options, format!("Failed to remove {}. Error: {e}", existing_file.name));
+            }
+        }
+    }
+    //what is left of the last scan are files that couldn't be scanned again, which a full scan doesn't keep
+    for file in manifest.into_values().filter(|file| !renamed.contains(&file.id)) {
+        let result =
+            db::MatchRecord::delete_by_file(tx, &file.id).and_then(|_| db::FileRecord::delete_by_id(tx, &file.id));
+        if let Err(e) = result {
+            state.add_error(term, options, format!("Failed to remove {}. Error: {e}", file.name));
         }
     }
+    db::DirRecord::update_hashed_with(tx, &dir.id, &options.hashed_with())?;
+    Ok(())
+}
+
+fn remove_rescanned_file(conn: &Connection, manifest: &mut BTreeMap<String, db::FileRecord>, name: &str) -> Result<()> {
+    if let Some(file) = manifest.remove(name) {
+        db::MatchRecord::delete_by_file(conn, &file.id)?;
+        db::FileRecord::delete_by_id(conn, &file.id)?;
+    }
     Ok(())
 }
 
@@ -807,13 +3377,18 @@ fn scan_zip_file(
     dat_id: &db::DatId,
     path: &Utf8Path,
     incremental: bool,
-    exclude: &[String],
+    options: &ScanOptions,
     parent_id: &db::DirId,
-) -> Result<u64> {
+    warnings: &mut Vec<String>,
+) -> Result<Vec<ScannedFile>> {
     let maybe_dir = db::DirRecord::get_by_dat_path(conn, dat_id, path.as_str())?;
-    if incremental && maybe_dir.is_some() {
-        //if incremental and we have scanned this zip file before, skip it
-        return Ok(0);
+    if incremental
+        && maybe_dir
+            .as_ref()
+            .is_some_and(|dir| options.is_unchanged_archive(dir, path))
+    {
+        //if incremental and we have scanned this zip file before and it hasn't changed since, skip it
+        return Ok(Vec::new());
     }
 
     let dir_id = match maybe_dir {
@@ -835,100 +3410,583 @@ fn scan_zip_file(
             dir.id
         }
     };
+    db::DirRecord::update_hashed_with(conn, &dir_id, &options.hashed_with())?;
+    db::DirRecord::update_archive_state(conn, &dir_id, path.metadata()?.len(), util::modified_at(path).as_deref())?;
 
     let matched = match_sets(conn, dat_id, path)?;
+    db::DirRecord::update_target_set(conn, &dir_id, matched.first())?;
+    if let Some(files) = archive_scanned_elsewhere(conn, dat_id, path, options)? {
+        return insert_scanned_elsewhere(conn, dat_id, &dir_id, path, files, &matched, options);
+    }
 
     let file = File::open(path)?;
     let mut zip = zip::ZipArchive::new(file).with_context(|| format!("could not open '{}' as a zip file", path))?;
-    let mut file_count = 0u64;
+    //go through what the archive says about its entries first, as the ones matched by their crc don't need reading
+    let mut entries = Vec::new();
     for i in 0..zip.len() {
-        match zip.by_index(i) {
-            Ok(mut inner_file) => {
-                if inner_file.is_file() {
-                    if util::has_extension(inner_file.name(), exclude) {
-                        continue;
-                    }
+        let inner_file = zip.by_index_raw(i)?;
+        if !inner_file.is_file()
+            || util::has_extension(inner_file.name(), options.exclude)
+            || util::is_archive_junk(inner_file.name())
+        {
+            continue;
+        }
 
-                    file_count += 1;
-                    let (hash, file_size) = util::calc_hash(&mut inner_file)?;
-                    insert_files_and_matches(conn, dat_id, &dir_id, inner_file.name(), file_size, &hash, &matched)?;
-                }
-            }
-            Err(error) => bail!("{}", error),
+        //names not flagged as utf-8 are decoded as cp437, which garbles names in other encodings
+        //but keeps them stable, so they can still be found again in the archive
+        if std::str::from_utf8(inner_file.name_raw()).is_err() {
+            warnings.push(format!(
+                "{path} has an entry `{}` with a name that is not utf-8, stored as `{}`",
+                String::from_utf8_lossy(inner_file.name_raw()),
+                inner_file.name()
+            ));
+        }
+
+        let stored_crc = inner_file.crc32();
+        //a header rule changes what gets hashed, so the stored crc can't stand in for it,
+        //and verifying the crc needs the entry read anyway
+        let known_hash = if options.header.is_none() && !options.verify_crc {
+            hash_from_crc(conn, dat_id, stored_crc, inner_file.size())?
+        } else {
+            None
+        };
+        entries.push(ZipEntry {
+            index: i,
+            name: inner_file.name().to_string(),
+            size: inner_file.size(),
+            stored_crc,
+            known_hash,
+            compression: format!("{:?}", inner_file.compression()).to_ascii_lowercase(),
+            compressed_size: inner_file.compressed_size(),
+            modified_at: inner_file.last_modified().and_then(|time| {
+                util::modified_at_from_dos(
+                    time.year(),
+                    time.month(),
+                    time.day(),
+                    time.hour(),
+                    time.minute(),
+                    time.second(),
+                )
+            }),
+        });
+    }
+
+    //the entries are hashed on the pool, each thread reading them through its own handle to the archive
+    let (hash_type, hash_types, rate_limit, header) =
+        (options.hash_type, &options.hash_types, options.rate_limit.as_ref(), options.header);
+    let hashed: Vec<Result<(FileHashes, Option<u32>)>> = options.matching.timed(ScanPhase::Hashing, || {
+        options.pool.install(|| {
+            entries
+                .par_iter()
+                .map_init(
+                    || {
+                        File::open(path)
+                            .map_err(zip::result::ZipError::from)
+                            .and_then(zip::ZipArchive::new)
+                    },
+                    |zip, entry| match &entry.known_hash {
+                        //with no header rule the stored crc is the crc of what would be hashed
+                        Some(hash) => Ok((
+                            FileHashes {
+                                hash: hash.clone(),
+                                crc: Some(format!("{:08x}", entry.stored_crc)),
+                                size: entry.size,
+                            },
+                            None,
+                        )),
+                        None => {
+                            let zip = zip
+                                .as_mut()
+                                .map_err(|e| anyhow!("could not open '{path}' as a zip file: {e}"))?;
+                            hash_zip_entry(zip, entry.index, hash_types, rate_limit, header)
+                                .map(|(hashes, crc)| (FileHashes::new(&hashes, hash_type), crc))
+                        }
+                    },
+                )
+                .collect()
+        })
+    });
+
+    let mut scanned_files = Vec::new();
+    for (entry, hashed) in entries.into_iter().zip(hashed) {
+        let (hashed, crc) = hashed?;
+        //a mismatch here means the archive itself is damaged, not that the file is the wrong one
+        if let Some(crc) = crc.filter(|crc| *crc != entry.stored_crc) {
+            let message = format!(
+                "{path} has a corrupt entry `{}`, its crc is {crc:08x} but the archive stored {:08x}",
+                entry.name, entry.stored_crc
+            );
+            ensure!(options.verify_crc, message);
+            warnings.push(message);
         }
+        let new_file = db::NewFile {
+            compression: Some(entry.compression),
+            compressed_size: Some(db::SizeWrapper(entry.compressed_size)),
+            modified_at: entry.modified_at,
+            crc: hashed.crc,
+            ..db::NewFile::new(dat_id, &dir_id, &entry.name, hashed.size, &hashed.hash)
+        };
+        let (file_id, matches) = insert_files_and_matches(conn, &new_file, &matched, &options.matching)?;
+        scanned_files.push(ScannedFile {
+            file_id,
+            path: path.join(&entry.name),
+            hash: hashed.hash,
+            size: hashed.size,
+            matches,
+        });
     }
 
     //we could be smarter here and try to infer the largest set matched
     //and assume that the set is supposed to be that if no set was matched.
 
-    Ok(file_count)
+    Ok(scanned_files)
 }
 
-fn match_sets<P: AsRef<Utf8Path>>(conn: &Connection, dat_id: &db::DatId, path: P) -> Result<BTreeSet<db::SetId>> {
-    let name = path.as_ref().file_prefix().context("should have a file name")?;
-    let sets = db::SetRecord::find_by_name(conn, dat_id, name, true)?;
-    let matched: BTreeSet<db::SetId> = sets.iter().map(|record| record.id.clone()).collect();
-    Ok(matched)
+// What the central directory of a zip archive says about an entry
+struct ZipEntry {
+    index: usize,
+    name: String,
+    size: u64,
+    stored_crc: u32,
+    known_hash: Option<String>,
+    compression: String,
+    compressed_size: u64,
+    modified_at: Option<String>,
 }
 
-fn scan_file(conn: &Connection, dat_id: &db::DatId, dir_id: &db::DirId, path: &Utf8Path, filename: &str) -> Result<()> {
-    //scan the file,find a match and insert
-    let file = File::open(path)?;
-    let file_size = file.metadata()?.len();
-
-    let mut reader = BufReader::new(&file);
-    let (hash, _) = util::calc_hash(&mut reader)?;
-
-    insert_files_and_matches(conn, dat_id, dir_id, filename, file_size, &hash, &BTreeSet::new())?;
-    Ok(())
+//the crc of what was read comes back as well, to check against the one the archive stored
+fn hash_zip_entry(
+    zip: &mut zip::ZipArchive<File>,
+    index: usize,
+    hash_types: &[util::HashType],
+    rate_limit: Option<&util::RateLimiter>,
+    header: Option<util::HeaderRule>,
+) -> Result<(util::Hashes, Option<u32>)> {
+    let mut inner_file = zip.by_index(index)?;
+    let mut reader =
+        util::CrcReader::new(util::ThrottledReader::new(util::CrcCheckSkipReader::new(&mut inner_file), rate_limit));
+    let hashes = util::calc_hashes(&mut util::HeaderSkipReader::new(&mut reader, header), hash_types)?;
+    Ok((hashes, Some(reader.crc())))
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub struct FileMatch {
-    pub status: db::MatchStatus,
-    pub set_id: db::SetId,
-    pub rom_id: db::RomId,
-}
+//the name, hash, size and modification time of an entry in a 7z archive
+type HashedEntry = (String, FileHashes, Option<String>);
 
-fn match_roms(
+fn scan_7z_file(
     conn: &Connection,
     dat_id: &db::DatId,
-    filename: &str,
-    file_size: u64,
-    hash: &str,
-    matched_sets: &BTreeSet<db::SetId>,
-) -> Result<Option<Vec<FileMatch>>> {
-    // Step 1: is there any roms called the same as the filename?
-    let named_roms = db::RomRecord::find_by_name(conn, dat_id, filename, true)?;
-    if !named_roms.is_empty() {
-        //Step 2: if something is named the same, check for exact matches with those items, and return if so.
-        let exact_matches = match_exact(file_size, hash, matched_sets, &named_roms);
-        if exact_matches.is_some() {
-            return Ok(exact_matches);
+    path: &Utf8Path,
+    incremental: bool,
+    options: &ScanOptions,
+    parent_id: &db::DirId,
+) -> Result<Vec<ScannedFile>> {
+    let maybe_dir = db::DirRecord::get_by_dat_path(conn, dat_id, path.as_str())?;
+    if incremental
+        && maybe_dir
+            .as_ref()
+            .is_some_and(|dir| options.is_unchanged_archive(dir, path))
+    {
+        //if incremental and we have scanned this 7z file before and it hasn't changed since, skip it
+        return Ok(Vec::new());
+    }
+
+    let dir_id = match maybe_dir {
+        Some(dir) => {
+            //wipe existing file records and do full scan
+            let _ = dir.delete_files(conn)?;
+            dir.id
+        }
+        None => {
+            let dir = db::DirRecord::insert(
+                conn,
+                &db::NewDir {
+                    dat_id: dat_id.clone(),
+                    path: path.to_string(),
+                    parent_id: Some(parent_id.clone()),
+                },
+            )?;
+            dir.id
+        }
+    };
+    db::DirRecord::update_hashed_with(conn, &dir_id, &options.hashed_with())?;
+    db::DirRecord::update_archive_state(conn, &dir_id, path.metadata()?.len(), util::modified_at(path).as_deref())?;
+
+    let matched = match_sets(conn, dat_id, path)?;
+    db::DirRecord::update_target_set(conn, &dir_id, matched.first())?;
+    if let Some(files) = archive_scanned_elsewhere(conn, dat_id, path, options)? {
+        return insert_scanned_elsewhere(conn, dat_id, &dir_id, path, files, &matched, options);
+    }
+
+    //entries are hashed while the archive is decompressed, and only inserted once it all decompressed fine
+    let reader = sevenz_rust::SevenZReader::open(path, sevenz_rust::Password::empty())
+        .with_context(|| format!("could not open '{}' as a 7z file", path))?;
+    let archive = reader.archive();
+    let (exclude, hash_type, hash_types, rate_limit, header) =
+        (options.exclude, options.hash_type, &options.hash_types, options.rate_limit.as_ref(), options.header);
+    let hash_entry = |entry: &sevenz_rust::SevenZArchiveEntry, reader: &mut dyn Read| -> Result<Option<HashedEntry>> {
+        if entry.is_directory() || util::has_extension(entry.name(), exclude) || util::is_archive_junk(entry.name()) {
+            //entries in a solid archive are decompressed in order, so a skipped one still has to be read past
+            std::io::copy(reader, &mut std::io::sink())?;
+            return Ok(None);
+        }
+        let reader = util::ThrottledReader::new(reader, rate_limit);
+        let hashes = util::calc_hashes(&mut util::HeaderSkipReader::new(reader, header), hash_types)
+            .with_context(|| format!("could not read `{}`", entry.name()))?;
+        let modified_at = entry
+            .has_last_modified_date
+            .then(|| util::modified_at_from_filetime(entry.last_modified_date.to_raw()))
+            .flatten();
+        Ok(Some((entry.name().to_string(), FileHashes::new(&hashes, hash_type), modified_at)))
+    };
+    //the entries of each block have to be decompressed in order, but the blocks can each be done on the pool
+    let blocks: Vec<Result<Vec<HashedEntry>>> = options.matching.timed(ScanPhase::Hashing, || {
+        options.pool.install(|| {
+            (0..archive.folders.len())
+                .into_par_iter()
+                .map(|block| {
+                    let mut source = File::open(path)?;
+                    let mut hashed = Vec::new();
+                    let mut hash_error = None;
+                    sevenz_rust::BlockDecoder::new(block, archive, &[], &mut source).for_each_entries(
+                        &mut |entry, reader| match hash_entry(entry, reader) {
+                            Ok(entry) => {
+                                hashed.extend(entry);
+                                Ok(true)
+                            }
+                            Err(error) => {
+                                hash_error = Some(error);
+                                Ok(false)
+                            }
+                        },
+                    )?;
+                    match hash_error {
+                        Some(error) => Err(error),
+                        None => Ok(hashed),
+                    }
+                })
+                .collect()
+        })
+    });
+    let mut hashed = Vec::new();
+    for block in blocks {
+        hashed.extend(block?);
+    }
+    //empty files aren't in any block
+    for (index, entry) in archive.files.iter().enumerate() {
+        if archive.stream_map.file_folder_index[index].is_none() {
+            hashed.extend(hash_entry(entry, &mut std::io::empty())?);
+        }
+    }
+
+    let mut scanned_files = Vec::new();
+    for (name, hashed, modified_at) in hashed {
+        let new_file = db::NewFile {
+            modified_at,
+            crc: hashed.crc,
+            ..db::NewFile::new(dat_id, &dir_id, &name, hashed.size, &hashed.hash)
+        };
+        let (file_id, matches) = insert_files_and_matches(conn, &new_file, &matched, &options.matching)?;
+        scanned_files.push(ScannedFile {
+            file_id,
+            path: path.join(&name),
+            hash: hashed.hash,
+            size: hashed.size,
+            matches,
+        });
+    }
+    Ok(scanned_files)
+}
+
+fn scan_gzip_file(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    path: &Utf8Path,
+    incremental: bool,
+    options: &ScanOptions,
+    parent_id: &db::DirId,
+) -> Result<Vec<ScannedFile>> {
+    let maybe_dir = db::DirRecord::get_by_dat_path(conn, dat_id, path.as_str())?;
+    if incremental
+        && maybe_dir
+            .as_ref()
+            .is_some_and(|dir| options.is_unchanged_archive(dir, path))
+    {
+        //if incremental and we have scanned this gzip file before and it hasn't changed since, skip it
+        return Ok(Vec::new());
+    }
+
+    let dir_id = match maybe_dir {
+        Some(dir) => {
+            //wipe existing file records and do full scan
+            let _ = dir.delete_files(conn)?;
+            dir.id
+        }
+        None => {
+            let dir = db::DirRecord::insert(
+                conn,
+                &db::NewDir {
+                    dat_id: dat_id.clone(),
+                    path: path.to_string(),
+                    parent_id: Some(parent_id.clone()),
+                },
+            )?;
+            dir.id
+        }
+    };
+    db::DirRecord::update_hashed_with(conn, &dir_id, &options.hashed_with())?;
+    db::DirRecord::update_archive_state(conn, &dir_id, path.metadata()?.len(), util::modified_at(path).as_deref())?;
+
+    if let Some(files) = archive_scanned_elsewhere(conn, dat_id, path, options)? {
+        return insert_scanned_elsewhere(conn, dat_id, &dir_id, path, files, &BTreeSet::new(), options);
+    }
+
+    let mut decoder = flate2::bufread::GzDecoder::new(BufReader::new(File::open(path)?));
+    let reader = util::ThrottledReader::new(&mut decoder, options.rate_limit.as_ref());
+    let hashes = options.matching.timed(ScanPhase::Hashing, || {
+        util::calc_hashes(&mut util::HeaderSkipReader::new(reader, options.header), &options.hash_types)
+    })?;
+    let hashed = FileHashes::new(&hashes, options.hash_type);
+    let name = gzip_inner_name(path, decoder.header());
+    let new_file = db::NewFile {
+        modified_at: util::modified_at(path),
+        crc: hashed.crc,
+        ..db::NewFile::new(dat_id, &dir_id, &name, hashed.size, &hashed.hash)
+    };
+    let (file_id, matches) = insert_files_and_matches(conn, &new_file, &BTreeSet::new(), &options.matching)?;
+    Ok(vec![ScannedFile {
+        file_id,
+        path: path.join(&name),
+        hash: hashed.hash,
+        size: hashed.size,
+        matches,
+    }])
+}
+
+//the same archive scanned for another dat file, if it hasn't changed since and was hashed the same way, so its
+//entries don't need to be read again
+fn archive_scanned_elsewhere(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    path: &Utf8Path,
+    options: &ScanOptions,
+) -> Result<Option<Vec<db::FileRecord>>> {
+    if options.force_rehash || options.verify_crc {
+        return Ok(None);
+    }
+    for dir in db::DirRecord::get_by_path(conn, path.as_str())? {
+        if dir.dat_id != *dat_id && options.is_unchanged_archive(&dir, path) {
+            return Ok(Some(dir.get_files(conn)?));
+        }
+    }
+    Ok(None)
+}
+
+fn insert_scanned_elsewhere(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    dir_id: &db::DirId,
+    path: &Utf8Path,
+    files: Vec<db::FileRecord>,
+    matched: &BTreeSet<db::SetId>,
+    options: &ScanOptions,
+) -> Result<Vec<ScannedFile>> {
+    let mut scanned_files = Vec::new();
+    for file in files
+        .into_iter()
+        .filter(|file| !util::has_extension(&file.name, options.exclude))
+    {
+        let new_file = db::NewFile {
+            compression: file.compression,
+            compressed_size: file.compressed_size.map(db::SizeWrapper),
+            modified_at: file.modified_at,
+            crc: file.crc,
+            ..db::NewFile::new(dat_id, dir_id, &file.name, file.size, &file.hash)
+        };
+        let (file_id, matches) = insert_files_and_matches(conn, &new_file, matched, &options.matching)?;
+        scanned_files.push(ScannedFile {
+            file_id,
+            path: path.join(&file.name),
+            hash: file.hash,
+            size: file.size,
+            matches,
+        });
+    }
+    Ok(scanned_files)
+}
+
+//the original name is stored in the header by most tools, otherwise it is the name without the `.gz`
+fn gzip_inner_name(path: &Utf8Path, header: Option<&flate2::GzHeader>) -> String {
+    header
+        .and_then(|header| header.filename())
+        .and_then(|name| std::str::from_utf8(name).ok())
+        .and_then(|name| Utf8Path::new(name).file_name())
+        .or_else(|| path.file_stem())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn match_sets<P: AsRef<Utf8Path>>(conn: &Connection, dat_id: &db::DatId, path: P) -> Result<BTreeSet<db::SetId>> {
+    let name = path.as_ref().file_prefix().context("should have a file name")?;
+    let sets = db::SetRecord::find_by_name(conn, dat_id, name, true)?;
+    let matched: BTreeSet<db::SetId> = sets.iter().map(|record| record.id.clone()).collect();
+    Ok(matched)
+}
+
+// What is kept from hashing a file: the dat's own hash, the crc when the dat has crcs, and the size hashed
+struct FileHashes {
+    hash: String,
+    crc: Option<String>,
+    size: u64,
+}
+
+impl FileHashes {
+    fn new(hashes: &util::Hashes, hash_type: util::HashType) -> Self {
+        FileHashes {
+            hash: hashes
+                .get(hash_type)
+                .expect("the dat's hash should be calculated")
+                .to_string(),
+            crc: hashes.get(util::HashType::Crc).map(str::to_string),
+            size: hashes.size,
+        }
+    }
+
+    //what an earlier scan of the file found
+    fn of(file: &db::FileRecord) -> Self {
+        FileHashes {
+            hash: file.hash.clone(),
+            crc: file.crc.clone(),
+            size: file.size,
+        }
+    }
+
+    fn is_same_as(&self, file: &db::FileRecord) -> bool {
+        self.hash == file.hash && self.size == file.size
+    }
+}
+
+//the size is of what was hashed, so doesn't include a skipped header
+fn hash_file(
+    path: &Utf8Path,
+    hash_types: &[util::HashType],
+    rate_limit: Option<&util::RateLimiter>,
+    header: Option<util::HeaderRule>,
+) -> Result<util::Hashes> {
+    let file = File::open(path)?;
+    let reader = util::ThrottledReader::new(BufReader::new(&file), rate_limit);
+    util::calc_hashes(&mut util::HeaderSkipReader::new(reader, header), hash_types)
+}
+
+fn hash_split_files(
+    paths: &[Utf8PathBuf],
+    hash_types: &[util::HashType],
+    rate_limit: Option<&util::RateLimiter>,
+    header: Option<util::HeaderRule>,
+) -> Result<util::Hashes> {
+    //chain the parts together so they are hashed as one file
+    let mut reader: Box<dyn Read> = Box::new(std::io::empty());
+    for path in paths {
+        reader = Box::new(reader.chain(BufReader::new(File::open(path)?)));
+    }
+    let reader = util::ThrottledReader::new(reader, rate_limit);
+    util::calc_hashes(&mut util::HeaderSkipReader::new(reader, header), hash_types)
+}
+
+// Finds the parts of a file that was scanned as joined split files, if it was
+fn find_split_files(dir: &Utf8Path, name: &str) -> Vec<Utf8PathBuf> {
+    (1..)
+        .map(|part| dir.join(format!("{name}.{part:03}")))
+        .take_while(|path| path.is_file())
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FileMatch {
+    pub status: db::MatchStatus,
+    pub set_id: db::SetId,
+    pub rom_id: db::RomId,
+}
+
+//zip entries carry a crc in the central directory, so when the dat has crcs an entry can be
+//matched without decompressing it. the crc and size have to point at a single rom hash,
+//otherwise the entry is hashed as usual. this trusts the archive: a damaged entry with an
+//intact crc is matched as good, which is what `--verify-crc` is there to catch.
+fn hash_from_crc(conn: &Connection, dat_id: &db::DatId, crc: u32, size: u64) -> Result<Option<String>> {
+    let roms = db::RomRecord::get_by_crc(conn, dat_id, &format!("{crc:08x}"))?;
+    let mut hashes = roms.iter().filter(|rom| rom.size == Some(size)).map(|rom| &rom.hash);
+    match hashes.next() {
+        Some(hash) if hashes.all(|other| other == hash) => Ok(Some(hash.clone())),
+        _ => Ok(None),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn match_roms(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    filename: &str,
+    file_size: u64,
+    hash: &str,
+    crc: Option<&str>,
+    matched_sets: &BTreeSet<db::SetId>,
+    options: &MatchOptions,
+) -> Result<Option<Vec<FileMatch>>> {
+    let filename = filename.trim();
+    let crc = crc.filter(|_| options.by_crc);
+    let mut hash_roms = match crc {
+        Some(crc) => db::RomRecord::get_by_crc(conn, dat_id, crc)?,
+        None => db::RomRecord::get_by_hash(conn, dat_id, hash)?,
+    };
+    // Step 1: is there any roms called the same as the filename, or have it as an alias?
+    let mut named_roms = db::RomRecord::find_by_name(conn, dat_id, filename, true)?;
+    for rom in db::RomRecord::get_by_alias(conn, dat_id, filename)? {
+        if !named_roms.contains(&rom) {
+            named_roms.push(rom);
+        }
+    }
+    //a rom with a name that can't be used on disk is also named the same as a file with its mapped name
+    for rom in &hash_roms {
+        if options.name_map.apply(&rom.name) == filename && !named_roms.contains(rom) {
+            named_roms.push(rom.clone());
+        }
+    }
+    if !named_roms.is_empty() {
+        //Step 2: if something is named the same, check for exact matches with those items, and return if so.
+        let exact_matches = match_exact(file_size, hash, crc, matched_sets, &named_roms);
+        if exact_matches.is_some() {
+            return Ok(exact_matches);
         }
     }
     // Step 3: if something is named the same, but the hash doesn't match,
     // check whether we got hash only matches if we ignore the filename.
     // If so, then treat it as a hash match, otherwise return the name only matches,
     // if there are any.
-    let hash_roms = db::RomRecord::get_by_hash(conn, dat_id, hash)?;
+    if options.strict_size {
+        hash_roms.retain(|rom| rom.size_matches(file_size));
+    }
     if hash_roms.is_empty() {
-        Ok(match_names(matched_sets, &named_roms))
+        Ok(match_names(file_size, matched_sets, &named_roms))
     } else {
         Ok(match_hashes(matched_sets, &hash_roms))
     }
 }
 
+//only matched by crc when one is given
 fn match_exact(
     file_size: u64,
     hash: &str,
+    crc: Option<&str>,
     matched_sets: &BTreeSet<db::Id<db::SetRecord>>,
     named_roms: &[db::RomRecord],
 ) -> Option<Vec<FileMatch>> {
+    let hash_matches = |rom: &db::RomRecord| match crc {
+        Some(crc) => rom.crc.as_deref() == Some(crc),
+        None => hash == rom.hash,
+    };
     let matches: Vec<_> = named_roms
         .iter()
         .filter(|rom| matched_sets.is_empty() || matched_sets.contains(&rom.set_id))
-        .filter(|rom| file_size == rom.size && hash == rom.hash)
+        .filter(|rom| rom.size_matches(file_size) && hash_matches(rom))
         .map(|rom| FileMatch {
             status: db::MatchStatus::Match,
             set_id: rom.set_id.clone(),
@@ -938,12 +3996,21 @@ fn match_exact(
     if matches.is_empty() { None } else { Some(matches) }
 }
 
-fn match_names(matched_sets: &BTreeSet<db::Id<db::SetRecord>>, named_roms: &[db::RomRecord]) -> Option<Vec<FileMatch>> {
+fn match_names(
+    file_size: u64,
+    matched_sets: &BTreeSet<db::Id<db::SetRecord>>,
+    named_roms: &[db::RomRecord],
+) -> Option<Vec<FileMatch>> {
     let matches: Vec<_> = named_roms
         .iter()
         .filter(|rom| matched_sets.is_empty() || matched_sets.contains(&rom.set_id))
         .map(|rom| FileMatch {
-            status: db::MatchStatus::Name,
+            //a different size as well as hash is unlikely to be a bad dump of the rom
+            status: match rom.size {
+                Some(size) if size == file_size => db::MatchStatus::Corrupt,
+                Some(_) => db::MatchStatus::NameOnly,
+                None => db::MatchStatus::Name,
+            },
             set_id: rom.set_id.clone(),
             rom_id: rom.id.clone(),
         })
@@ -966,25 +4033,13 @@ fn match_hashes(matched_sets: &BTreeSet<db::Id<db::SetRecord>>, hash_roms: &[db:
 
 fn insert_files_and_matches(
     conn: &Connection,
-    dat_id: &db::DatId,
-    dir_id: &db::DirId,
-    file_name: &str,
-    file_size: u64,
-    hash: &str,
+    new_file: &db::NewFile,
     matched_sets: &BTreeSet<db::SetId>,
-) -> Result<()> {
-    let file = db::FileRecord::insert(
-        conn,
-        &db::NewFile {
-            dat_id: dat_id.clone(),
-            dir_id: dir_id.clone(),
-            name: file_name.to_string(),
-            size: db::SizeWrapper(file_size),
-            hash: hash.to_string(),
-        },
-    )?;
-
-    insert_matches(conn, dat_id, &file, matched_sets)
+    options: &MatchOptions,
+) -> Result<(db::FileId, Vec<FileMatch>)> {
+    let file = options.timed(ScanPhase::Inserting, || db::FileRecord::insert(conn, new_file))?;
+    let matches = insert_matches(conn, &new_file.dat_id, &file, matched_sets, options)?;
+    Ok((file.id, matches))
 }
 
 fn insert_matches(
@@ -992,23 +4047,25 @@ fn insert_matches(
     dat_id: &db::DatId,
     file: &db::FileRecord,
     matched_sets: &BTreeSet<db::Id<db::SetRecord>>,
-) -> std::result::Result<(), anyhow::Error> {
-    let matched = match_roms(conn, dat_id, &file.name, file.size, &file.hash, matched_sets)?;
-    if let Some(items) = matched {
-        for item in items {
-            db::MatchRecord::insert(
-                conn,
-                &db::NewMatch {
-                    dat_id: dat_id.clone(),
-                    file_id: file.id.clone(),
-                    status: item.status,
-                    set_id: item.set_id,
-                    rom_id: item.rom_id,
-                },
-            )?;
-        }
-    }
-    Ok(())
+    options: &MatchOptions,
+) -> Result<Vec<FileMatch>> {
+    let matched = options
+        .timed(ScanPhase::Matching, || {
+            match_roms(conn, dat_id, &file.name, file.size, &file.hash, file.crc.as_deref(), matched_sets, options)
+        })?
+        .unwrap_or_default();
+    let new_matches: Vec<_> = matched
+        .iter()
+        .map(|item| db::NewMatch {
+            dat_id: dat_id.clone(),
+            file_id: file.id.clone(),
+            status: item.status.clone(),
+            set_id: item.set_id.clone(),
+            rom_id: item.rom_id.clone(),
+        })
+        .collect();
+    options.timed(ScanPhase::Inserting, || db::MatchRecord::insert_many(conn, &new_matches))?;
+    Ok(matched)
 }
 
 fn should_display_file_status(status: Option<&db::MatchStatus>, mode: &ListMode) -> bool {
@@ -1017,6 +4074,8 @@ fn should_display_file_status(status: Option<&db::MatchStatus>, mode: &ListMode)
         (None, ListMode::Unmatched | ListMode::All)
             | (Some(db::MatchStatus::Hash), ListMode::Warning | ListMode::All)
             | (Some(db::MatchStatus::Name), ListMode::Warning | ListMode::All)
+            | (Some(db::MatchStatus::Corrupt), ListMode::Warning | ListMode::All)
+            | (Some(db::MatchStatus::NameOnly), ListMode::Warning | ListMode::All)
             | (Some(db::MatchStatus::Match), ListMode::Matched | ListMode::All)
     )
 }
@@ -1030,13 +4089,20 @@ fn format_file_indicator(status: Option<&db::MatchStatus>, is_tty: bool) -> &str
                 "NONE"
             }
         }
-        Some(db::MatchStatus::Hash) | Some(db::MatchStatus::Name) => {
+        Some(db::MatchStatus::Hash) | Some(db::MatchStatus::Name) | Some(db::MatchStatus::NameOnly) => {
             if is_tty {
                 "⚠️"
             } else {
                 "WARN"
             }
         }
+        Some(db::MatchStatus::Corrupt) => {
+            if is_tty {
+                "💥"
+            } else {
+                "BAD "
+            }
+        }
         Some(db::MatchStatus::Match) => {
             if is_tty {
                 "✅"
@@ -1048,82 +4114,260 @@ fn format_file_indicator(status: Option<&db::MatchStatus>, is_tty: bool) -> &str
 }
 
 fn format_file_status(
-    conn: &Connection,
     file: &db::FileRecord,
-    matched: Option<&db::MatchRecord>,
+    matched: Option<(&db::MatchRecord, &db::RomRecord)>,
     is_tty: bool,
-) -> Result<String> {
-    let indicator = format_file_indicator(matched.map(|m| &m.status), is_tty);
-    let result = match matched {
-        None => {
-            format!("[{indicator}] {} {} - unknown file", file.hash, file.name)
-        }
-        Some(m) => match m.status {
-            db::MatchStatus::Hash => {
-                let rom = db::RomRecord::get_by_id(conn, &m.rom_id)?;
-                format!("[{indicator}] {} {} - incorrect name, should be named {}", file.hash, file.name, rom.name)
-            }
-            db::MatchStatus::Name => {
-                let rom = db::RomRecord::get_by_id(conn, &m.rom_id)?;
-                format!("[{indicator}] {} {} - incorrect hash, should have hash {}", file.hash, file.name, rom.hash)
-            }
-            db::MatchStatus::Match => {
-                format!("[{indicator}] {} {}", file.hash, file.name)
-            }
-        },
+) -> Vec<String> {
+    let indicator = format_file_indicator(matched.map(|(m, _)| &m.status), is_tty);
+    let message = match matched {
+        None => format!("unknown file, {}", util::human_size(file.size)),
+        Some((m, rom)) => format_match_message(&m.status, rom),
     };
-    Ok(result)
+    vec![format!("[{indicator}]"), file.hash.clone(), file.name.clone(), message]
 }
 
-fn list_files(
-    conn: &mut Connection,
-    dat_id: &db::DatId,
-    term: &TermInfo,
-    mode: &ListMode,
-    partial_name: Option<&str>,
-) -> Result<()> {
+//the same fields as a line of `scan --json-lines`, with the set and rom names when matched
+fn file_json(
+    dir_path: &str,
+    file: &db::FileRecord,
+    matched: Option<&db::MatchRecord>,
+    set_names: &BTreeMap<db::SetId, String>,
+    roms_by_id: &BTreeMap<db::RomId, db::RomRecord>,
+) -> serde_json::Value {
+    let set = matched.and_then(|m| set_names.get(&m.set_id));
+    let rom = matched.and_then(|m| roms_by_id.get(&m.rom_id)).map(|rom| &rom.name);
+    serde_json::json!({
+        "path": Utf8Path::new(dir_path).join(&file.name).as_str(),
+        "name": file.name,
+        "hash": file.hash,
+        "crc": file.crc,
+        "size": file.size,
+        "status": matched.map_or("unknown", |m| m.status.as_str()),
+        "set": set,
+        "rom": rom,
+    })
+}
+
+fn format_match_message(status: &db::MatchStatus, rom: &db::RomRecord) -> String {
+    match status {
+        db::MatchStatus::Hash => format!("incorrect name, should be named {}", rom.name),
+        db::MatchStatus::Name => format!("incorrect hash, should have hash {}", rom.hash),
+        db::MatchStatus::Corrupt => format!("corrupt, has the right size but should have hash {}", rom.hash),
+        db::MatchStatus::NameOnly => format!(
+            "different file with the same name, should have size {} and hash {}",
+            format_rom_size(rom.size),
+            rom.hash
+        ),
+        db::MatchStatus::Match => String::new(),
+    }
+}
+
+struct ListOptions<'a> {
+    mode: &'a ListMode,
+    sort: &'a ListSort,
+    limit: Option<usize>,
+    summary_only: bool,
+    partial_name: Option<&'a str>,
+    glob: bool,
+}
+
+impl<'a> ListOptions<'a> {
+    fn new(mode: &'a ListMode, partial_name: Option<&'a str>) -> Self {
+        ListOptions {
+            mode,
+            sort: &ListSort::Name,
+            limit: None,
+            summary_only: false,
+            partial_name,
+            glob: false,
+        }
+    }
+}
+
+//how much of the set an archive is named for its files match, so an archive that matches nothing still has context
+fn format_archive_target(
+    dir: &db::DirRecord,
+    set_names: &BTreeMap<db::SetId, String>,
+    rom_counts: &BTreeMap<&db::SetId, usize>,
+    files_by_dir: &BTreeMap<&db::DirId, Vec<&db::FileRecord>>,
+    matches_by_file: &BTreeMap<&db::FileId, Vec<&db::MatchRecord>>,
+) -> Option<String> {
+    let set_id = dir.target_set_id.as_ref()?;
+    let found: BTreeSet<_> = files_by_dir
+        .get(&dir.id)
+        .into_iter()
+        .flatten()
+        .flat_map(|file| matches_by_file.get(&file.id).into_iter().flatten())
+        .filter(|m| m.set_id == *set_id)
+        .map(|m| &m.rom_id)
+        .collect();
+    let roms = rom_counts.get(set_id).copied().unwrap_or_default();
+    Some(format!("archive intended for set {}, {}/{roms} matched.", set_names.get(set_id)?, found.len()))
+}
+
+//files scanned for several dat files are listed together, so a file is only unmatched if none of them match it
+fn list_files(conn: &mut Connection, dat_ids: &[db::DatId], term: &TermInfo, options: &ListOptions) -> Result<()> {
     //get these in bulk to avoid doing a query per file when we display them
-    let matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
+    let mut matches = Vec::new();
+    let mut dirs = Vec::new();
+    let mut all_files = Vec::new();
+    let mut dat_names = BTreeMap::new();
+    for dat_id in dat_ids {
+        matches.extend(db::MatchRecord::get_by_dat(conn, dat_id)?);
+        dirs.extend(db::DirRecord::get_by_dat(conn, dat_id)?);
+        all_files.extend(db::FileRecord::get_by_dat(conn, dat_id)?);
+        dat_names.insert(dat_id.clone(), db::DatRecord::get_by_id(conn, dat_id)?.name);
+    }
     let matches_by_file: BTreeMap<_, Vec<_>> = matches.iter().fold(BTreeMap::new(), |mut acc, m| {
         acc.entry(&m.file_id).or_default().push(m);
         acc
     });
+    let files_by_dir: BTreeMap<_, Vec<_>> = all_files.iter().fold(BTreeMap::new(), |mut acc, file| {
+        acc.entry(&file.dir_id).or_default().push(file);
+        acc
+    });
+    //which dat file matched is only worth showing when there is more than one
+    let dat_name = |file: &db::FileRecord| (dat_ids.len() > 1).then(|| dat_names[&file.dat_id].as_str());
 
-    let dirs = db::DirRecord::get_by_dat(conn, dat_id)?;
-    for dir in dirs {
-        let files = if let Some(partial_name) = partial_name {
-            dir.find_files(conn, partial_name, false)?
-        } else {
-            dir.get_files(conn)?
-        };
+    //a path scanned for several dat files has a dir record for each
+    let paths: BTreeMap<_, Vec<_>> = dirs.iter().fold(BTreeMap::new(), |mut acc, dir| {
+        acc.entry(dir.path.as_str()).or_default().push(dir);
+        acc
+    });
 
-        if files.is_empty() {
-            continue;
+    //collect everything first, so that sorting and limiting can apply across directories
+    let mut entries = Vec::new();
+    let (mut matched, mut warnings, mut unmatched) = (0, 0, 0);
+    for (path, path_dirs) in &paths {
+        let mut files_by_name: BTreeMap<String, Vec<db::FileRecord>> = BTreeMap::new();
+        for dir in path_dirs {
+            let files = if let Some(partial_name) = options.partial_name {
+                if options.glob {
+                    dir.glob_files(conn, partial_name)?
+                } else {
+                    dir.find_files(conn, partial_name, false)?
+                }
+            } else {
+                files_by_dir
+                    .get(&dir.id)
+                    .into_iter()
+                    .flatten()
+                    .map(|file| (*file).clone())
+                    .collect()
+            };
+            for file in files {
+                files_by_name.entry(file.name.clone()).or_default().push(file);
+            }
         }
 
-        let mut lines = Vec::new();
-        for file in files {
-            if let Some(file_matches) = matches_by_file.get(&file.id) {
-                for fm in file_matches {
-                    if should_display_file_status(Some(&fm.status), mode) {
-                        lines.push(format_file_status(conn, &file, Some(fm), term.tty_out)?);
-                    }
+        for files in files_by_name.into_values() {
+            let statuses: Vec<_> = files
+                .iter()
+                .flat_map(|file| {
+                    matches_by_file
+                        .get(&file.id)
+                        .into_iter()
+                        .flatten()
+                        .map(move |fm| (file, Some(*fm)))
+                })
+                .collect();
+            let statuses = if statuses.is_empty() { vec![(&files[0], None)] } else { statuses };
+            for (file, fm) in statuses {
+                let status = fm.map(|fm| &fm.status);
+                if !should_display_file_status(status, options.mode) {
+                    continue;
+                }
+                match status {
+                    Some(db::MatchStatus::Match) => matched += 1,
+                    Some(_) => warnings += 1,
+                    None => unmatched += 1,
+                }
+                if !options.summary_only {
+                    entries.push((*path, file.clone(), fm));
                 }
-            } else if should_display_file_status(None, mode) {
-                lines.push(format_file_status(conn, &file, None, term.tty_out)?);
             }
         }
+    }
 
-        if lines.is_empty() {
-            continue;
+    if let ListSort::Size = options.sort {
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.1.size));
+    }
+    if let Some(limit) = options.limit {
+        entries.truncate(limit);
+    }
+
+    //the sets and roms that were matched or archives are named for, to show them without a query per file
+    let mut set_names = BTreeMap::new();
+    let mut roms_by_id = BTreeMap::new();
+    if entries.iter().any(|(_, _, fm)| fm.is_some()) || dirs.iter().any(|dir| dir.target_set_id.is_some()) {
+        for dat_id in dat_ids {
+            set_names.extend(
+                db::SetRecord::get_by_dat(conn, dat_id)?
+                    .into_iter()
+                    .map(|set| (set.id, set.name)),
+            );
+            roms_by_id.extend(
+                db::RomRecord::get_by_dat(conn, dat_id)?
+                    .into_iter()
+                    .map(|rom| (rom.id.clone(), rom)),
+            );
         }
+    }
 
-        println!("--- FILES IN '{}' ---", dir.path);
-        let mut lock = std::io::stdout().lock();
-        for line in lines {
-            writeln!(lock, "{}", &line)?;
+    if term.json() {
+        let mut files = Vec::new();
+        for (path, file, fm) in entries {
+            let mut json = file_json(path, &file, fm, &set_names, &roms_by_id);
+            if let (Some(dat_name), Some(_)) = (dat_name(&file), fm) {
+                json["dat"] = dat_name.into();
+            }
+            files.push(json);
         }
+        let listing = serde_json::json!({
+            "files": files,
+            "matched": matched,
+            "warnings": warnings,
+            "unmatched": unmatched,
+        });
+        println!("{}", serde_json::to_string_pretty(&listing)?);
+        return Ok(());
     }
+
+    let rom_counts = roms_by_id
+        .values()
+        .fold(BTreeMap::new(), |mut acc, rom: &db::RomRecord| {
+            *acc.entry(&rom.set_id).or_default() += 1;
+            acc
+        });
+
+    //keep the directories in order of their first entry, so the largest files come first when sorting by size
+    let mut tables: BTreeMap<&str, (usize, util::Table)> = BTreeMap::new();
+    for (path, file, fm) in entries {
+        let matched = fm.map(|fm| (fm, &roms_by_id[&fm.rom_id]));
+        let mut row = format_file_status(&file, matched, term.tty_out);
+        if let Some(dat_name) = dat_name(&file) {
+            row.push(fm.map_or_else(String::new, |_| format!("({dat_name})")));
+        }
+        let order = tables.len();
+        tables
+            .entry(path)
+            .or_insert_with(|| (order, util::Table::new()))
+            .1
+            .add_row(row);
+    }
+    let mut tables: Vec<_> = tables.into_iter().collect();
+    tables.sort_by_key(|(_, (order, _))| *order);
+
+    for (path, (_, table)) in tables {
+        println!("--- FILES IN '{path}' ---");
+        for dir in &paths[path] {
+            if let Some(target) = format_archive_target(dir, &set_names, &rom_counts, &files_by_dir, &matches_by_file) {
+                println!("{target}");
+            }
+        }
+        table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+    }
+    println!("{matched} matched, {warnings} warnings, {unmatched} unmatched.");
     Ok(())
 }
 
@@ -1159,21 +4403,165 @@ fn format_set_indicator(status: &SetStatus, is_tty: bool) -> &str {
     }
 }
 
-fn list_sets(
-    conn: &mut Connection,
-    dat_id: &db::DatId,
-    term: &TermInfo,
+struct SetListOptions<'a> {
     missing: bool,
-    partial_name: Option<&str>,
-) -> Result<()> {
-    //get these in bulk to avoid doing a query per file when we display them
-    let matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
+    summary_only: bool,
+    parents_only: bool,
+    renamable: bool,
+    percent: bool,
+    show_paths: bool,
+    write_dat: Option<&'a Utf8Path>,
+    merge_mode: MergeMode,
+    partial_name: Option<&'a str>,
+}
+
+impl<'a> SetListOptions<'a> {
+    fn new(partial_name: Option<&'a str>) -> Self {
+        SetListOptions {
+            missing: false,
+            summary_only: false,
+            parents_only: false,
+            renamable: false,
+            percent: false,
+            show_paths: false,
+            write_dat: None,
+            merge_mode: MergeMode::Split,
+            partial_name,
+        }
+    }
+}
+
+// A set as it is laid out for a merge mode, with every rom it needs to be complete
+struct MergedSet<'a> {
+    set: &'a db::SetRecord,
+    //the sets whose matched files are part of this one, which is only more than itself for merged sets
+    members: Vec<&'a db::SetId>,
+    //each rom with the name it has in the set
+    roms: Vec<(String, &'a db::RomRecord)>,
+}
+
+//the set's own roms come first, then those of its parents or clones. a rom named the same as one already in the
+//set is left out when it is the same rom, otherwise a merged set keeps it in a folder named for its clone, as
+//clrmamepro does, and a non-merged set leaves it out as the set's own version of it came first
+fn merge_sets<'a>(sets: &'a [db::SetRecord], roms: &'a [db::RomRecord], mode: MergeMode) -> Vec<MergedSet<'a>> {
+    let sets_by_id: BTreeMap<_, _> = sets.iter().map(|set| (&set.id, set)).collect();
+    let mut roms_by_set: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for rom in roms {
+        roms_by_set.entry(&rom.set_id).or_default().push(rom);
+    }
+
+    //the parents of a set, nearest first, the limit stops a broken dat from looping forever
+    let parents = |set: &'a db::SetRecord| {
+        let mut parents = Vec::new();
+        let mut current = set;
+        for _ in 0..sets.len() {
+            match current.parent_id.as_ref().and_then(|id| sets_by_id.get(id)) {
+                Some(parent) => {
+                    parents.push(&parent.id);
+                    current = parent;
+                }
+                None => break,
+            }
+        }
+        parents
+    };
+
+    let layout: Vec<(&db::SetRecord, Vec<&db::SetId>, Vec<&db::SetId>)> = match mode {
+        MergeMode::Split => sets.iter().map(|set| (set, vec![&set.id], vec![&set.id])).collect(),
+        MergeMode::NonMerged => sets
+            .iter()
+            .map(|set| {
+                let rom_sets = std::iter::once(&set.id).chain(parents(set)).collect();
+                (set, vec![&set.id], rom_sets)
+            })
+            .collect(),
+        MergeMode::Merged => {
+            let mut families: BTreeMap<_, Vec<_>> = BTreeMap::new();
+            for set in sets {
+                let root = parents(set).last().copied().unwrap_or(&set.id);
+                families.entry(root).or_default().push(set);
+            }
+            //kept in the same order as the sets they are for
+            sets.iter()
+                .filter_map(|set| {
+                    let mut family = families.remove(&set.id)?;
+                    family.sort_by_key(|member| (member.id != set.id, &member.name));
+                    let members: Vec<_> = family.iter().map(|member| &member.id).collect();
+                    Some((set, members.clone(), members))
+                })
+                .collect()
+        }
+    };
+
+    layout
+        .into_iter()
+        .map(|(set, members, rom_sets)| {
+            let mut hashes_by_name = BTreeMap::new();
+            let roms = rom_sets
+                .into_iter()
+                .filter_map(|id| roms_by_set.get(id))
+                .flatten()
+                .copied()
+                .filter_map(|rom| {
+                    if mode == MergeMode::Split {
+                        return Some((rom.name.clone(), rom));
+                    }
+                    match hashes_by_name.get(&rom.name.to_ascii_lowercase()) {
+                        None => {
+                            hashes_by_name.insert(rom.name.to_ascii_lowercase(), &rom.hash);
+                            Some((rom.name.clone(), rom))
+                        }
+                        Some(hash) if **hash == rom.hash => None,
+                        Some(_) if mode == MergeMode::Merged => {
+                            Some((format!("{}/{}", sets_by_id[&rom.set_id].name, rom.name), rom))
+                        }
+                        Some(_) => None,
+                    }
+                })
+                .collect();
+            MergedSet { set, members, roms }
+        })
+        .collect()
+}
+
+fn list_sets(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    catalog: &DatCatalog,
+    term: &TermInfo,
+    options: &SetListOptions,
+) -> Result<()> {
+    let SetListOptions {
+        missing,
+        summary_only,
+        parents_only,
+        renamable,
+        percent,
+        show_paths,
+        write_dat,
+        merge_mode,
+        partial_name,
+    } = *options;
+    //get these in bulk to avoid doing a query per file when we display them
+    let matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
     let matches_by_file: BTreeMap<_, Vec<_>> = matches.iter().fold(BTreeMap::new(), |mut acc, m| {
         acc.entry(&m.file_id).or_default().push(m);
         acc
     });
 
     let all_files = db::FileRecord::get_by_dat(conn, dat_id)?;
+    let dirs_by_id: BTreeMap<_, _> = if show_paths {
+        db::DirRecord::get_by_dat(conn, dat_id)?
+            .into_iter()
+            .map(|dir| (dir.id, dir.path))
+            .collect()
+    } else {
+        BTreeMap::new()
+    };
+    let file_name = |file: &db::FileRecord| match dirs_by_id.get(&file.dir_id) {
+        Some(path) => Utf8Path::new(path).join(&file.name).to_string(),
+        None => file.name.clone(),
+    };
 
     let mut sets_to_files: BTreeMap<_, Vec<_>> = BTreeMap::new();
     let mut found_roms: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
@@ -1191,39 +4579,288 @@ fn list_sets(
         }
     }
 
-    let all_sets = db::SetRecord::get_by_dat(conn, dat_id)?;
-    if missing {
-        println!("--- MISSING SETS ---");
+    let merged_sets = merge_sets(&catalog.sets, &catalog.roms, merge_mode);
+    if merge_mode != MergeMode::Split {
+        //merged and non-merged sets hold roms of other sets, which only match the set their archive is named for,
+        //so a rom is also found when a file with the same contents is part of the set
+        let dirs = db::DirRecord::get_by_dat(conn, dat_id)?;
+        let target_sets: BTreeMap<_, _> = dirs
+            .iter()
+            .filter_map(|dir| dir.target_set_id.as_ref().map(|set_id| (&dir.id, set_id)))
+            .collect();
+        let mut archive_hashes: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+        for file in &all_files {
+            if let Some(set_id) = target_sets.get(&file.dir_id) {
+                archive_hashes.entry(*set_id).or_default().insert(file.hash.as_str());
+            }
+        }
+
+        let mut merged_files = BTreeMap::new();
+        let mut merged_found = BTreeMap::new();
+        for merged in &merged_sets {
+            let files: Vec<_> = merged
+                .members
+                .iter()
+                .filter_map(|id| sets_to_files.get(*id))
+                .flatten()
+                .copied()
+                .collect();
+            let hashes: BTreeSet<_> = files
+                .iter()
+                .filter(|(_, fm)| matches!(fm.status, db::MatchStatus::Match | db::MatchStatus::Hash))
+                .map(|(file, _)| file.hash.as_str())
+                .chain(
+                    merged
+                        .members
+                        .iter()
+                        .filter_map(|id| archive_hashes.get(id))
+                        .flatten()
+                        .copied(),
+                )
+                .collect();
+            let found: BTreeSet<_> = merged
+                .roms
+                .iter()
+                .map(|(_, rom)| rom)
+                .filter(|rom| {
+                    let matched = merged.members.contains(&&rom.set_id)
+                        && found_roms.get(&rom.set_id).is_some_and(|s| s.contains(&rom.id));
+                    matched || hashes.contains(rom.hash.as_str())
+                })
+                .map(|rom| rom.id.clone())
+                .collect();
+            if !files.is_empty() || !found.is_empty() {
+                merged_files.insert(merged.set.id.clone(), files);
+            }
+            merged_found.insert(merged.set.id.clone(), found);
+        }
+        sets_to_files = merged_files;
+        found_roms = merged_found;
+    }
+
+    let all_sets: Vec<_> = merged_sets
+        .iter()
+        .map(|merged| merged.set)
+        .filter(|set| !parents_only || set.parent_id.is_none())
+        .collect();
+    //the roms are named as they are in the set, which is only different for a clone's own roms in a merged set
+    let roms_by_set: BTreeMap<_, Vec<_>> = merged_sets
+        .iter()
+        .map(|merged| {
+            let roms = merged
+                .roms
+                .iter()
+                .map(|(name, rom)| {
+                    if *name == rom.name {
+                        Cow::Borrowed(*rom)
+                    } else {
+                        Cow::Owned(db::RomRecord {
+                            name: name.clone(),
+                            ..(*rom).clone()
+                        })
+                    }
+                })
+                .collect();
+            (&merged.set.id, roms)
+        })
+        .collect();
+    let roms_by_id: BTreeMap<_, _> = catalog.roms.iter().map(|rom| (&rom.id, rom)).collect();
+    let found_count = all_sets
+        .iter()
+        .filter(|set| sets_to_files.contains_key(&set.id))
+        .count();
+    let name_shown = |set: &db::SetRecord| {
+        partial_name.is_none_or(|partial_name| {
+            set.name
+                .to_ascii_lowercase()
+                .contains(&partial_name.to_ascii_lowercase())
+        })
+    };
+    if missing && let Some(path) = write_dat {
+        let dat = db::DatRecord::get_by_id(conn, dat_id)?;
+        let header = dat::DatHeader {
+            name: Some(dat.name.clone()),
+            description: Some(dat.description.clone()),
+            version: Some(dat.version.clone()),
+            author: Some(dat.author.clone()),
+            header_detector: dat.header_detector.clone(),
+            url: dat.url.clone(),
+        };
+        let out = std::io::BufWriter::new(File::create(path)?);
+        let mut writer = dat::DatWriter::new(out, &header, dat.hash_type.parse()?)?;
+        let (mut set_count, mut rom_count) = (0, 0);
+        for set in all_sets.iter().filter(|set| name_shown(set)) {
+            let found = found_roms.get(&set.id);
+            let roms: Vec<_> = roms_by_set
+                .get(&set.id)
+                .into_iter()
+                .flatten()
+                .filter(|rom| !found.is_some_and(|s| s.contains(&rom.id)))
+                .map(|rom| rom.clone().into_owned())
+                .collect();
+            if !roms.is_empty() {
+                writer.write_game(&set.name, &roms)?;
+                set_count += 1;
+                rom_count += roms.len();
+            }
+        }
+        writer.finish()?;
+        term.notice(&format!("{set_count} sets with {rom_count} missing roms written to {path}."));
+        return Ok(());
+    }
+    if missing && term.json() {
+        let names: Vec<_> = all_sets
+            .iter()
+            .filter(|set| !summary_only && !sets_to_files.contains_key(&set.id) && name_shown(set))
+            .map(|set| &set.name)
+            .collect();
+        let listing = serde_json::json!({
+            "missing": names,
+            "missing_count": all_sets.len() - found_count,
+            "total": all_sets.len(),
+        });
+        println!("{}", serde_json::to_string_pretty(&listing)?);
+    } else if missing {
         let status = format_set_indicator(&SetStatus::Missing, term.tty_out);
-        for set in &all_sets {
-            if let Some(partial_name) = partial_name
-                && !set
-                    .name
-                    .to_ascii_lowercase()
-                    .contains(&partial_name.to_ascii_lowercase())
-            {
+        if !summary_only {
+            println!("--- MISSING SETS ---");
+        }
+        for set in all_sets.iter().filter(|_| !summary_only) {
+            if !name_shown(set) {
                 continue;
             }
             println_if!(!sets_to_files.contains_key(&set.id), "[{status}] {}", set.name);
         }
-        println!("{} / {} sets missing.", all_sets.len() - sets_to_files.len(), all_sets.len());
+        println!("{} / {} sets missing.", all_sets.len() - found_count, all_sets.len());
     } else {
-        let all_roms = db::RomRecord::get_by_dat(conn, dat_id)?;
-        let mut roms_by_set: BTreeMap<_, Vec<_>> = BTreeMap::new();
-        all_roms
+        if percent {
+            let (mut found_total, mut expected_total) = (0, 0);
+            let mut completion = Vec::new();
+            for set in &all_sets {
+                let expected = roms_by_set.get(&set.id).map(|roms| roms.len()).unwrap_or_default();
+                let found = found_roms
+                    .get(&set.id)
+                    .map(|roms| roms.len())
+                    .unwrap_or_default()
+                    .min(expected);
+                found_total += found;
+                expected_total += expected;
+                if found == 0 || expected == 0 {
+                    continue;
+                }
+                if !name_shown(set) {
+                    continue;
+                }
+                completion.push((found * 100 / expected, &set.name, found, expected));
+            }
+            completion.sort();
+            let overall = (found_total * 100).checked_div(expected_total).unwrap_or_default();
+            if term.json() {
+                let sets: Vec<_> = completion
+                    .iter()
+                    .filter(|_| !summary_only)
+                    .map(|(percent, name, found, expected)| {
+                        serde_json::json!({ "name": name, "found": found, "expected": expected, "percent": percent })
+                    })
+                    .collect();
+                let listing = serde_json::json!({
+                    "sets": sets,
+                    "found_roms": found_total,
+                    "expected_roms": expected_total,
+                    "percent": overall,
+                });
+                println!("{}", serde_json::to_string_pretty(&listing)?);
+                return Ok(());
+            }
+            if !summary_only {
+                let mut table = util::Table::new();
+                for (percent, name, found, expected) in completion {
+                    table.add_row([format!("{percent}%"), format!("{found}/{expected}"), name.clone()]);
+                }
+                table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+            }
+            println!("{overall}% complete, {found_total} / {expected_total} roms found.");
+            return Ok(());
+        }
+
+        //a set is renamable if every rom is there by hash, and the only problem is loose files with the wrong name,
+        //as those are the only ones that renaming will fix
+        let zip_dirs: BTreeSet<_> = db::DirRecord::get_by_dat(conn, dat_id)?
+            .into_iter()
+            .filter(|dir| util::is_archive_file(&dir.path))
+            .map(|dir| dir.id)
+            .collect();
+        let renamable_sets: BTreeSet<_> = all_sets
             .iter()
-            .for_each(|rom| roms_by_set.entry(&rom.set_id).or_default().push(rom));
+            .filter(|set| {
+                let Some(files) = sets_to_files.get(&set.id) else {
+                    return false;
+                };
+                let rom_count = roms_by_set.get(&set.id).map(|roms| roms.len()).unwrap_or_default();
+                found_roms.get(&set.id).is_some_and(|s| s.len() >= rom_count)
+                    && files.iter().any(|(_, fm)| fm.status == db::MatchStatus::Hash)
+                    && files.iter().all(|(file, fm)| match fm.status {
+                        db::MatchStatus::Match => true,
+                        db::MatchStatus::Hash => !zip_dirs.contains(&file.dir_id),
+                        _ => false,
+                    })
+            })
+            .map(|set| &set.id)
+            .collect();
+
+        if term.json() {
+            let mut sets = Vec::new();
+            for set in all_sets
+                .iter()
+                .filter(|set| !summary_only && (!renamable || renamable_sets.contains(&set.id)) && name_shown(set))
+            {
+                let (Some(files), Some(roms)) = (sets_to_files.get(&set.id), roms_by_set.get(&set.id)) else {
+                    continue;
+                };
+                let found = found_roms.get(&set.id);
+                let files: Vec<_> = files
+                    .iter()
+                    .map(|(file, fm)| {
+                        serde_json::json!({
+                            "path": file_name(file),
+                            "hash": file.hash,
+                            "size": file.size,
+                            "status": fm.status.as_str(),
+                            "rom": roms_by_id[&fm.rom_id].name,
+                        })
+                    })
+                    .collect();
+                let missing: Vec<_> = roms
+                    .iter()
+                    .filter(|rom| !found.is_some_and(|s| s.contains(&rom.id)))
+                    .map(|rom| rom_json(rom))
+                    .collect();
+                sets.push(serde_json::json!({
+                    "name": set.name,
+                    "complete": missing.is_empty(),
+                    "files": files,
+                    "missing": missing,
+                }));
+            }
+            let listing = if renamable {
+                serde_json::json!({ "sets": sets, "renamable": renamable_sets.len(), "total": all_sets.len() })
+            } else {
+                serde_json::json!({ "sets": sets, "found": found_count, "total": all_sets.len() })
+            };
+            println!("{}", serde_json::to_string_pretty(&listing)?);
+            return Ok(());
+        }
 
-        println!("--- FOUND SETS ---");
         let partial_status = format_set_indicator(&SetStatus::Partial, term.tty_out);
         let complete_status = format_set_indicator(&SetStatus::Complete, term.tty_out);
-        for set in &all_sets {
-            if let Some(partial_name) = partial_name
-                && !set
-                    .name
-                    .to_ascii_lowercase()
-                    .contains(&partial_name.to_ascii_lowercase())
-            {
+        if !summary_only {
+            println!("{}", if renamable { "--- RENAMABLE SETS ---" } else { "--- FOUND SETS ---" });
+        }
+        for set in all_sets.iter().filter(|_| !summary_only) {
+            if renamable && !renamable_sets.contains(&set.id) {
+                continue;
+            }
+            if !name_shown(set) {
                 continue;
             }
 
@@ -1231,7 +4868,6 @@ fn list_sets(
                 && let Some(roms) = roms_by_set.get(&set.id)
             {
                 //iterate through the file matches and check if we have all the roms in the set matched, if so its complete, otherwise its partial
-                let roms_by_id: BTreeMap<_, _> = roms.iter().map(|&rom| (&rom.id, rom)).collect();
                 if found_roms.get(&set.id).is_some_and(|s| s.len() >= roms.len()) {
                     //we found the same number (or more) of unique roms that are in the set
                     println!("[{complete_status}] {}", set.name);
@@ -1241,21 +4877,34 @@ fn list_sets(
 
                 for (file, fm) in files {
                     let indicator = format_file_indicator(Some(&fm.status), term.tty_out);
+                    let name = file_name(file);
                     match fm.status {
                         db::MatchStatus::Hash => {
                             println!(
-                                " {indicator}  {} {}, should be named {}",
-                                file.hash, file.name, roms_by_id[&fm.rom_id].name
+                                " {indicator}  {} {name}, should be named {}",
+                                file.hash, roms_by_id[&fm.rom_id].name
                             );
                         }
                         db::MatchStatus::Name => {
                             println!(
-                                "  {indicator}  {} {}, should have hash {}",
-                                file.hash, file.name, roms_by_id[&fm.rom_id].hash
+                                "  {indicator}  {} {name}, should have hash {}",
+                                file.hash, roms_by_id[&fm.rom_id].hash
+                            );
+                        }
+                        db::MatchStatus::Corrupt => {
+                            println!(
+                                "  {indicator}  {} {name}, corrupt, should have hash {}",
+                                file.hash, roms_by_id[&fm.rom_id].hash
+                            );
+                        }
+                        db::MatchStatus::NameOnly => {
+                            println!(
+                                "  {indicator}  {} {name}, different file, should have hash {}",
+                                file.hash, roms_by_id[&fm.rom_id].hash
                             );
                         }
                         db::MatchStatus::Match => {
-                            println!(" {indicator}  {} {}", file.hash, file.name);
+                            println!(" {indicator}  {} {name}", file.hash);
                         }
                     }
                 }
@@ -1271,22 +4920,254 @@ fn list_sets(
                 }
             }
         }
-        println!("{} / {} sets found.", sets_to_files.len(), all_sets.len());
+        if renamable {
+            println!("{} / {} sets can be completed by renaming.", renamable_sets.len(), all_sets.len());
+        } else {
+            println!("{found_count} / {} sets found.", all_sets.len());
+        }
+    }
+    Ok(())
+}
+
+const REPORT_STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+summary{cursor:pointer;padding:2px}\
+table{border-collapse:collapse;margin:4px 0 12px 24px}\
+td{padding:2px 8px;font-family:monospace}\
+.complete{color:#1a7f37}.partial{color:#9a6700}.missing{color:#cf222e}";
+
+fn write_html_report(conn: &Connection, dat_id: &db::DatId, path: &Utf8Path) -> Result<()> {
+    let dat = db::DatRecord::get_by_id(conn, dat_id)?;
+    let files_by_id: BTreeMap<_, _> = db::FileRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|file| (file.id.clone(), file))
+        .collect();
+    let mut matches_by_rom: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for m in db::MatchRecord::get_by_dat(conn, dat_id)? {
+        matches_by_rom.entry(m.rom_id.clone()).or_default().push(m);
+    }
+    let mut roms_by_set: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for rom in db::RomRecord::get_by_dat(conn, dat_id)? {
+        roms_by_set.entry(rom.set_id.clone()).or_default().push(rom);
+    }
+    let mut sets = db::SetRecord::get_by_dat(conn, dat_id)?;
+    sets.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let set_status = |set: &db::SetRecord| {
+        let roms = roms_by_set.get(&set.id).map(Vec::as_slice).unwrap_or_default();
+        match roms.iter().filter(|rom| matches_by_rom.contains_key(&rom.id)).count() {
+            0 => SetStatus::Missing,
+            found if found < roms.len() => SetStatus::Partial,
+            _ => SetStatus::Complete,
+        }
+    };
+    let statuses: Vec<_> = sets.iter().map(set_status).collect();
+    let complete = statuses
+        .iter()
+        .filter(|status| matches!(status, SetStatus::Complete))
+        .count();
+    let partial = statuses
+        .iter()
+        .filter(|status| matches!(status, SetStatus::Partial))
+        .count();
+    let percent = if sets.is_empty() { 0.0 } else { complete as f64 * 100.0 / sets.len() as f64 };
+
+    let mut out = std::io::BufWriter::new(File::create(path)?);
+    let title = escape(format!("{} ({})", dat.name, dat.version));
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(
+        out,
+        "<html><head><meta charset=\"utf-8\"><title>{title}</title><style>{REPORT_STYLE}</style></head><body>"
+    )?;
+    writeln!(out, "<h1>{title}</h1>")?;
+    writeln!(out, "<p><strong>{percent:.1}% complete</strong>, generated {}</p>", util::timestamp())?;
+    writeln!(
+        out,
+        "<p><span class=\"complete\">{complete} complete</span>, <span class=\"partial\">{partial} partial</span>, \
+        <span class=\"missing\">{} missing</span> of {} sets</p>",
+        sets.len() - complete - partial,
+        sets.len()
+    )?;
+    for (set, status) in sets.iter().zip(&statuses) {
+        let (class, indicator) = match status {
+            SetStatus::Complete => ("complete", "✅"),
+            SetStatus::Partial => ("partial", "⚠️"),
+            SetStatus::Missing => ("missing", "❌"),
+        };
+        writeln!(out, "<details><summary class=\"{class}\">{indicator} {}</summary><table>", escape(&set.name))?;
+        for rom in roms_by_set.get(&set.id).into_iter().flatten() {
+            let found = matches_by_rom.get(&rom.id).and_then(|matches| {
+                //show the best file for the rom, if there are several
+                let m = matches
+                    .iter()
+                    .find(|m| m.status == db::MatchStatus::Match)
+                    .unwrap_or(&matches[0]);
+                Some((m, files_by_id.get(&m.file_id)?))
+            });
+            let (class, detail) = match found {
+                Some((m, _)) if m.status == db::MatchStatus::Match => ("complete", "found".to_string()),
+                Some((m, file)) => ("partial", format!("{}: {}", file.name, format_match_message(&m.status, rom))),
+                None => ("missing", "missing".to_string()),
+            };
+            writeln!(
+                out,
+                "<tr class=\"{class}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&rom.name),
+                escape(format_rom_size(rom.size)),
+                escape(&rom.hash),
+                escape(&detail)
+            )?;
+        }
+        writeln!(out, "</table></details>")?;
+    }
+    writeln!(out, "</body></html>")?;
+    out.flush()?;
+    Ok(())
+}
+
+fn list_sets_tree(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    term: &TermInfo,
+    summary_only: bool,
+    partial_name: Option<&str>,
+) -> Result<()> {
+    let mut found_roms: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+    for m in db::MatchRecord::get_by_dat(conn, dat_id)? {
+        found_roms.entry(m.set_id).or_default().insert(m.rom_id);
+    }
+    let mut rom_counts: BTreeMap<_, usize> = BTreeMap::new();
+    for rom in db::RomRecord::get_by_dat(conn, dat_id)? {
+        *rom_counts.entry(rom.set_id).or_default() += 1;
+    }
+
+    //clones whose parent is missing from the dat file have no parent, so are listed at the top level
+    let all_sets = db::SetRecord::get_by_dat(conn, dat_id)?;
+    let mut clones: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    let mut parents = Vec::new();
+    for set in &all_sets {
+        match &set.parent_id {
+            Some(parent_id) => clones.entry(parent_id).or_default().push(set),
+            None => parents.push(set),
+        }
     }
+    parents.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let tree = SetTree {
+        term,
+        clones: &clones,
+        found_roms: &found_roms,
+        rom_counts: &rom_counts,
+    };
+    let partial_name = partial_name.map(|name| name.to_ascii_lowercase());
+    if !summary_only {
+        println!("--- FOUND SETS ---");
+    }
+    for parent in parents {
+        //only show families that have something found, and that match the name if given
+        let family = tree.family(parent);
+        if !family.iter().any(|set| found_roms.contains_key(&set.id)) {
+            continue;
+        }
+        if let Some(partial_name) = &partial_name
+            && !family
+                .iter()
+                .any(|set| set.name.to_ascii_lowercase().contains(partial_name))
+        {
+            continue;
+        }
+        if !summary_only {
+            tree.print(parent, 0);
+        }
+    }
+    let found_count = all_sets.iter().filter(|set| found_roms.contains_key(&set.id)).count();
+    println!("{found_count} / {} sets found.", all_sets.len());
     Ok(())
 }
 
-fn rename_files(conn: &mut Connection, dat_id: &db::DatId, term: &TermInfo) -> Result<()> {
+struct SetTree<'a> {
+    term: &'a TermInfo,
+    clones: &'a BTreeMap<&'a db::SetId, Vec<&'a db::SetRecord>>,
+    found_roms: &'a BTreeMap<db::SetId, BTreeSet<db::RomId>>,
+    rom_counts: &'a BTreeMap<db::SetId, usize>,
+}
+
+impl SetTree<'_> {
+    //the set along with all of its clones, and their clones
+    fn family<'s>(&'s self, set: &'s db::SetRecord) -> Vec<&'s db::SetRecord> {
+        let mut family = vec![set];
+        for clone in self.clones.get(&set.id).into_iter().flatten() {
+            family.extend(self.family(clone));
+        }
+        family
+    }
+
+    fn print(&self, set: &db::SetRecord, depth: usize) {
+        let found = self.found_roms.get(&set.id).map(|roms| roms.len()).unwrap_or_default();
+        let total = self.rom_counts.get(&set.id).copied().unwrap_or_default();
+        let status = match found {
+            0 => SetStatus::Missing,
+            found if found < total => SetStatus::Partial,
+            _ => SetStatus::Complete,
+        };
+        println!(
+            "{}[{}] {} ({found} / {total} roms)",
+            "    ".repeat(depth),
+            format_set_indicator(&status, self.term.tty_out),
+            set.name
+        );
+        let mut clones = self.clones.get(&set.id).cloned().unwrap_or_default();
+        clones.sort_by(|a, b| a.name.cmp(&b.name));
+        for clone in clones {
+            self.print(clone, depth + 1);
+        }
+    }
+}
+
+fn rename_files(
+    conn: &mut Connection,
+    dat_id: &db::DatId,
+    term: &TermInfo,
+    atomic: bool,
+    path: Option<&Utf8Path>,
+) -> Result<()> {
     let mut tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
-    for directory in db::DirRecord::get_by_dat(&tx, dat_id)? {
-        if util::is_zip_file(&directory.path) {
+    let mut renamed = Vec::new();
+    if let Err(e) = rename_matched_files(&mut tx, dat_id, term, atomic, path, &mut renamed) {
+        //the database changes are rolled back, so put the files back to match it, in reverse order
+        //in case a file was renamed to a name that another file had before
+        for (old_path, new_path) in renamed.iter().rev() {
+            if let Err(e) = std::fs::rename(new_path, old_path) {
+                eprintln!("Failed to restore {new_path} to {old_path}. Error was {e}");
+            }
+        }
+        tx.rollback()?;
+        return Err(e.context("No files were renamed"));
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+fn rename_matched_files(
+    tx: &mut Transaction,
+    dat_id: &db::DatId,
+    term: &TermInfo,
+    atomic: bool,
+    path: Option<&Utf8Path>,
+    renamed: &mut Vec<(Utf8PathBuf, Utf8PathBuf)>,
+) -> Result<()> {
+    let name_map = resolve_name_map(tx, dat_id)?;
+    for directory in db::DirRecord::get_by_dat(tx, dat_id)? {
+        if util::is_archive_file(&directory.path)
+            || path.is_some_and(|path| !Utf8Path::new(&directory.path).starts_with(path))
+        {
             continue;
         }
 
-        let files = directory.get_files(&tx)?;
+        let files = directory.get_files(tx)?;
         let mut matches_by_name = BTreeMap::new();
         for file in &files {
-            let file_matches = db::MatchRecord::get_by_file_status(&tx, &file.id, "hash")?;
+            let file_matches = db::MatchRecord::get_by_file_status(tx, &file.id, "hash")?;
             if file_matches.len() != 1 {
                 continue;
             }
@@ -1300,35 +5181,1461 @@ fn rename_files(conn: &mut Connection, dat_id: &db::DatId, term: &TermInfo) -> R
         for (name, records) in matches_by_name {
             if records.len() == 1 {
                 let (file, file_match) = &records[0];
-                let rom = db::RomRecord::get_by_id(&tx, &file_match.rom_id)?;
+                let rom = db::RomRecord::get_by_id(tx, &file_match.rom_id)?;
 
                 let mut sp = tx.savepoint()?;
                 match file_match.update(&sp, &db::MatchStatus::Match) {
                     Ok(new_match) => {
+                        let new_name = name_map.apply(&rom.name);
                         let old_path = path.join(name);
-                        let new_path = path.join(&rom.name);
+                        let new_path = path.join(&new_name);
 
                         match std::fs::rename(&old_path, &new_path) {
                             Ok(_) => {
                                 let indicator = format_file_indicator(Some(&new_match.status), term.tty_out);
-                                println!("[{indicator}] {} {} -> {}", file.hash, file.name, &rom.name);
+                                println!("[{indicator}] {} {} -> {new_name}", file.hash, file.name);
                                 sp.commit()?;
+                                renamed.push((old_path, new_path));
                             }
                             Err(e) => {
-                                eprintln!("Failed to rename {old_path} to {new_path}. Error was {e}");
                                 sp.rollback()?;
+                                ensure!(!atomic, "Failed to rename {old_path} to {new_path}. Error was {e}");
+                                eprintln!("Failed to rename {old_path} to {new_path}. Error was {e}");
                             }
                         }
                     }
                     Err(e) => {
-                        eprintln!("Failed to rename {name} in database. Error was {e}");
                         sp.rollback()?;
+                        ensure!(!atomic, "Failed to rename {name} in database. Error was {e}");
+                        eprintln!("Failed to rename {name} in database. Error was {e}");
                     }
                 }
             }
         }
     }
+    Ok(())
+}
 
-    tx.commit()?;
+struct RebuildOptions {
+    zip: bool,
+    incomplete: bool,
+    yes: bool,
+    merge_mode: MergeMode,
+}
+
+fn rebuild_sets(
+    conn: &Connection,
+    dat_id: &db::DatId,
+    term: &TermInfo,
+    output: &Utf8Path,
+    options: &RebuildOptions,
+) -> Result<()> {
+    std::fs::create_dir_all(output).with_context(|| format!("Unable to create {output}"))?;
+    let name_map = resolve_name_map(conn, dat_id)?;
+    let dirs_by_id: BTreeMap<_, _> = db::DirRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|dir| (dir.id.clone(), dir))
+        .collect();
+    let files_by_id: BTreeMap<_, _> = db::FileRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|file| (file.id.clone(), file))
+        .collect();
+    let sets = db::SetRecord::get_by_dat(conn, dat_id)?;
+    let roms = db::RomRecord::get_by_dat(conn, dat_id)?;
+
+    //a file matched by hash has the right contents, so is copied with the right name; exact matches go first
+    let mut matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
+    matches.retain(|m| matches!(m.status, db::MatchStatus::Match | db::MatchStatus::Hash));
+    matches.sort_by_key(|m| m.status != db::MatchStatus::Match);
+    let mut files_by_rom = BTreeMap::new();
+    for m in &matches {
+        files_by_rom.entry(&m.rom_id).or_insert(&files_by_id[&m.file_id]);
+    }
+    //roms of other sets only match the set their archive is named for, so any file with the same contents will do
+    let mut files_by_hash = BTreeMap::new();
+    if options.merge_mode != MergeMode::Split {
+        for file in files_by_rom.values().copied().chain(files_by_id.values()) {
+            files_by_hash.entry(file.hash.as_str()).or_insert(file);
+        }
+    }
+
+    let (mut rebuilt, mut incomplete, mut existing, mut failed) = (0, 0, 0, 0);
+    for merged in merge_sets(&sets, &roms, options.merge_mode) {
+        let roms = &merged.roms;
+        let found: Vec<_> = roms
+            .iter()
+            .filter_map(|(name, rom)| {
+                let file = files_by_rom
+                    .get(&rom.id)
+                    .or_else(|| files_by_hash.get(rom.hash.as_str()));
+                file.map(|file| (name, *file))
+            })
+            .collect();
+        if found.is_empty() {
+            continue;
+        }
+        if found.len() < roms.len() && !options.incomplete {
+            incomplete += 1;
+            continue;
+        }
+
+        let set_name = name_map.apply(&merged.set.name);
+        let files: Vec<_> = found
+            .into_iter()
+            .map(|(name, file)| (name_map.apply(name), &dirs_by_id[&file.dir_id], file))
+            .collect();
+        if let Some(name) = std::iter::once(&set_name)
+            .chain(files.iter().map(|(name, _, _)| name))
+            .find(|name| !is_contained_name(name))
+        {
+            failed += 1;
+            eprintln!("Failed to rebuild {set_name}. Error: `{name}` is not a name that can be used inside {output}");
+            continue;
+        }
+
+        let dest = if options.zip { output.join(format!("{set_name}.zip")) } else { output.join(&set_name) };
+        //a set that is already where it would be rebuilt would be read from the files being replaced
+        let same_source = files.iter().find_map(|(name, dir, file)| {
+            let source = scanned_file_path(dir, file);
+            let target = if options.zip { dest.clone() } else { dest.join(name) };
+            util::is_same_file(&source, &target).then_some(source)
+        });
+        if let Some(source) = same_source {
+            existing += 1;
+            eprintln!("Skipped {dest}, as {source} is already there.");
+            continue;
+        }
+        if dest.exists()
+            && !ask_for_confirmation(term, &format!("{dest} already exists, replace it? (y/N): "), options.yes)?
+        {
+            existing += 1;
+            continue;
+        }
+
+        let rebuilding = if options.zip { rebuild_zip(&dest, &files) } else { rebuild_dir(&dest, &files) };
+        match rebuilding {
+            Ok(()) => rebuilt += 1,
+            Err(e) => {
+                failed += 1;
+                eprintln!("Failed to rebuild {dest}. Error: {e}");
+            }
+        }
+    }
+
+    println!(
+        "{rebuilt} sets rebuilt in {output}, {incomplete} incomplete sets skipped, {existing} already there and {failed} failed."
+    );
+    Ok(())
+}
+
+//zip files are written as torrentzips so rebuilding the same files always gives the same archive.
+//the zip is written next to where it goes and moved there when done, so a failure doesn't leave half a set behind
+fn rebuild_zip(dest: &Utf8Path, files: &[(String, &db::DirRecord, &db::FileRecord)]) -> Result<()> {
+    let mut files: Vec<_> = files.iter().collect();
+    files.sort_by_key(|(name, _, _)| name.to_ascii_lowercase());
+    let partial = dest.with_extension("zip.part");
+    let writing = (|| {
+        let mut zip = torrentzip::TorrentZipWriter::new(BufWriter::new(File::create(&partial)?));
+        for (name, dir, file) in files {
+            read_scanned_file(dir, file, |reader| zip.add(name, reader))?;
+        }
+        zip.finish()?;
+        Ok(())
+    })();
+    match writing {
+        Ok(()) => Ok(std::fs::rename(&partial, dest)?),
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+//the same as a zip, the set is written next to where it goes and moved there when done, which also means replacing
+//a set doesn't keep files from the old one
+fn rebuild_dir(dest: &Utf8Path, files: &[(String, &db::DirRecord, &db::FileRecord)]) -> Result<()> {
+    let partial = Utf8PathBuf::from(format!("{dest}.part"));
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)?;
+    }
+    let writing = (|| {
+        for (name, dir, file) in files {
+            let path = partial.join(name);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut out = File::create(&path)?;
+            read_scanned_file(dir, file, |reader| Ok(std::io::copy(reader, &mut out)?))?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = writing {
+        let _ = std::fs::remove_dir_all(&partial);
+        return Err(e);
+    }
+    if dest.is_dir() {
+        std::fs::remove_dir_all(dest)?;
+    } else if dest.exists() {
+        std::fs::remove_file(dest)?;
+    }
+    Ok(std::fs::rename(&partial, dest)?)
+}
+
+//names from the dat file become paths inside the output, so they can't be absolute or lead out of it
+fn is_contained_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.split(['/', '\\']).any(|part| part == "..")
+        && Utf8Path::new(name)
+            .components()
+            .all(|component| matches!(component, camino::Utf8Component::Normal(_)))
+}
+
+//where a scanned file is on disk, which is the archive for a file inside one
+fn scanned_file_path(dir: &db::DirRecord, file: &db::FileRecord) -> Utf8PathBuf {
+    if util::is_archive_file(&dir.path) {
+        Utf8PathBuf::from(&dir.path)
+    } else {
+        Utf8Path::new(&dir.path).join(&file.name)
+    }
+}
+
+fn link_matched_files(conn: &Connection, dat_id: &db::DatId, target: &Utf8Path) -> Result<()> {
+    std::fs::create_dir_all(target).with_context(|| format!("Unable to create {target}"))?;
+    let name_map = resolve_name_map(conn, dat_id)?;
+    let dirs_by_id: BTreeMap<_, _> = db::DirRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|dir| (dir.id.clone(), dir))
+        .collect();
+    let files_by_id: BTreeMap<_, _> = db::FileRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|file| (file.id.clone(), file))
+        .collect();
+    let sets_by_id: BTreeMap<_, _> = db::SetRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|set| (set.id.clone(), set))
+        .collect();
+    let roms_by_id: BTreeMap<_, _> = db::RomRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|rom| (rom.id.clone(), rom))
+        .collect();
+
+    //a file matched by hash has the right contents, so is linked with the right name; exact matches go first
+    let mut matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
+    matches.retain(|m| matches!(m.status, db::MatchStatus::Match | db::MatchStatus::Hash));
+    matches.sort_by_key(|m| m.status != db::MatchStatus::Match);
+
+    let mut linked = BTreeSet::new();
+    let mut not_linked = BTreeMap::new();
+    let (mut hard, mut symbolic, mut copied, mut existing) = (0, 0, 0, 0);
+    for m in &matches {
+        if linked.contains(&m.rom_id) {
+            continue;
+        }
+        let file = &files_by_id[&m.file_id];
+        let dir = &dirs_by_id[&file.dir_id];
+        let source = Utf8Path::new(&dir.path).join(&file.name);
+        //files in archives and joined split files don't exist as a single file to link to
+        if util::is_archive_file(&dir.path) || !source.is_file() {
+            not_linked
+                .entry(&m.rom_id)
+                .or_insert(format!("{source} is not a loose file"));
+            continue;
+        }
+        let dest = target
+            .join(name_map.apply(&sets_by_id[&m.set_id].name))
+            .join(name_map.apply(&roms_by_id[&m.rom_id].name));
+        if dest.exists() {
+            existing += 1;
+            linked.insert(&m.rom_id);
+            continue;
+        }
+        let linking = dest
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| util::link_file(&source, &dest));
+        match linking {
+            Ok(kind) => {
+                let fallback = match kind {
+                    util::LinkKind::Hard => {
+                        hard += 1;
+                        None
+                    }
+                    util::LinkKind::Symbolic => {
+                        symbolic += 1;
+                        Some("symlink")
+                    }
+                    util::LinkKind::Copy => {
+                        copied += 1;
+                        Some("copy")
+                    }
+                };
+                if let Some(fallback) = fallback {
+                    eprintln!("{source} could not be hardlinked, so {dest} is a {fallback} instead.");
+                }
+                linked.insert(&m.rom_id);
+            }
+            Err(e) => {
+                not_linked.insert(&m.rom_id, format!("Failed to link {source} to {dest}. Error was {e}"));
+            }
+        }
+    }
+
+    not_linked.retain(|rom_id, _| !linked.contains(rom_id));
+    if !not_linked.is_empty() {
+        eprintln!("Not linked ({}):", not_linked.len());
+        for reason in not_linked.values() {
+            eprintln!("  {reason}");
+        }
+    }
+    println!(
+        "{hard} files hardlinked, {symbolic} symlinked, {copied} copied and {existing} already in {target}, {} not linked.",
+        not_linked.len()
+    );
     Ok(())
 }
+
+fn move_unmatched_files(conn: &mut Connection, dat_id: &db::DatId, target: &Utf8Path) -> Result<()> {
+    std::fs::create_dir_all(target).with_context(|| format!("Unable to create {target}"))?;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+    let mut moved = Vec::new();
+    if let Err(e) = move_files_to(&tx, dat_id, target, &mut moved) {
+        //the database changes are rolled back, so put the files back to match it
+        for (old_path, new_path) in moved.iter().rev() {
+            if let Err(e) = util::move_file(new_path, old_path) {
+                eprintln!("Failed to restore {new_path} to {old_path}. Error was {e}");
+            }
+        }
+        tx.rollback()?;
+        return Err(e.context("No files were moved"));
+    }
+
+    tx.commit()?;
+    println!("{} unmatched files moved to {target}.", moved.len());
+    Ok(())
+}
+
+fn move_files_to(
+    tx: &Transaction,
+    dat_id: &db::DatId,
+    target: &Utf8Path,
+    moved: &mut Vec<(Utf8PathBuf, Utf8PathBuf)>,
+) -> Result<()> {
+    let matched_files: BTreeSet<_> = db::MatchRecord::get_by_dat(tx, dat_id)?
+        .into_iter()
+        .map(|m| m.file_id)
+        .collect();
+    for directory in db::DirRecord::get_by_dat(tx, dat_id)? {
+        if util::is_archive_file(&directory.path) {
+            continue;
+        }
+        for file in directory.get_files(tx)? {
+            let old_path = Utf8Path::new(&directory.path).join(&file.name);
+            //joined split files don't exist as a single file, so leave them where they are
+            if matched_files.contains(&file.id) || !old_path.is_file() {
+                continue;
+            }
+            let new_path = unused_path(target, &file.name);
+            util::move_file(&old_path, &new_path)
+                .with_context(|| format!("Failed to move {old_path} to {new_path}"))?;
+            println!("{old_path} -> {new_path}");
+            moved.push((old_path, new_path));
+            db::FileRecord::delete_by_id(tx, &file.id)?;
+        }
+    }
+    Ok(())
+}
+
+//files from different directories can have the same name, so number them to keep them all
+fn unused_path(dir: &Utf8Path, name: &str) -> Utf8PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let name = Utf8Path::new(name);
+    let stem = name.file_stem().unwrap_or_default();
+    (1..)
+        .map(|n| match name.extension() {
+            Some(ext) => dir.join(format!("{stem} ({n}).{ext}")),
+            None => dir.join(format!("{stem} ({n})")),
+        })
+        .find(|path| !path.exists())
+        .expect("there should be an unused name")
+}
+
+//a file matching several roms has a row for each, and an unknown file has a row with no set or rom
+fn export_files_csv(conn: &Connection, dat_id: &db::DatId, path: &Utf8Path) -> Result<usize> {
+    //get these in bulk to avoid doing a query per file when we write them
+    let matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
+    let matches_by_file: BTreeMap<_, Vec<_>> = matches.iter().fold(BTreeMap::new(), |mut acc, m| {
+        acc.entry(&m.file_id).or_default().push(m);
+        acc
+    });
+    let sets_by_id: BTreeMap<_, _> = db::SetRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|set| (set.id.clone(), set))
+        .collect();
+    let roms_by_id: BTreeMap<_, _> = db::RomRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|rom| (rom.id.clone(), rom))
+        .collect();
+
+    let mut writer = csv::Writer::from_path(path).with_context(|| format!("Unable to create {path}"))?;
+    writer.write_record(["dir", "name", "size", "hash", "status", "set", "rom"])?;
+    let mut rows = 0;
+    for dir in db::DirRecord::get_by_dat(conn, dat_id)? {
+        for file in dir.get_files(conn)? {
+            let size = file.size.to_string();
+            let row = [dir.path.as_str(), file.name.as_str(), size.as_str(), file.hash.as_str()];
+            match matches_by_file.get(&file.id) {
+                Some(file_matches) => {
+                    for m in file_matches {
+                        let set = sets_by_id
+                            .get(&m.set_id)
+                            .map(|set| set.name.as_str())
+                            .unwrap_or_default();
+                        let rom = roms_by_id
+                            .get(&m.rom_id)
+                            .map(|rom| rom.name.as_str())
+                            .unwrap_or_default();
+                        writer.write_record(row.iter().copied().chain([m.status.as_str(), set, rom]))?;
+                        rows += 1;
+                    }
+                }
+                None => {
+                    writer.write_record(row.iter().copied().chain(["unknown", "", ""]))?;
+                    rows += 1;
+                }
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(rows)
+}
+
+fn list_extra_files(conn: &Connection, dat_id: &db::DatId, term: &TermInfo) -> Result<()> {
+    let dirs_by_id: BTreeMap<_, _> = db::DirRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|dir| (dir.id.clone(), dir))
+        .collect();
+    let files = db::FileRecord::get_by_dat(conn, dat_id)?;
+    let file_path = |file: &db::FileRecord| Utf8Path::new(&dirs_by_id[&file.dir_id].path).join(&file.name);
+
+    let matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
+    let matched_files: BTreeSet<_> = matches.iter().map(|m| &m.file_id).collect();
+    let duplicates = find_duplicates(&matches);
+    let files_by_id: BTreeMap<_, _> = files.iter().map(|file| (&file.id, file)).collect();
+
+    let mut table = util::Table::new();
+    let (mut count, mut total) = (0, 0);
+    for file in &files {
+        let reason = match duplicates.get(&file.id) {
+            Some(keeper) => format!("duplicate of {}", file_path(files_by_id[keeper])),
+            None if !matched_files.contains(&file.id) => "unknown file".to_string(),
+            None => continue,
+        };
+        count += 1;
+        total += file.size;
+        table.add_row([file_path(file).to_string(), reason]);
+    }
+
+    table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+    println!("{count} extra files, {} total.", util::human_size(total));
+    Ok(())
+}
+
+//a file can match roms in sets other than the ones being collected, e.g. a rom shared between sets.
+//when that file is the only thing found for a set, the set has been started without being scanned for.
+fn list_orphan_matches(conn: &Connection, dat_id: &db::DatId, term: &TermInfo) -> Result<()> {
+    let dirs_by_id: BTreeMap<_, _> = db::DirRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|dir| (dir.id.clone(), dir))
+        .collect();
+    let files_by_id: BTreeMap<_, _> = db::FileRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|file| (file.id.clone(), file))
+        .collect();
+    let set_names: BTreeMap<_, _> = db::SetRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|set| (set.id, set.name))
+        .collect();
+    let roms_by_id: BTreeMap<_, _> = db::RomRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|rom| (rom.id.clone(), rom))
+        .collect();
+    let mut rom_counts: BTreeMap<_, usize> = BTreeMap::new();
+    for rom in roms_by_id.values() {
+        *rom_counts.entry(&rom.set_id).or_default() += 1;
+    }
+
+    let matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
+    let mut matches_by_set: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for m in &matches {
+        matches_by_set.entry(&m.set_id).or_default().push(m);
+    }
+
+    let mut orphans = Vec::new();
+    for (set_id, set_matches) in matches_by_set {
+        let file_id = &set_matches[0].file_id;
+        if set_matches.iter().any(|m| &m.file_id != file_id) {
+            continue;
+        }
+        let found: BTreeSet<_> = set_matches.iter().map(|m| &m.rom_id).collect();
+        let total = rom_counts.get(set_id).copied().unwrap_or_default();
+        if found.len() >= total {
+            continue;
+        }
+        let file = &files_by_id[file_id];
+        let path = Utf8Path::new(&dirs_by_id[&file.dir_id].path).join(&file.name);
+        let roms: Vec<_> = found.iter().map(|rom_id| roms_by_id[*rom_id].name.as_str()).collect();
+        orphans.push((
+            set_names[set_id].clone(),
+            format!("{}/{total}", found.len()),
+            path.to_string(),
+            roms.join(", "),
+        ));
+    }
+    orphans.sort();
+
+    let mut table = util::Table::new();
+    for (set, found, path, roms) in &orphans {
+        table.add_row([set.as_str(), found.as_str(), path.as_str(), roms.as_str()]);
+    }
+    table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+    println!("{} sets started by a single file.", orphans.len());
+    Ok(())
+}
+
+//each rom only needs one file, prefer an exact match then the first file scanned. A file is a duplicate
+//when every rom it matches is kept by another file, and is mapped to the file that keeps its first rom.
+//only files with the right contents count, so a bad dump is never kept in place of a good copy
+fn find_duplicates(matches: &[db::MatchRecord]) -> BTreeMap<&db::FileId, &db::FileId> {
+    let mut matches_by_file: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    let mut keepers: BTreeMap<_, (bool, &db::FileId)> = BTreeMap::new();
+    for m in matches
+        .iter()
+        .filter(|m| matches!(m.status, db::MatchStatus::Match | db::MatchStatus::Hash))
+    {
+        matches_by_file.entry(&m.file_id).or_default().push(m);
+        let candidate = (m.status != db::MatchStatus::Match, &m.file_id);
+        keepers
+            .entry(&m.rom_id)
+            .and_modify(|keeper| *keeper = (*keeper).min(candidate))
+            .or_insert(candidate);
+    }
+    matches_by_file
+        .into_iter()
+        .filter(|(file_id, file_matches)| !file_matches.iter().any(|m| keepers[&m.rom_id].1 == *file_id))
+        .map(|(file_id, file_matches)| (file_id, keepers[&file_matches[0].rom_id].1))
+        .collect()
+}
+
+fn list_duplicate_files(
+    conn: &mut Connection,
+    dat_id: &db::DatId,
+    term: &TermInfo,
+    delete_extra: bool,
+    yes: bool,
+) -> Result<()> {
+    let dirs_by_id: BTreeMap<_, _> = db::DirRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|dir| (dir.id.clone(), dir))
+        .collect();
+    let files_by_id: BTreeMap<_, _> = db::FileRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .map(|file| (file.id.clone(), file))
+        .collect();
+    let file_path = |file: &db::FileRecord| Utf8Path::new(&dirs_by_id[&file.dir_id].path).join(&file.name);
+    let matches = db::MatchRecord::get_by_dat(conn, dat_id)?;
+    let duplicates = find_duplicates(&matches);
+
+    let mut table = util::Table::new();
+    let mut total = 0;
+    for (file_id, keeper_id) in &duplicates {
+        let file = &files_by_id[*file_id];
+        total += file.size;
+        table.add_row([
+            file_path(file).to_string(),
+            format!("duplicate of {}", file_path(&files_by_id[*keeper_id])),
+        ]);
+    }
+    table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+    println!("{} duplicate files, {} total.", duplicates.len(), util::human_size(total));
+
+    if !delete_extra
+        || duplicates.is_empty()
+        || !ask_for_confirmation(term, "Are you sure you want to delete the duplicate files? (y/N): ", yes)?
+    {
+        return Ok(());
+    }
+
+    //files in archives can't be deleted on their own, and a deleted file can't be restored, so each file is
+    //removed from the database as soon as it is deleted from disk
+    let hash_type = resolve_hash_type(conn, dat_id)?;
+    let header = resolve_header_rule(conn, dat_id)?;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+    let (mut deleted, mut reclaimed) = (0, 0);
+    //the kept copy has to still be there and unchanged since it was scanned, or the duplicate is the only copy left
+    let mut checked_keepers: BTreeMap<&db::FileId, bool> = BTreeMap::new();
+    for (file_id, keeper_id) in &duplicates {
+        let file = &files_by_id[*file_id];
+        let path = file_path(file);
+        if util::is_archive_file(&dirs_by_id[&file.dir_id].path) || !path.is_file() {
+            eprintln!("Skipped {path}, as it is not a loose file.");
+            continue;
+        }
+        let keeper_ok = *checked_keepers.entry(keeper_id).or_insert_with(|| {
+            let keeper = &files_by_id[*keeper_id];
+            let dir = &dirs_by_id[&keeper.dir_id];
+            scanned_file_exists(dir, keeper).unwrap_or(false)
+                && hash_scanned_file(dir, keeper, &[hash_type], header)
+                    .is_ok_and(|hashes| FileHashes::new(&hashes, hash_type).is_same_as(keeper))
+        });
+        if !keeper_ok {
+            eprintln!(
+                "Skipped {path}, as {} is missing or has changed since it was scanned.",
+                file_path(&files_by_id[*keeper_id])
+            );
+            continue;
+        }
+        if let Err(e) = std::fs::remove_file(&path) {
+            eprintln!("Failed to delete {path}. Error was {e}");
+            continue;
+        }
+        db::MatchRecord::delete_by_file(&tx, &file.id)?;
+        db::FileRecord::delete_by_id(&tx, &file.id)?;
+        deleted += 1;
+        reclaimed += file.size;
+    }
+    tx.commit()?;
+    println!("{deleted} duplicate files deleted, {} reclaimed.", util::human_size(reclaimed));
+    Ok(())
+}
+
+fn format_check_indicator(passed: bool, is_tty: bool) -> &'static str {
+    match (passed, is_tty) {
+        (true, true) => "✅",
+        (true, false) => " OK ",
+        (false, true) => "❌",
+        (false, false) => "FAIL",
+    }
+}
+
+// Reads every entry to the end, so the zip reader checks it decompresses and matches its crc
+fn verify_archive(path: &Utf8Path) -> Result<Vec<String>> {
+    let mut zip =
+        zip::ZipArchive::new(File::open(path)?).with_context(|| format!("could not open '{}' as a zip file", path))?;
+    let mut failures = Vec::new();
+    for i in 0..zip.len() {
+        match zip.by_index(i) {
+            Ok(mut inner_file) => {
+                if inner_file.is_file()
+                    && let Err(e) = std::io::copy(&mut inner_file, &mut std::io::sink())
+                {
+                    failures.push(format!("{}: {e}", inner_file.name()));
+                }
+            }
+            Err(e) => failures.push(format!("entry {i}: {e}")),
+        }
+    }
+    Ok(failures)
+}
+
+fn verify_archives(conn: &Connection, dat_id: &db::DatId, term: &TermInfo) -> Result<()> {
+    let archives: Vec<_> = db::DirRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .filter(|dir| util::is_zip_file(&dir.path))
+        .collect();
+
+    let mut passed = 0;
+    let mut table = util::Table::new();
+    for archive in &archives {
+        let failures = verify_archive(Utf8Path::new(&archive.path)).unwrap_or_else(|e| vec![e.to_string()]);
+        if failures.is_empty() {
+            passed += 1;
+        }
+        table.add_row([
+            format!("[{}]", format_check_indicator(failures.is_empty(), term.tty_out)),
+            archive.path.clone(),
+            failures.join("; "),
+        ]);
+    }
+    table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+    println!("{passed} / {} archives passed.", archives.len());
+    Ok(())
+}
+
+//entries compressed to more than this much of their size gain little from being compressed
+const POOR_COMPRESSION_RATIO: f64 = 0.95;
+
+fn list_archive_compression(conn: &Connection, dat_id: &db::DatId, term: &TermInfo, stored: bool) -> Result<()> {
+    let archives: Vec<_> = db::DirRecord::get_by_dat(conn, dat_id)?
+        .into_iter()
+        .filter(|dir| util::is_zip_file(&dir.path))
+        .collect();
+
+    let mut table = util::Table::new();
+    let (mut count, mut flagged) = (0, 0);
+    for archive in &archives {
+        for file in archive.get_files(conn)? {
+            //scanned before compression was recorded, so there is nothing to show until it is scanned again
+            let (Some(compression), Some(compressed_size)) = (&file.compression, file.compressed_size) else {
+                continue;
+            };
+            let ratio = if file.size == 0 { 1.0 } else { compressed_size as f64 / file.size as f64 };
+            let poor = compression == "stored" || ratio > POOR_COMPRESSION_RATIO;
+            count += 1;
+            if poor {
+                flagged += 1;
+            }
+            if stored && !poor {
+                continue;
+            }
+            table.add_row([
+                Utf8Path::new(&archive.path).join(&file.name).to_string(),
+                compression.clone(),
+                format!("{:.1}%", ratio * 100.0),
+            ]);
+        }
+    }
+
+    table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+    println!("{flagged} / {count} entries stored uncompressed or barely compressed.");
+    Ok(())
+}
+
+fn locate_roms(conn: &Connection, dat_id: &db::DatId, term: &TermInfo, rom: &str) -> Result<()> {
+    let mut roms = db::RomRecord::get_by_hash(conn, dat_id, &rom.to_ascii_lowercase())?;
+    if roms.is_empty() {
+        roms = db::RomRecord::find_by_name(conn, dat_id, rom, false)?;
+    }
+    if roms.is_empty() {
+        println!("No roms found.");
+        return Ok(());
+    }
+
+    let mut found = 0;
+    for rom in &roms {
+        let set = db::SetRecord::get_by_id(conn, &rom.set_id)?;
+        println!("{} / {}", set.name, rom.name);
+
+        let mut table = util::Table::indented(4);
+        for m in db::MatchRecord::get_by_rom(conn, &rom.id)? {
+            let file = db::FileRecord::get_by_id(conn, &m.file_id)?;
+            let dir = db::DirRecord::get_by_id(conn, &file.dir_id)?;
+            let message = format_match_message(&m.status, rom);
+            table.add_row([
+                format!("[{}]", format_file_indicator(Some(&m.status), term.tty_out)),
+                Utf8Path::new(&dir.path).join(&file.name).to_string(),
+                message,
+            ]);
+        }
+
+        if table.is_empty() {
+            println!("    [{}] missing", format_file_indicator(None, term.tty_out));
+        } else {
+            found += 1;
+            table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+        }
+    }
+    println!("{found} / {} roms found.", roms.len());
+    Ok(())
+}
+
+fn hash_scanned_file(
+    dir: &db::DirRecord,
+    file: &db::FileRecord,
+    hash_types: &[util::HashType],
+    header: Option<util::HeaderRule>,
+) -> Result<util::Hashes> {
+    read_scanned_file(dir, file, |reader| {
+        util::calc_hashes(&mut util::HeaderSkipReader::new(reader, header), hash_types)
+    })
+}
+
+//reads a scanned file wherever it was found, in an archive, joined from split files or as a loose file
+fn read_scanned_file<T>(
+    dir: &db::DirRecord,
+    file: &db::FileRecord,
+    read: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<T> {
+    if util::is_zip_file(&dir.path) {
+        let mut zip = zip::ZipArchive::new(File::open(&dir.path)?)
+            .with_context(|| format!("could not open '{}' as a zip file", dir.path))?;
+        let mut inner_file = zip.by_name(&file.name)?;
+        read(&mut inner_file)
+    } else if util::is_7z_file(&dir.path) {
+        let mut read = Some(read);
+        let mut result = None;
+        let mut archive = sevenz_rust::SevenZReader::open(&dir.path, sevenz_rust::Password::empty())
+            .with_context(|| format!("could not open '{}' as a 7z file", dir.path))?;
+        archive.for_each_entries(|entry, reader| match read.take() {
+            Some(read) if entry.name() == file.name => {
+                result = Some(read(reader));
+                Ok(false)
+            }
+            other => {
+                read = other;
+                std::io::copy(reader, &mut std::io::sink())?;
+                Ok(true)
+            }
+        })?;
+        result.with_context(|| format!("could not find `{}` in '{}'", file.name, dir.path))?
+    } else if util::is_gzip_file(&dir.path) {
+        let mut decoder = flate2::bufread::GzDecoder::new(BufReader::new(File::open(&dir.path)?));
+        read(&mut decoder)
+    } else {
+        let path = Utf8Path::new(&dir.path).join(&file.name);
+        let split_files = find_split_files(Utf8Path::new(&dir.path), &file.name);
+        if !path.exists() && split_files.len() > 1 {
+            let mut reader: Box<dyn Read> = Box::new(std::io::empty());
+            for path in &split_files {
+                reader = Box::new(reader.chain(BufReader::new(File::open(path)?)));
+            }
+            read(&mut reader)
+        } else {
+            read(&mut BufReader::new(File::open(&path)?))
+        }
+    }
+}
+
+//whether a scanned file can still be found, without reading it
+fn scanned_file_exists(dir: &db::DirRecord, file: &db::FileRecord) -> Result<bool> {
+    let dir_path = Utf8Path::new(&dir.path);
+    if util::is_zip_file(&dir.path) {
+        if !dir_path.exists() {
+            return Ok(false);
+        }
+        let zip = zip::ZipArchive::new(File::open(dir_path)?)
+            .with_context(|| format!("could not open '{}' as a zip file", dir.path))?;
+        Ok(zip.index_for_name(&file.name).is_some())
+    } else if util::is_7z_file(&dir.path) {
+        if !dir_path.exists() {
+            return Ok(false);
+        }
+        let archive = sevenz_rust::SevenZReader::open(dir_path, sevenz_rust::Password::empty())
+            .with_context(|| format!("could not open '{}' as a 7z file", dir.path))?;
+        Ok(archive.archive().files.iter().any(|entry| entry.name() == file.name))
+    } else if util::is_gzip_file(&dir.path) {
+        Ok(dir_path.exists())
+    } else {
+        Ok(dir_path.join(&file.name).exists() || find_split_files(dir_path, &file.name).len() > 1)
+    }
+}
+
+//checks the files scanned in a dir, reading an archive only once however many of them it holds, and returns
+//whether each file is missing or changed
+fn verify_dir_files(
+    dir: &db::DirRecord,
+    files: &[&db::FileRecord],
+    hash_type: util::HashType,
+    header: Option<util::HeaderRule>,
+) -> Result<Vec<Result<Option<&'static str>>>> {
+    //an entry that fails its archive crc check is corrupt, which is as much a change as a different hash
+    let compare = |file: &db::FileRecord, hashed: Result<util::Hashes>| match hashed {
+        Ok(hashes) => Ok((!FileHashes::new(&hashes, hash_type).is_same_as(file)).then_some("changed")),
+        Err(e) if util::is_checksum_error(&e) => Ok(Some("changed")),
+        Err(e) => Err(e),
+    };
+
+    let dir_path = Utf8Path::new(&dir.path);
+    if util::is_set_archive(dir_path) && !dir_path.exists() {
+        return Ok(files.iter().map(|_| Ok(Some("missing"))).collect());
+    }
+    if util::is_zip_file(dir_path) {
+        let mut zip = zip::ZipArchive::new(File::open(dir_path)?)
+            .with_context(|| format!("could not open '{}' as a zip file", dir.path))?;
+        Ok(files
+            .iter()
+            .map(|file| match zip.index_for_name(&file.name) {
+                None => Ok(Some("missing")),
+                Some(index) => {
+                    let hashed = zip.by_index(index).map_err(anyhow::Error::from).and_then(|mut entry| {
+                        util::calc_hashes(&mut util::HeaderSkipReader::new(&mut entry, header), &[hash_type])
+                    });
+                    compare(file, hashed)
+                }
+            })
+            .collect())
+    } else if util::is_7z_file(dir_path) {
+        let mut archive = sevenz_rust::SevenZReader::open(dir_path, sevenz_rust::Password::empty())
+            .with_context(|| format!("could not open '{}' as a 7z file", dir.path))?;
+        let wanted: BTreeSet<_> = files.iter().map(|file| file.name.as_str()).collect();
+        let mut hashed = BTreeMap::new();
+        archive.for_each_entries(|entry, reader| {
+            if wanted.contains(entry.name()) {
+                let result = util::calc_hashes(&mut util::HeaderSkipReader::new(reader, header), &[hash_type]);
+                hashed.insert(entry.name().to_string(), result);
+            } else {
+                std::io::copy(reader, &mut std::io::sink())?;
+            }
+            Ok(true)
+        })?;
+        Ok(files
+            .iter()
+            .map(|file| match hashed.remove(&file.name) {
+                None => Ok(Some("missing")),
+                Some(result) => compare(file, result),
+            })
+            .collect())
+    } else {
+        Ok(files
+            .iter()
+            .map(|file| match scanned_file_exists(dir, file) {
+                Ok(false) => Ok(Some("missing")),
+                Ok(true) => compare(file, hash_scanned_file(dir, file, &[hash_type], header)),
+                Err(e) => Err(e),
+            })
+            .collect())
+    }
+}
+
+fn verify_files(conn: &mut Connection, dat_id: &db::DatId, term: &TermInfo, prune: bool) -> Result<()> {
+    let hash_type = resolve_hash_type(conn, dat_id)?;
+    let header = resolve_header_rule(conn, dat_id)?;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+
+    let dirs: BTreeMap<_, _> = db::DirRecord::get_by_dat(&tx, dat_id)?
+        .into_iter()
+        .map(|dir| (dir.id.clone(), dir))
+        .collect();
+    let files = db::FileRecord::get_by_dat(&tx, dat_id)?;
+
+    let files_by_dir: BTreeMap<_, Vec<_>> = files.iter().fold(BTreeMap::new(), |mut acc, file| {
+        acc.entry(&file.dir_id).or_default().push(file);
+        acc
+    });
+
+    let mut table = util::Table::new();
+    let (mut verified, mut failed) = (0, 0);
+    let mut stale = Vec::new();
+    for (dir_id, dir_files) in files_by_dir {
+        let Some(dir) = dirs.get(dir_id) else {
+            continue;
+        };
+        let problems = match verify_dir_files(dir, &dir_files, hash_type, header) {
+            Ok(problems) => problems,
+            Err(e) => {
+                eprintln!("Failed to verify {}. Error: {e}", dir.path);
+                failed += dir_files.len();
+                continue;
+            }
+        };
+        for (file, problem) in dir_files.into_iter().zip(problems) {
+            let path = Utf8Path::new(&dir.path).join(&file.name);
+            match problem {
+                Ok(None) => verified += 1,
+                Ok(Some(problem)) => {
+                    table.add_row([path.to_string(), problem.to_string()]);
+                    stale.push(file);
+                }
+                Err(e) => {
+                    eprintln!("Failed to verify {path}. Error: {e}");
+                    failed += 1;
+                }
+            }
+        }
+    }
+    table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+
+    if prune {
+        for file in &stale {
+            db::MatchRecord::delete_by_file(&tx, &file.id)?;
+            db::FileRecord::delete_by_id(&tx, &file.id)?;
+        }
+    }
+    tx.commit()?;
+
+    println!(
+        "{verified} / {} files verified, {} missing or changed, {failed} could not be read.",
+        files.len(),
+        stale.len()
+    );
+    if prune && !stale.is_empty() {
+        println!("{} stale files pruned.", stale.len());
+    }
+    Ok(())
+}
+
+fn recheck_files(conn: &mut Connection, dat_id: &db::DatId, term: &TermInfo, warnings: bool) -> Result<()> {
+    let hash_type = resolve_hash_type(conn, dat_id)?;
+    let hash_types = resolve_hash_types(conn, dat_id)?;
+    let header = resolve_header_rule(conn, dat_id)?;
+    let matching = MatchOptions::new(conn, dat_id)?;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+
+    let matches = db::MatchRecord::get_by_dat(&tx, dat_id)?;
+    let matches_by_file: BTreeMap<_, Vec<_>> = matches.iter().fold(BTreeMap::new(), |mut acc, m| {
+        acc.entry(&m.file_id).or_default().push(m);
+        acc
+    });
+
+    let mut checked = 0;
+    let mut resolved = 0;
+    for dir in db::DirRecord::get_by_dat(&tx, dat_id)? {
+        //archives restrict matches to the set they are named for
+        let matched_sets =
+            if util::is_set_archive(&dir.path) { match_sets(&tx, dat_id, &dir.path)? } else { BTreeSet::new() };
+
+        let mut table = util::Table::new();
+        for file in dir.get_files(&tx)? {
+            let was_warning = matches_by_file
+                .get(&file.id)
+                .is_some_and(|file_matches| file_matches.iter().all(|m| m.status != db::MatchStatus::Match));
+            if warnings && !was_warning {
+                continue;
+            }
+
+            let hashed = match hash_scanned_file(&dir, &file, &hash_types, header) {
+                Ok(hashes) => FileHashes::new(&hashes, hash_type),
+                Err(e) => {
+                    eprintln!("Failed to recheck {} in {}. Error: {e}", file.name, dir.path);
+                    continue;
+                }
+            };
+            checked += 1;
+
+            let file = file.update_hash(&tx, hashed.size, &hashed.hash, hashed.crc.as_deref())?;
+            db::MatchRecord::delete_by_file(&tx, &file.id)?;
+            insert_matches(&tx, dat_id, &file, &matched_sets, &matching)?;
+
+            let new_matches = db::MatchRecord::get_by_file(&tx, &file.id)?;
+            if was_warning && new_matches.iter().any(|m| m.status == db::MatchStatus::Match) {
+                resolved += 1;
+            }
+            if new_matches.is_empty() {
+                table.add_row(format_file_status(&file, None, term.tty_out));
+            }
+            for m in &new_matches {
+                let rom = db::RomRecord::get_by_id(&tx, &m.rom_id)?;
+                table.add_row(format_file_status(&file, Some((m, &rom)), term.tty_out));
+            }
+        }
+
+        if !table.is_empty() {
+            println!("--- FILES IN '{}' ---", dir.path);
+            table.write(&mut std::io::stdout().lock(), term.tty_out)?;
+        }
+    }
+
+    tx.commit()?;
+
+    println!("{checked} files rechecked, {resolved} warnings resolved to matches.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_match(id: i64, file_id: i64, status: db::MatchStatus, rom_id: i64) -> db::MatchRecord {
+        db::MatchRecord {
+            id: id.into(),
+            dat_id: 1.into(),
+            file_id: file_id.into(),
+            status,
+            set_id: 1.into(),
+            rom_id: rom_id.into(),
+        }
+    }
+
+    fn import_fixture(name: &str) -> (Connection, db::DatRecord) {
+        let conn = db::open_or_create(":memory:").unwrap();
+        let path = Utf8Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        let (dat, _) = parse_dat_file(&conn, &path, open_dat_file(&path).unwrap(), None).unwrap();
+        (conn, dat)
+    }
+
+    fn scan_fixture(conn: &mut Connection, dat_id: &db::DatId, dir: &str) {
+        let term = TermInfo {
+            tty_in: false,
+            tty_out: false,
+            format: OutputFormat::Text,
+        };
+        let options = ScanOptions {
+            exclude: &[],
+            recursive: true,
+            incremental: false,
+            force_rehash: false,
+            hash_type: resolve_hash_type(conn, dat_id).unwrap(),
+            hash_types: resolve_hash_types(conn, dat_id).unwrap(),
+            header: None,
+            show_completions: false,
+            verbose: false,
+            join_splits: false,
+            json_lines: false,
+            rate_limit: None,
+            verify_crc: false,
+            matching: MatchOptions::new(conn, dat_id).unwrap(),
+            only_new: false,
+            commit_every: None,
+            pool: hashing_pool(Some(1)).unwrap(),
+        };
+        let path = Utf8Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(dir);
+        scan_files(conn, dat_id, &term, &path, &options).unwrap();
+    }
+
+    fn match_statuses(
+        conn: &Connection,
+        dat_id: &db::DatId,
+        name: &str,
+        size: u64,
+        hash: &str,
+    ) -> Vec<db::MatchStatus> {
+        let options = MatchOptions::new(conn, dat_id).unwrap();
+        let matches = match_roms(conn, dat_id, name, size, hash, None, &BTreeSet::new(), &options).unwrap();
+        matches.into_iter().flatten().map(|m| m.status).collect()
+    }
+
+    #[test]
+    fn files_can_be_matched_by_crc_instead_of_the_dat_hash() {
+        let (conn, dat) = import_fixture("crc_and_md5.dat");
+        assert!(!resolve_match_by_crc(&conn, &dat.id, Some(util::HashType::Md5)).unwrap());
+        assert!(resolve_match_by_crc(&conn, &dat.id, Some(util::HashType::Crc)).unwrap());
+        assert!(resolve_match_by_crc(&conn, &dat.id, Some(util::HashType::Sha1)).is_err());
+
+        //the md5 in the dat is wrong, but the crc is right
+        let (hash, crc) = ("b1946ac92492d2347c6235b4d2611184", Some("363a3020"));
+        let options = MatchOptions::new(&conn, &dat.id).unwrap();
+        let matches = match_roms(&conn, &dat.id, "hello.bin", 6, hash, crc, &BTreeSet::new(), &options).unwrap();
+        assert_ne!(matches.into_iter().flatten().map(|m| m.status).collect::<Vec<_>>(), vec![db::MatchStatus::Match]);
+
+        let options = MatchOptions {
+            by_crc: true,
+            ..MatchOptions::new(&conn, &dat.id).unwrap()
+        };
+        let matches = match_roms(&conn, &dat.id, "hello.bin", 6, hash, crc, &BTreeSet::new(), &options).unwrap();
+        assert_eq!(matches.into_iter().flatten().map(|m| m.status).collect::<Vec<_>>(), vec![db::MatchStatus::Match]);
+    }
+
+    #[test]
+    fn name_search_matches_words_and_case() {
+        let search = db::NameSearch {
+            match_all: false,
+            ignore_case: false,
+            whole_word: false,
+        };
+        assert!(search.matches("Pokémon Red", &["kémon"]));
+        assert!(!search.matches("Pokémon Red", &["POKÉMON"]));
+        assert!(search.matches("Pokémon Red", &["Blue", "Red"]));
+        assert!(search.matches("Pokémon Red", &[] as &[&str]));
+
+        let search = db::NameSearch {
+            ignore_case: true,
+            ..search
+        };
+        assert!(search.matches("POKÉMON Red", &["pokémon"]));
+
+        let search = db::NameSearch {
+            match_all: true,
+            ..search
+        };
+        assert!(!search.matches("Pokémon Red", &["Blue", "Red"]));
+        assert!(search.matches("Pokémon Red", &["pokémon", "red"]));
+
+        let search = db::NameSearch {
+            whole_word: true,
+            ..search
+        };
+        assert!(search.matches("Pokémon Red (USA)", &["red", "usa"]));
+        assert!(!search.matches("Pokémonster Red", &["pokémon", "red"]));
+        assert!(!search.matches("Pokémon Reds", &["red"]));
+    }
+
+    #[test]
+    fn names_are_found_ignoring_case_outside_ascii() {
+        let (conn, dat) = import_fixture("unicode.dat");
+        let search = db::NameSearch {
+            match_all: false,
+            ignore_case: true,
+            whole_word: true,
+        };
+        let sets = db::SetRecord::find_by_names(&conn, &dat.id, &["pokémon"], &search).unwrap();
+        assert_eq!(sets.iter().map(|set| set.name.as_str()).collect::<Vec<_>>(), vec!["POKÉMON Red"]);
+    }
+
+    #[test]
+    fn strict_size_is_kept_for_rematches() {
+        let (conn, dat) = import_fixture("crc_only.dat");
+        assert_eq!(match_statuses(&conn, &dat.id, "other.bin", 32, "1a2b3c4d"), vec![db::MatchStatus::Hash]);
+
+        db::DatRecord::update_strict_size(&conn, &dat.id, true).unwrap();
+        assert!(match_statuses(&conn, &dat.id, "other.bin", 32, "1a2b3c4d").is_empty());
+        assert_eq!(match_statuses(&conn, &dat.id, "other.bin", 16, "1a2b3c4d"), vec![db::MatchStatus::Hash]);
+    }
+
+    fn torrentzip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = torrentzip::TorrentZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, data) in files {
+            zip.add(name, &mut &data[..]).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn torrentzips_read_back_as_written() {
+        let files: [(&str, &[u8]); 3] = [("a.bin", b"hello\n"), ("B.txt", &[7; 100_000]), ("ünïcode.bin", b"")];
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(torrentzip(&files))).unwrap();
+        assert!(
+            std::str::from_utf8(archive.comment())
+                .unwrap()
+                .starts_with("TORRENTZIPPED-")
+        );
+        assert_eq!(archive.len(), files.len());
+        for (index, (name, data)) in files.iter().enumerate() {
+            let mut entry = archive.by_index(index).unwrap();
+            assert_eq!(entry.name(), *name);
+            assert_eq!(entry.compression(), zip::CompressionMethod::Deflated);
+            assert_eq!(entry.crc32(), crc32fast::hash(data));
+            let mut read = Vec::new();
+            entry.read_to_end(&mut read).unwrap();
+            assert_eq!(read, *data);
+        }
+    }
+
+    #[test]
+    fn torrentzips_match_the_torrentzip_layout() {
+        //built separately to the TorrentZip layout, with zlib at level 9 for the compressed data
+        let expected =
+            std::fs::read(Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/torrentzip.zip")).unwrap();
+        let words = [
+            "rom", "set", "clone", "parent", "merged", "split", "zip", "crc", "sha1", "dat", "header", "torrent",
+        ];
+        let text: Vec<u8> = (0..5000)
+            .flat_map(|i: usize| {
+                let end = if i % 10 == 9 { '\n' } else { ' ' };
+                format!("{}{}{end}", words[(i * i * 7 + i * 13) % words.len()], i % 97).into_bytes()
+            })
+            .collect();
+        assert_eq!(torrentzip(&[("A.bin", b"hello\n"), ("b.txt", &text), ("ü.bin", b"")]), expected);
+    }
+
+    #[test]
+    fn md5_only_dats_match_by_md5() {
+        let (mut conn, dat) = import_fixture("md5_only.dat");
+        assert_eq!(dat.hash_type, "md5");
+        scan_fixture(&mut conn, &dat.id, "scan");
+
+        let files = db::FileRecord::get_by_dat(&conn, &dat.id).unwrap();
+        let hello = files.iter().find(|file| file.name == "hello.bin").unwrap();
+        assert_eq!(hello.hash, "b1946ac92492d2347c6235b4d2611184");
+        let matches = db::MatchRecord::get_by_file(&conn, &hello.id).unwrap();
+        assert_eq!(matches.iter().map(|m| m.status.clone()).collect::<Vec<_>>(), vec![db::MatchStatus::Match]);
+    }
+
+    #[test]
+    fn games_and_machines_are_both_sets() {
+        let (conn, dat) = import_fixture("mixed.dat");
+        let sets = db::SetRecord::get_by_dat(&conn, &dat.id).unwrap();
+        let names: BTreeSet<_> = sets.iter().map(|set| set.name.as_str()).collect();
+        assert_eq!(names, BTreeSet::from(["Clone", "Empty", "Game", "Machine"]));
+
+        let set = |name: &str| sets.iter().find(|set| set.name == name).unwrap();
+        assert_eq!(set("Clone").parent_id.as_ref(), Some(&set("Machine").id));
+        let roms: BTreeSet<_> = db::RomRecord::get_by_dat(&conn, &dat.id)
+            .unwrap()
+            .into_iter()
+            .map(|rom| rom.name)
+            .collect();
+        assert_eq!(roms, BTreeSet::from(["clone.bin", "game.bin", "machine.bin"].map(String::from)));
+    }
+
+    #[test]
+    fn software_lists_keep_the_area_of_each_rom() {
+        let (conn, dat) = import_fixture("softwarelist.xml");
+        assert_eq!(dat.name, "testsl");
+        assert_eq!(dat.description, "Test Software List");
+
+        let sets = db::SetRecord::get_by_dat(&conn, &dat.id).unwrap();
+        let roms_of = |name: &str| {
+            let set = sets.iter().find(|set| set.name == name).unwrap();
+            set.get_roms(&conn)
+                .unwrap()
+                .into_iter()
+                .map(|rom| (rom.area, rom.name, rom.hash))
+                .collect::<BTreeSet<_>>()
+        };
+        let area = |name: &str| Some(name.to_string());
+        //the continuation and the rom that was never dumped are left out
+        assert_eq!(
+            roms_of("gamea"),
+            BTreeSet::from([
+                (area("cart/chr"), "b.bin".to_string(), "7c211433f02071597741e6ff5a8ea34789abbf43".to_string()),
+                (area("cart/prg"), "a.bin".to_string(), "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d".to_string()),
+            ])
+        );
+        //roms with the same name in different parts are both kept
+        assert_eq!(
+            roms_of("gameb"),
+            BTreeSet::from([
+                (area("flop1/flop"), "disk.bin".to_string(), "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d".to_string()),
+                (area("flop2/flop"), "disk.bin".to_string(), "7c211433f02071597741e6ff5a8ea34789abbf43".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn archive_junk_is_not_scanned() {
+        let (mut conn, dat) = import_fixture("md5_only.dat");
+        scan_fixture(&mut conn, &dat.id, "junk");
+
+        let names: Vec<_> = db::FileRecord::get_by_dat(&conn, &dat.id)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.name)
+            .collect();
+        assert_eq!(names, vec!["hello.bin".to_string()]);
+    }
+
+    #[test]
+    fn zip_names_that_are_not_utf8_can_be_found_again() {
+        let (mut conn, dat) = import_fixture("md5_only.dat");
+        scan_fixture(&mut conn, &dat.id, "sjis");
+
+        //テスト.bin in shift-jis, without the utf-8 flag, so it reads as cp437
+        let files = db::FileRecord::get_by_dat(&conn, &dat.id).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "âeâXâg.bin");
+        assert_eq!(files[0].hash, "b1946ac92492d2347c6235b4d2611184");
+
+        let path = Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sjis/Hello.zip");
+        let zip = zip::ZipArchive::new(File::open(path).unwrap()).unwrap();
+        assert_eq!(zip.index_for_name(&files[0].name), Some(0));
+    }
+
+    #[test]
+    fn whitespace_around_rom_names_is_trimmed() {
+        let (conn, dat) = import_fixture("trailing_space.dat");
+        let roms = db::RomRecord::get_by_dat(&conn, &dat.id).unwrap();
+        assert_eq!(roms.iter().map(|rom| rom.name.as_str()).collect::<Vec<_>>(), vec!["hello.bin"]);
+
+        let hash = "b1946ac92492d2347c6235b4d2611184";
+        assert_eq!(match_statuses(&conn, &dat.id, "hello.bin", 6, hash), vec![db::MatchStatus::Match]);
+        assert_eq!(match_statuses(&conn, &dat.id, " hello.bin ", 6, hash), vec![db::MatchStatus::Match]);
+    }
+
+    #[test]
+    fn bad_dumps_are_told_apart_from_other_files() {
+        let (conn, dat) = import_fixture("md5_only.dat");
+        //the same name and size with a hash that no rom has is a bad dump
+        assert_eq!(match_statuses(&conn, &dat.id, "hello.bin", 6, "ffff"), vec![db::MatchStatus::Corrupt]);
+        assert_eq!(match_statuses(&conn, &dat.id, "hello.bin", 7, "ffff"), vec![db::MatchStatus::NameOnly]);
+        for is_tty in [true, false] {
+            assert_ne!(
+                format_file_indicator(Some(&db::MatchStatus::Corrupt), is_tty),
+                format_file_indicator(Some(&db::MatchStatus::Name), is_tty)
+            );
+        }
+    }
+
+    #[test]
+    fn diffs_keep_roms_with_the_same_name_apart() {
+        let (conn, dat) = import_fixture("softwarelist.xml");
+        let stored = stored_contents(&conn, &dat.id).unwrap();
+        let path = Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/softwarelist.xml");
+        let (read, version) = read_contents(&path, util::HashType::Sha1).unwrap();
+        assert_eq!(version, "");
+        assert_eq!(stored, read);
+        assert_eq!(stored["gameb"].values().map(Vec::len).sum::<usize>(), 2);
+        assert!(diff_contents(&stored, &read).rows.is_empty());
+
+        let mut changed = read.clone();
+        let key = (Some("flop2/flop".to_string()), "disk.bin".to_string());
+        changed.get_mut("gameb").unwrap().get_mut(&key).unwrap()[0].1 = "ffff".to_string();
+        let diff = diff_contents(&stored, &changed);
+        assert_eq!((diff.added, diff.removed, diff.changed), (0, 0, 1));
+        assert_eq!(diff.rows.len(), 1);
+        assert_eq!(diff.rows[0][..3], ["rom changed", "gameb", "disk.bin (flop2/flop)"]);
+    }
+
+    #[test]
+    fn sizeless_roms_are_matched_by_hash() {
+        let (conn, dat) = import_fixture("sizeless.dat");
+        let sizes: BTreeMap<_, _> = db::RomRecord::get_by_dat(&conn, &dat.id)
+            .unwrap()
+            .into_iter()
+            .map(|rom| (rom.name, rom.size))
+            .collect();
+        assert_eq!(sizes["game.bin"], None);
+        assert_eq!(sizes["empty.bin"], Some(0));
+
+        let hash = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(match_statuses(&conn, &dat.id, "game.bin", 1024, hash), vec![db::MatchStatus::Match]);
+        assert_eq!(match_statuses(&conn, &dat.id, "other.bin", 1024, hash), vec![db::MatchStatus::Hash]);
+        //an empty rom has a size, so a file that isn't empty is only named the same
+        assert_eq!(match_statuses(&conn, &dat.id, "empty.bin", 4, "ffff"), vec![db::MatchStatus::NameOnly]);
+        //without a size, a different hash can't be told to be a bad dump
+        assert_eq!(match_statuses(&conn, &dat.id, "game.bin", 1024, "ffff"), vec![db::MatchStatus::Name]);
+    }
+
+    #[test]
+    fn bad_dumps_are_never_kept_over_good_copies() {
+        //the bad dump was scanned first, so has the lower id
+        let matches = vec![
+            test_match(1, 1, db::MatchStatus::Corrupt, 1),
+            test_match(2, 2, db::MatchStatus::Hash, 1),
+        ];
+        assert!(find_duplicates(&matches).is_empty());
+
+        let matches = vec![
+            test_match(1, 1, db::MatchStatus::Corrupt, 1),
+            test_match(2, 2, db::MatchStatus::Hash, 1),
+            test_match(3, 3, db::MatchStatus::Match, 1),
+        ];
+        let duplicates = find_duplicates(&matches);
+        let file_2 = db::FileId::from(2);
+        let file_3 = db::FileId::from(3);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates.get(&file_2), Some(&&file_3));
+    }
+
+    #[test]
+    fn merged_sets_keep_clone_roms_that_differ_from_the_parent() {
+        let set = |id: i64, name: &str, parent_id: Option<i64>| db::SetRecord {
+            id: id.into(),
+            dat_id: 1.into(),
+            name: name.to_string(),
+            parent_id: parent_id.map(db::SetId::from),
+        };
+        let rom = |id: i64, set_id: i64, name: &str, hash: &str| db::RomRecord {
+            id: id.into(),
+            dat_id: 1.into(),
+            set_id: set_id.into(),
+            name: name.to_string(),
+            size: Some(4),
+            hash: hash.to_string(),
+            crc: None,
+            area: None,
+        };
+        let sets = vec![set(1, "Parent", None), set(2, "Clone", Some(1))];
+        let roms = vec![
+            rom(1, 1, "a.bin", "aaaa"),
+            rom(2, 1, "b.bin", "bbbb"),
+            rom(3, 2, "a.bin", "cccc"),
+            rom(4, 2, "b.bin", "bbbb"),
+        ];
+        let names = |merged: &MergedSet| -> Vec<String> { merged.roms.iter().map(|(name, _)| name.clone()).collect() };
+
+        let merged = merge_sets(&sets, &roms, MergeMode::Merged);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(names(&merged[0]), vec!["a.bin", "b.bin", "Clone/a.bin"]);
+
+        let non_merged = merge_sets(&sets, &roms, MergeMode::NonMerged);
+        assert_eq!(names(&non_merged[1]), vec!["a.bin", "b.bin"]);
+        assert_eq!(non_merged[1].roms[0].1.hash, "cccc");
+    }
+
+    #[test]
+    fn rebuilt_names_stay_inside_the_output() {
+        assert!(is_contained_name("Game (USA)"));
+        assert!(is_contained_name("sub/rom.bin"));
+        assert!(!is_contained_name(""));
+        assert!(!is_contained_name("../rom.bin"));
+        assert!(!is_contained_name("sub/../../rom.bin"));
+        assert!(!is_contained_name("..\\rom.bin"));
+        assert!(!is_contained_name("/etc/passwd"));
+    }
+}
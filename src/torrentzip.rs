@@ -0,0 +1,173 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{Context, Result, ensure};
+use flate2::Compression;
+use flate2::write::DeflateEncoder;
+
+// constants for TorrentZip archives, every entry has the same fixed values so the output only depends on the files
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIR_SIGNATURE: u32 = 0x06054b50;
+const VERSION_NEEDED: u16 = 20;
+//bit 1 marks the entry as compressed with maximum compression
+const FLAGS: u16 = 2;
+//bit 11 marks the name as utf-8, which is only set when it isn't ascii so those names are read the same everywhere
+const FLAG_UTF8: u16 = 1 << 11;
+const METHOD_DEFLATE: u16 = 8;
+//1996-12-24 23:32:00 as a dos time and date
+const DOS_TIME: u16 = 0xBC00;
+const DOS_DATE: u16 = 0x2198;
+const COMMENT_PREFIX: &str = "TORRENTZIPPED-";
+
+struct Entry {
+    name: String,
+    name_len: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+}
+
+// Writes a zip file the way TorrentZip does, so the same files always give the same archive.
+// Entries have to be added sorted by their lowercased name, as they are written as they are added.
+// Archives needing zip64 are not supported.
+// The compressed data comes from flate2 using zlib at the best compression level, the same as TorrentZip
+// itself uses, as other deflate implementations don't give byte for byte the same output.
+pub struct TorrentZipWriter<W: Write + Seek> {
+    writer: W,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write + Seek> TorrentZipWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add<R: Read + ?Sized>(&mut self, name: &str, reader: &mut R) -> Result<()> {
+        if let Some(last) = self.entries.last() {
+            ensure!(
+                last.name.to_ascii_lowercase() <= name.to_ascii_lowercase(),
+                "`{name}` added after `{}`, entries must be sorted by their lowercased name",
+                last.name
+            );
+        }
+        let offset = self.writer.stream_position()?;
+
+        //sizes and crc are not known until the data is written, so the header is filled in afterwards
+        let mut entry = Entry {
+            name: name.to_string(),
+            name_len: u16::try_from(name.len())
+                .with_context(|| format!("a name of {} bytes is too long for a zip file", name.len()))?,
+            crc: 0,
+            compressed_size: 0,
+            size: 0,
+            offset: to_u32(offset)?,
+        };
+        self.write_local_header(&entry)?;
+        let data_start = self.writer.stream_position()?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut encoder = DeflateEncoder::new(&mut self.writer, Compression::best());
+        let mut buffer = [0; 64 * 1024];
+        let mut size: u64 = 0;
+        loop {
+            let count = reader.read(&mut buffer)?;
+            if count == 0 {
+                break;
+            }
+            hasher.update(&buffer[..count]);
+            encoder.write_all(&buffer[..count])?;
+            size += count as u64;
+        }
+        encoder.finish()?;
+        let data_end = self.writer.stream_position()?;
+
+        entry.crc = hasher.finalize();
+        entry.size = to_u32(size)?;
+        entry.compressed_size = to_u32(data_end - data_start)?;
+        self.writer.seek(SeekFrom::Start(offset))?;
+        self.write_local_header(&entry)?;
+        self.writer.seek(SeekFrom::Start(data_end))?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        let central_dir_offset = self.writer.stream_position()?;
+        let mut central_dir = Vec::new();
+        for entry in &self.entries {
+            write_u32(&mut central_dir, CENTRAL_HEADER_SIGNATURE);
+            //version made by is left as 0 (ms-dos)
+            write_u16(&mut central_dir, 0);
+            write_entry_fields(&mut central_dir, entry);
+            //comment length, disk number, internal and external attributes
+            write_u16(&mut central_dir, 0);
+            write_u16(&mut central_dir, 0);
+            write_u16(&mut central_dir, 0);
+            write_u32(&mut central_dir, 0);
+            write_u32(&mut central_dir, entry.offset);
+            central_dir.extend_from_slice(entry.name.as_bytes());
+        }
+        self.writer.write_all(&central_dir)?;
+
+        let count = u16::try_from(self.entries.len())
+            .ok()
+            .filter(|count| *count != u16::MAX)
+            .context("too many files for a zip file without zip64")?;
+        let comment = format!("{COMMENT_PREFIX}{:08X}", crc32fast::hash(&central_dir));
+        let mut end = Vec::new();
+        write_u32(&mut end, END_OF_CENTRAL_DIR_SIGNATURE);
+        write_u16(&mut end, 0);
+        write_u16(&mut end, 0);
+        write_u16(&mut end, count);
+        write_u16(&mut end, count);
+        write_u32(&mut end, to_u32(central_dir.len() as u64)?);
+        write_u32(&mut end, to_u32(central_dir_offset)?);
+        write_u16(&mut end, comment.len() as u16);
+        end.extend_from_slice(comment.as_bytes());
+        self.writer.write_all(&end)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_local_header(&mut self, entry: &Entry) -> Result<()> {
+        let mut header = Vec::new();
+        write_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+        write_entry_fields(&mut header, entry);
+        header.extend_from_slice(entry.name.as_bytes());
+        self.writer.write_all(&header)?;
+        Ok(())
+    }
+}
+
+//the fields shared by the local and central headers, from the version needed to the extra field length
+fn write_entry_fields(buffer: &mut Vec<u8>, entry: &Entry) {
+    write_u16(buffer, VERSION_NEEDED);
+    write_u16(buffer, if entry.name.is_ascii() { FLAGS } else { FLAGS | FLAG_UTF8 });
+    write_u16(buffer, METHOD_DEFLATE);
+    write_u16(buffer, DOS_TIME);
+    write_u16(buffer, DOS_DATE);
+    write_u32(buffer, entry.crc);
+    write_u32(buffer, entry.compressed_size);
+    write_u32(buffer, entry.size);
+    write_u16(buffer, entry.name_len);
+    write_u16(buffer, 0);
+}
+
+fn write_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn to_u32(value: u64) -> Result<u32> {
+    u32::try_from(value)
+        .ok()
+        .filter(|value| *value != u32::MAX)
+        .context("too large for a zip file without zip64")
+}
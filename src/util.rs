@@ -1,5 +1,8 @@
-use anyhow::Result;
-use sha1::{Digest, Sha1};
+use std::io::{Read, Write};
+
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use digest::Digest;
 
 use camino::{Utf8Path, Utf8PathBuf};
 
@@ -52,18 +55,205 @@ pub fn is_hidden_file<P: AsRef<Utf8Path>>(file: P) -> bool {
         .is_some_and(|filename| filename.starts_with('.'))
 }
 
+// Metadata that operating systems add to archives, which is never part of a set
+pub fn is_archive_junk(name: &str) -> bool {
+    let path = Utf8Path::new(name);
+    path.components().any(|component| component.as_str() == "__MACOSX")
+        || path
+            .file_name()
+            .is_some_and(|filename| filename == ".DS_Store" || filename == "Thumbs.db" || filename.starts_with("._"))
+}
+
 pub fn is_zip_file<P: AsRef<Utf8Path>>(file: P) -> bool {
     file.as_ref()
         .extension()
         .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
 }
 
+// The modification time of a file as seconds and nanoseconds since the epoch, so it
+// can be compared exactly with what was stored for it
+pub fn modified_at<P: AsRef<Utf8Path>>(path: P) -> Option<String> {
+    let modified = path.as_ref().metadata().and_then(|metadata| metadata.modified()).ok()?;
+    let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some(format!("{}.{:09}", since_epoch.as_secs(), since_epoch.subsec_nanos()))
+}
+
+// The modification time of a zip entry in the same form, which has no time zone so is taken as utc
+pub fn modified_at_from_dos(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Option<String> {
+    let month = time::Month::try_from(month).ok()?;
+    let date = time::Date::from_calendar_date(year.into(), month, day).ok()?;
+    let time = time::Time::from_hms(hour, minute, second).ok()?;
+    let secs = u64::try_from(time::PrimitiveDateTime::new(date, time).assume_utc().unix_timestamp()).ok()?;
+    Some(format!("{secs}.000000000"))
+}
+
+// The modification time of a 7z entry in the same form, from 100ns intervals since 1601
+pub fn modified_at_from_filetime(intervals: u64) -> Option<String> {
+    const INTERVALS_PER_SEC: u64 = 10_000_000;
+    const SECS_TO_UNIX_EPOCH: u64 = 11_644_473_600;
+    let secs = (intervals / INTERVALS_PER_SEC).checked_sub(SECS_TO_UNIX_EPOCH)?;
+    Some(format!("{}.{:09}", secs, (intervals % INTERVALS_PER_SEC) * 100))
+}
+
+// A file is unchanged since it was scanned if its size and modification time are the same, so its hash can be kept
+pub fn is_unchanged<P: AsRef<Utf8Path>>(
+    path: P,
+    size: u64,
+    modified_at: Option<&str>,
+    header: Option<HeaderRule>,
+) -> bool {
+    //the size stored for a file with a header skipped is the size without it
+    let same_size = |len: u64| len == size || header.is_some_and(|header| len == size + header.size as u64);
+    modified_at.is_some()
+        && modified_at == self::modified_at(&path).as_deref()
+        && path.as_ref().metadata().is_ok_and(|metadata| same_size(metadata.len()))
+}
+
+// Moves a file, copying it if it is going to a different file system
+pub fn move_file<P: AsRef<Utf8Path>, Q: AsRef<Utf8Path>>(from: P, to: Q) -> std::io::Result<()> {
+    if std::fs::rename(from.as_ref(), to.as_ref()).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from.as_ref(), to.as_ref())?;
+    std::fs::remove_file(from.as_ref())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Hard,
+    Symbolic,
+    Copy,
+}
+
+// Links a file without duplicating its data if possible, falling back to a symlink then a copy across filesystems
+pub fn link_file<P: AsRef<Utf8Path>, Q: AsRef<Utf8Path>>(from: P, to: Q) -> std::io::Result<LinkKind> {
+    if std::fs::hard_link(from.as_ref(), to.as_ref()).is_ok() {
+        return Ok(LinkKind::Hard);
+    }
+    if symlink_file(from.as_ref(), to.as_ref()).is_ok() {
+        return Ok(LinkKind::Symbolic);
+    }
+    std::fs::copy(from.as_ref(), to.as_ref()).map(|_| LinkKind::Copy)
+}
+
+#[cfg(unix)]
+fn symlink_file(from: &Utf8Path, to: &Utf8Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(from, to)
+}
+
+#[cfg(windows)]
+fn symlink_file(from: &Utf8Path, to: &Utf8Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(from, to)
+}
+
+// A single compressed file, as opposed to a tarball which is an archive of many
+pub fn is_gzip_file<P: AsRef<Utf8Path>>(file: P) -> bool {
+    let file = file.as_ref();
+    file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+        && !file
+            .file_stem()
+            .and_then(|stem| Utf8Path::new(stem).extension())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("tar"))
+}
+
+pub fn is_7z_file<P: AsRef<Utf8Path>>(file: P) -> bool {
+    file.as_ref()
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("7z"))
+}
+
+// Archives of a whole set, which are named for the set they hold
+pub fn is_set_archive<P: AsRef<Utf8Path>>(file: P) -> bool {
+    is_zip_file(&file) || is_7z_file(&file)
+}
+
+// Files scanned as a directory of the files inside them
+pub fn is_archive_file<P: AsRef<Utf8Path>>(file: P) -> bool {
+    is_set_archive(&file) || is_gzip_file(&file)
+}
+
 pub fn has_extension<P: AsRef<Utf8Path>, S: AsRef<str>>(file: P, extensions: &[S]) -> bool {
     file.as_ref()
         .extension()
         .is_some_and(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e.as_ref())))
 }
 
+// Splits a name like `game.iso.001` into the joined name and its part number
+pub fn split_file_part(name: &str) -> Option<(&str, u32)> {
+    let (base, ext) = name.rsplit_once('.')?;
+    if base.is_empty() || ext.len() != 3 || !ext.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((base, ext.parse().ok()?))
+}
+
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+// File that is removed from disk when dropped, used to hold downloads
+#[derive(Debug)]
+pub struct TempFile(Utf8PathBuf);
+
+impl TempFile {
+    pub fn path(&self) -> &Utf8Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+pub fn download(url: &str) -> Result<TempFile> {
+    let (temp_file, mut file) = create_temp_file("rrm-download")?;
+    std::io::copy(&mut open_url(url)?, &mut file)?;
+    Ok(temp_file)
+}
+
+// Creates a file with a name that can't be guessed, that didn't already exist, so nothing else can have put a
+// file or link there first
+fn create_temp_file(prefix: &str) -> Result<(TempFile, std::fs::File)> {
+    use std::hash::{BuildHasher, Hasher};
+
+    let temp_dir = Utf8PathBuf::try_from(std::env::temp_dir())?;
+    let mut attempt = 0;
+    loop {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_nanos(),
+        );
+        let path = temp_dir.join(format!("{prefix}-{}-{:016x}", std::process::id(), hasher.finish()));
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((TempFile(path), file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt < 8 => attempt += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Streams what is at the url, so a caller that only needs the start of it doesn't download all of it
+pub fn open_url(url: &str) -> Result<impl Read> {
+    //a server that stops responding fails the request rather than leaving it waiting forever
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(std::time::Duration::from_secs(30))
+        .timeout_read(std::time::Duration::from_secs(60))
+        .build();
+    Ok(agent.get(url).call()?.into_reader())
+}
+
+pub fn timestamp() -> String {
+    let now = time::OffsetDateTime::now_utc();
+    now.replace_nanosecond(0)
+        .unwrap_or(now)
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default()
+}
+
 pub fn human_size(size: u64) -> String {
     let mut h_size = size;
     for unit in ["", "K", "M", "G"] {
@@ -75,10 +265,513 @@ pub fn human_size(size: u64) -> String {
     format!("{h_size} TB")
 }
 
-pub fn calc_hash<R: std::io::Read + ?Sized>(reader: &mut R) -> Result<(String, u64)> {
-    let mut hasher = Sha1::new();
-    let size = std::io::copy(reader, &mut hasher)?;
-    let digest = hasher.finalize();
-    let hash = base16ct::lower::encode_string(&digest);
-    Ok((hash, size))
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum HashType {
+    /// CRC32, as stored in zip files
+    Crc,
+    /// MD5
+    Md5,
+    /// SHA-1
+    Sha1,
+    /// SHA-256
+    Sha256,
+}
+
+impl HashType {
+    //strongest first, used to pick the best hash a dat file provides
+    pub const ALL: [HashType; 4] = [HashType::Sha256, HashType::Sha1, HashType::Md5, HashType::Crc];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashType::Crc => "crc",
+            HashType::Md5 => "md5",
+            HashType::Sha1 => "sha1",
+            HashType::Sha256 => "sha256",
+        }
+    }
+}
+
+impl std::fmt::Display for HashType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for HashType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "crc" => Ok(HashType::Crc),
+            "md5" => Ok(HashType::Md5),
+            "sha1" => Ok(HashType::Sha1),
+            "sha256" => Ok(HashType::Sha256),
+            _ => bail!("unknown hash type `{s}`"),
+        }
+    }
+}
+
+// The digests of everything read from a reader, and how much was read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hashes {
+    pub size: u64,
+    digests: Vec<(HashType, String)>,
+}
+
+impl Hashes {
+    pub fn get(&self, hash_type: HashType) -> Option<&str> {
+        self.digests
+            .iter()
+            .find(|(t, _)| *t == hash_type)
+            .map(|(_, digest)| digest.as_str())
+    }
+}
+
+// Calculates each of the hashes in a single pass, so large or compressed files only need reading once
+pub fn calc_hashes<R: Read + ?Sized>(reader: &mut R, hash_types: &[HashType]) -> Result<Hashes> {
+    let mut hashers: Vec<_> = hash_types.iter().map(|hash_type| Hasher::new(*hash_type)).collect();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hashers.iter_mut().for_each(|hasher| hasher.update(&buffer[..read]));
+        size += read as u64;
+    }
+    let digests = hash_types
+        .iter()
+        .copied()
+        .zip(hashers.into_iter().map(Hasher::finalize))
+        .collect();
+    Ok(Hashes { size, digests })
+}
+
+enum Hasher {
+    Crc(crc32fast::Hasher),
+    Md5(md5::Md5),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    fn new(hash_type: HashType) -> Self {
+        match hash_type {
+            HashType::Crc => Hasher::Crc(crc32fast::Hasher::new()),
+            HashType::Md5 => Hasher::Md5(md5::Md5::new()),
+            HashType::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashType::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => Digest::update(hasher, data),
+            Hasher::Sha1(hasher) => Digest::update(hasher, data),
+            Hasher::Sha256(hasher) => Digest::update(hasher, data),
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Crc(hasher) => format!("{:08x}", hasher.finalize()),
+            Hasher::Md5(hasher) => base16ct::lower::encode_string(&hasher.finalize()),
+            Hasher::Sha1(hasher) => base16ct::lower::encode_string(&hasher.finalize()),
+            Hasher::Sha256(hasher) => base16ct::lower::encode_string(&hasher.finalize()),
+        }
+    }
+}
+
+// Limits how fast files are read, shared by everything hashing at the same time so the limit is for all of it
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    //when what has been read so far is allowed to have been read by, so there is no burst after a pause
+    next_free: std::sync::Mutex<std::time::Instant>,
+}
+
+impl RateLimiter {
+    //no limit is applied if max_rate (in MB/s) is not given
+    pub fn new(max_rate: Option<u64>) -> Option<Self> {
+        max_rate.map(|rate| Self {
+            bytes_per_sec: rate.max(1) * 1024 * 1024,
+            next_free: std::sync::Mutex::new(std::time::Instant::now()),
+        })
+    }
+
+    fn throttle(&self, read: usize) {
+        let duration = std::time::Duration::from_secs_f64(read as f64 / self.bytes_per_sec as f64);
+        let done_at = {
+            let mut next_free = self.next_free.lock().unwrap_or_else(|e| e.into_inner());
+            *next_free = (*next_free).max(std::time::Instant::now()) + duration;
+            *next_free
+        };
+        std::thread::sleep(done_at.saturating_duration_since(std::time::Instant::now()));
+    }
+}
+
+// Reader that limits how fast the inner reader is read from, so that hashing
+// files on a network mount doesn't starve everything else using it
+pub struct ThrottledReader<'a, R> {
+    inner: R,
+    limiter: Option<&'a RateLimiter>,
+}
+
+impl<'a, R: Read> ThrottledReader<'a, R> {
+    pub fn new(inner: R, limiter: Option<&'a RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(limiter) = self.limiter else {
+            return self.inner.read(buf);
+        };
+        //read in bounded chunks so the sleeps stay short and regular
+        let chunk = buf.len().min(64 * 1024);
+        let read = self.inner.read(&mut buf[..chunk])?;
+        limiter.throttle(read);
+        Ok(read)
+    }
+}
+
+// Reader that calculates the CRC32 of everything read through it, so it can be
+// checked at the same time as another hash is calculated
+pub struct CrcReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> CrcReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    pub fn crc(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// Reader that hashes everything read through it, so data can be hashed while it is read for something else
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, hash_type: HashType) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(hash_type),
+        }
+    }
+
+    //reads whatever is left, so the hash is of all of it
+    pub fn finish(mut self) -> Result<String> {
+        std::io::copy(&mut self, &mut std::io::sink())?;
+        Ok(self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// The zip crate checks the crc of an entry once it has all been read, and fails the last read when it doesn't match
+pub fn is_crc_mismatch(error: &std::io::Error) -> bool {
+    error.kind() == std::io::ErrorKind::InvalidData && error.to_string() == "Invalid checksum"
+}
+
+// Whether reading a zip or 7z entry failed because its data no longer matches the crc stored in the archive
+pub fn is_checksum_error(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<std::io::Error>().is_some_and(|error| {
+        is_crc_mismatch(error)
+            || error
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<sevenz_rust::Error>())
+                .is_some_and(|inner| matches!(inner, sevenz_rust::Error::ChecksumVerificationFailed))
+    })
+}
+
+// Reader for a zip entry that treats the crc check failing as the end of the entry, as all of it has been
+// read by then, so a damaged entry can still be hashed and its crc compared by the caller
+pub struct CrcCheckSkipReader<R> {
+    inner: R,
+}
+
+impl<R: Read> CrcCheckSkipReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Read> Read for CrcCheckSkipReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.inner.read(buf) {
+            Err(e) if is_crc_mismatch(&e) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+// Replacements for characters in rom names that can't be used in file names, applied in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameMap(Vec<(String, String)>);
+
+impl Default for NameMap {
+    //names are only mapped by default where they have to be, elsewhere they are kept as they are
+    fn default() -> Self {
+        if cfg!(windows) { Self::windows() } else { NameMap(Vec::new()) }
+    }
+}
+
+impl NameMap {
+    pub fn new(pairs: Vec<(String, String)>) -> Self {
+        NameMap(pairs)
+    }
+
+    // Replaces the characters that windows doesn't allow in file names
+    pub fn windows() -> Self {
+        let mut pairs = vec![(": ".to_string(), " - ".to_string())];
+        pairs.extend([":", "<", ">", "\"", "|", "?", "*"].map(|from| (from.to_string(), "_".to_string())));
+        NameMap(pairs)
+    }
+
+    // Parses a replacement written as `from=to`
+    pub fn parse_pair(mapping: &str) -> Result<(String, String)> {
+        match mapping.split_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok((from.to_string(), to.to_string())),
+            _ => bail!("`{mapping}` is not a valid mapping, expected from=to"),
+        }
+    }
+
+    pub fn pairs(&self) -> &[(String, String)] {
+        &self.0
+    }
+
+    pub fn apply(&self, name: &str) -> String {
+        self.0
+            .iter()
+            .fold(name.to_string(), |name, (from, to)| name.replace(from.as_str(), to))
+    }
+}
+
+// A header that some dumps have in front of the rom data, which isn't included in the dat file's hashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderRule {
+    size: usize,
+    magic_offset: usize,
+    magic: &'static [u8],
+}
+
+// The clrmamepro header detectors that No-Intro dat files refer to, with a short name and the rule each one describes
+const HEADER_DETECTORS: [(&str, &str, HeaderRule); 4] = [
+    (
+        "No-Intro_NES.xml",
+        "ines",
+        HeaderRule {
+            size: 16,
+            magic_offset: 0,
+            magic: b"NES\x1a",
+        },
+    ),
+    (
+        "No-Intro_FDS.xml",
+        "fds",
+        HeaderRule {
+            size: 16,
+            magic_offset: 0,
+            magic: b"FDS\x1a",
+        },
+    ),
+    (
+        "No-Intro_A7800.xml",
+        "a7800",
+        HeaderRule {
+            size: 128,
+            magic_offset: 1,
+            magic: b"ATARI7800",
+        },
+    ),
+    (
+        "No-Intro_LNX.xml",
+        "lynx",
+        HeaderRule {
+            size: 64,
+            magic_offset: 0,
+            magic: b"LYNX",
+        },
+    ),
+];
+
+impl HeaderRule {
+    pub fn from_detector(name: &str) -> Option<Self> {
+        HEADER_DETECTORS
+            .iter()
+            .find(|(detector, short_name, _)| {
+                detector.eq_ignore_ascii_case(name) || short_name.eq_ignore_ascii_case(name)
+            })
+            .map(|(_, _, rule)| *rule)
+    }
+
+    // Skips the same number of bytes from every file, as there is no magic to check for
+    pub fn fixed(size: usize) -> Self {
+        HeaderRule {
+            size,
+            magic_offset: 0,
+            magic: b"",
+        }
+    }
+
+    // A rule set by the user, either a detector or a number of bytes to skip
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.parse::<usize>() {
+            Ok(size) if size > 0 => Some(Self::fixed(size)),
+            Ok(_) => None,
+            Err(_) => Self::from_detector(value),
+        }
+    }
+
+    pub fn detector_names() -> impl Iterator<Item = &'static str> {
+        HEADER_DETECTORS.iter().map(|(_, short_name, _)| *short_name)
+    }
+
+    // The short name of the detector the rule comes from, or the number of bytes skipped
+    pub fn name(&self) -> String {
+        HEADER_DETECTORS
+            .iter()
+            .find(|(_, _, rule)| rule == self)
+            .map_or_else(|| self.size.to_string(), |(_, short_name, _)| short_name.to_string())
+    }
+
+    fn matches(&self, header: &[u8]) -> bool {
+        header.len() == self.size
+            && header.get(self.magic_offset..self.magic_offset + self.magic.len()) == Some(self.magic)
+    }
+}
+
+// Reader that skips the header at the start of the data if it has one, otherwise
+// the data is read unchanged
+pub struct HeaderSkipReader<R> {
+    inner: R,
+    rule: Option<HeaderRule>,
+    pending: std::io::Cursor<Vec<u8>>,
+}
+
+impl<R: Read> HeaderSkipReader<R> {
+    pub fn new(inner: R, rule: Option<HeaderRule>) -> Self {
+        Self {
+            inner,
+            rule,
+            pending: std::io::Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl<R: Read> Read for HeaderSkipReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(rule) = self.rule.take() {
+            let mut header = Vec::with_capacity(rule.size);
+            (&mut self.inner).take(rule.size as u64).read_to_end(&mut header)?;
+            //not headered, so what was read is part of the data
+            if !rule.matches(&header) {
+                self.pending = std::io::Cursor::new(header);
+            }
+        }
+        let read = self.pending.read(buf)?;
+        if read > 0 {
+            return Ok(read);
+        }
+        self.inner.read(buf)
+    }
+}
+
+// Simple table that aligns its columns when written to a terminal. When not
+// aligned the cells are written tab separated so the output stays easy to parse.
+#[derive(Debug, Default)]
+pub struct Table {
+    rows: Vec<Vec<String>>,
+    indent: usize,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn indented(indent: usize) -> Self {
+        Self {
+            rows: Vec::new(),
+            indent,
+        }
+    }
+
+    pub fn add_row<I: IntoIterator<Item = S>, S: Into<String>>(&mut self, cells: I) {
+        self.rows.push(cells.into_iter().map(Into::into).collect());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W, align: bool) -> std::io::Result<()> {
+        let mut widths: Vec<usize> = Vec::new();
+        if align {
+            for row in &self.rows {
+                for (i, cell) in row.iter().enumerate() {
+                    let width = display_width(cell);
+                    match widths.get_mut(i) {
+                        Some(w) => *w = (*w).max(width),
+                        None => widths.push(width),
+                    }
+                }
+            }
+        }
+
+        for row in &self.rows {
+            let mut line = " ".repeat(self.indent);
+            for (i, cell) in row.iter().enumerate() {
+                if i > 0 {
+                    line.push(if align { ' ' } else { '\t' });
+                }
+                line.push_str(cell);
+                //don't pad the last column, it just leaves trailing whitespace
+                if align && i + 1 < row.len() {
+                    line.push_str(&" ".repeat(widths[i] - display_width(cell)));
+                }
+            }
+            writeln!(out, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+fn display_width(s: &str) -> usize {
+    //variation selectors are used on some of our status emoji and take up no space
+    s.chars().filter(|c| *c != '\u{FE0F}').count()
+}
+
+//both paths have to exist, as a path that doesn't can't be the same file as anything
+pub fn is_same_file(a: &Utf8Path, b: &Utf8Path) -> bool {
+    match (a.canonicalize_utf8(), b.canonicalize_utf8()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
 }